@@ -27,10 +27,8 @@ fn main() {
     // Find the suitable pixel format. Wwe don't want to generate non-opaque
     // pixels, `Xrgb8888` is the ideal choice. `Argb8888` is acceptable too
     // because we can generate valid alpha values.
-    let format = [Format::Xrgb8888, Format::Argb8888]
-        .iter()
-        .cloned()
-        .find(|&fmt1| sw_window.supported_formats().any(|fmt2| fmt1 == fmt2))
+    let format = sw_window
+        .best_format(&[Format::Xrgb8888, Format::Argb8888])
         .unwrap();
 
     sw_window.update_surface_to_fit(format);
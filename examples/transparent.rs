@@ -1,6 +1,6 @@
 //! An incomplete reimplementation of Desktop Ponies, demonstrating the use of
 //! a non-opaque window
-use log::debug;
+use log::{debug, warn};
 use std::time::{Duration, Instant};
 use swsurface::{Format, SwWindow};
 use winit::{
@@ -41,6 +41,9 @@ fn main() {
             ..Default::default()
         },
     );
+    if !sw_window.supports_transparency() {
+        warn!("The window doesn't support transparency; the background will be opaque");
+    }
     sw_window.update_surface_to_fit(FORMAT);
     sw_window.window().request_redraw();
 
@@ -54,7 +57,7 @@ fn main() {
     event_loop.run(move |event, _, control_flow| {
         let encourage_teleport = is_wnd_partially_escaping(sw_window.window());
 
-        let (action, needs_redraw) = state.update(&assets, encourage_teleport);
+        let (action, needs_redraw) = state.update(assets, encourage_teleport);
 
         match action {
             None => {}
@@ -86,10 +89,8 @@ fn main() {
 
                 _ => {}
             },
-            Event::RedrawRequested(id) => {
-                if sw_window.window().id() == id {
-                    redraw(&sw_window, &state)
-                };
+            Event::RedrawRequested(id) if sw_window.window().id() == id => {
+                redraw(&sw_window, &state);
             }
             Event::UserEvent(_) => {
                 redraw(&sw_window, &state);
@@ -338,7 +339,7 @@ impl<'a> PlaybackState<'a> {
         let t = self.timer.elapsed();
         let mut updated = false;
 
-        while self.frames.len() > 0 {
+        while !self.frames.is_empty() {
             let frame_dur = Duration::from_millis(self.frames[0].delay().to_integer() as _);
             let frame_end = self.frame_start + frame_dur;
             if t < frame_end {
@@ -1,8 +1,10 @@
-use std::fmt;
+use core::fmt;
 
+/// A power-of-two alignment value.
 #[derive(Debug, Copy, Clone)]
 pub struct Align(usize);
 
+/// The error type returned by [`Align::new`].
 #[derive(Debug)]
 pub struct AlignErr;
 
@@ -13,6 +15,8 @@ impl fmt::Display for AlignErr {
 }
 
 impl Align {
+    /// Construct an `Align`. Returns `Err(AlignErr)` if `x` is not a power of
+    /// two.
     pub fn new(x: usize) -> Result<Self, AlignErr> {
         if x > 0 && x.is_power_of_two() {
             Ok(Self(x - 1))
@@ -21,6 +25,13 @@ impl Align {
         }
     }
 
+    /// Get the alignment value passed to [`Align::new`].
+    pub fn get(&self) -> usize {
+        self.0 + 1
+    }
+
+    /// Round `x` up to a multiple of the alignment. Returns `None` on
+    /// overflow.
     pub fn align_up(&self, x: usize) -> Option<usize> {
         x.checked_add(self.0).map(|x| x & !self.0)
     }
@@ -35,7 +46,13 @@ mod tests {
         assert!(Align::new(4).is_ok());
         assert!(Align::new(0).is_err());
         assert!(Align::new(3).is_err());
-        assert!(Align::new(usize::max_value()).is_err());
+        assert!(Align::new(usize::MAX).is_err());
+    }
+
+    #[test]
+    fn get() {
+        assert_eq!(Align::new(1).unwrap().get(), 1);
+        assert_eq!(Align::new(16).unwrap().get(), 16);
     }
 
     #[test]
@@ -47,7 +64,7 @@ mod tests {
         assert_eq!(a1.align_up(2), Some(2));
         assert_eq!(a1.align_up(3), Some(3));
         assert_eq!(a1.align_up(4), Some(4));
-        assert_eq!(a1.align_up(usize::max_value()), Some(usize::max_value()));
+        assert_eq!(a1.align_up(usize::MAX), Some(usize::MAX));
 
         let a4 = Align::new(4).unwrap();
 
@@ -59,24 +76,12 @@ mod tests {
         assert_eq!(a4.align_up(5), Some(8));
         assert_eq!(a4.align_up(6), Some(8));
         assert_eq!(a4.align_up(7), Some(8));
-        assert_eq!(
-            a4.align_up(usize::max_value() - 6),
-            Some(usize::max_value() - 3)
-        );
-        assert_eq!(
-            a4.align_up(usize::max_value() - 5),
-            Some(usize::max_value() - 3)
-        );
-        assert_eq!(
-            a4.align_up(usize::max_value() - 4),
-            Some(usize::max_value() - 3)
-        );
-        assert_eq!(
-            a4.align_up(usize::max_value() - 3),
-            Some(usize::max_value() - 3)
-        );
-        assert_eq!(a4.align_up(usize::max_value() - 2), None);
-        assert_eq!(a4.align_up(usize::max_value() - 1), None);
-        assert_eq!(a4.align_up(usize::max_value()), None);
+        assert_eq!(a4.align_up(usize::MAX - 6), Some(usize::MAX - 3));
+        assert_eq!(a4.align_up(usize::MAX - 5), Some(usize::MAX - 3));
+        assert_eq!(a4.align_up(usize::MAX - 4), Some(usize::MAX - 3));
+        assert_eq!(a4.align_up(usize::MAX - 3), Some(usize::MAX - 3));
+        assert_eq!(a4.align_up(usize::MAX - 2), None);
+        assert_eq!(a4.align_up(usize::MAX - 1), None);
+        assert_eq!(a4.align_up(usize::MAX), None);
     }
 }
@@ -1,34 +1,119 @@
-use std::{
-    alloc::{alloc, dealloc, handle_alloc_error, realloc, Layout, LayoutErr},
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error, realloc};
+use core::{
+    alloc::{Layout, LayoutError},
     ptr::NonNull,
     slice::{from_raw_parts, from_raw_parts_mut},
 };
 
+/// A zero-initialized, heap-allocated byte buffer with a custom alignment.
 #[derive(Debug)]
 pub struct Buffer {
     ptr: NonNull<u8>,
+    /// The layout of the allocation. All bytes in the allocation are
+    /// initialized.
     layout: Layout,
+    /// The length of the buffer. `len <= layout.size()`
+    len: usize,
+    /// The position where the next `write` starts. `cursor <= len`
+    cursor: usize,
 }
 
 impl Buffer {
+    /// Allocate a `Buffer` with the specified layout.
+    ///
+    /// Panics if `layout.size()` is zero.
     pub fn new(layout: Layout) -> Self {
-        let ptr = if let Some(ptr) = NonNull::new(unsafe { alloc(layout) }) {
-            ptr
-        } else {
-            // Abort the process
-            handle_alloc_error(layout);
-        };
-
-        unsafe { ptr.as_ptr().write_bytes(0, layout.size()) };
+        assert_ne!(layout.size(), 0, "zero-sized buffer");
 
-        Self { ptr, layout }
+        Self {
+            ptr: alloc_zeroed_or_abort(layout),
+            layout,
+            len: layout.size(),
+            cursor: 0,
+        }
     }
 
-    pub fn from_size_align(size: usize, align: usize) -> Result<Self, LayoutErr> {
+    /// Allocate a `Buffer` with the specified size and alignment.
+    pub fn from_size_align(size: usize, align: usize) -> Result<Self, LayoutError> {
         Layout::from_size_align(size, align).map(Self::new)
     }
 
+    /// Get the number of bytes the buffer can hold without reallocation.
+    pub fn capacity(&self) -> usize {
+        self.layout.size()
+    }
+
+    /// Change the size of the buffer, preserving the contents. The alignment
+    /// is unchanged. The added bytes are zero-filled.
+    ///
+    /// Panics if `new_size` is zero.
     pub fn resize(&mut self, new_size: usize) {
+        assert_ne!(new_size, 0, "zero-sized buffer");
+
+        let old_len = self.len;
+        self.realloc(new_size);
+
+        if new_size > old_len {
+            unsafe {
+                self.ptr
+                    .as_ptr()
+                    .add(old_len)
+                    .write_bytes(0, new_size - old_len)
+            };
+        }
+
+        self.len = new_size;
+        self.cursor = self.cursor.min(new_size);
+    }
+
+    /// Change the size of the buffer like [`Buffer::resize`], but leave the
+    /// contents of the added bytes unspecified.
+    ///
+    /// The allocation is never shrunk, and the bytes within the capacity keep
+    /// stale contents from before. When growing beyond the capacity, a new
+    /// allocation is obtained by `alloc_zeroed`, which usually gets zeroed
+    /// pages from the operating system for large sizes without filling them,
+    /// and only the old contents are copied to it.
+    ///
+    /// Panics if `new_size` is zero.
+    pub fn resize_no_clear(&mut self, new_size: usize) {
+        assert_ne!(new_size, 0, "zero-sized buffer");
+
+        let old_capacity = self.capacity();
+        if new_size > old_capacity {
+            let new_layout = Layout::from_size_align(new_size, self.layout.align()).unwrap();
+            let new_ptr = alloc_zeroed_or_abort(new_layout);
+            unsafe {
+                new_ptr
+                    .as_ptr()
+                    .copy_from_nonoverlapping(self.ptr.as_ptr(), old_capacity);
+                dealloc(self.ptr.as_ptr(), self.layout);
+            }
+            self.ptr = new_ptr;
+            self.layout = new_layout;
+        }
+
+        self.len = new_size;
+        self.cursor = self.cursor.min(new_size);
+    }
+
+    /// Get the position where the next `write` starts.
+    pub fn position(&self) -> usize {
+        self.cursor
+    }
+
+    /// Move the write position to `pos` bytes from the beginning of the
+    /// buffer. Resizing the buffer moves the write position to the end if it
+    /// would be out of range.
+    ///
+    /// Panics if `pos` is greater than the buffer's length.
+    pub fn seek_to(&mut self, pos: usize) {
+        assert!(pos <= self.len, "position out of range");
+        self.cursor = pos;
+    }
+
+    /// Change the size of the allocation. The new bytes are uninitialized.
+    fn realloc(&mut self, new_size: usize) {
         let new_layout = Layout::from_size_align(new_size, self.layout.align()).unwrap();
 
         let new_ptr = unsafe { realloc(self.ptr.as_ptr(), self.layout, new_layout.size()) };
@@ -40,16 +125,19 @@ impl Buffer {
             handle_alloc_error(new_layout);
         };
 
-        if new_layout.size() > self.layout.size() {
-            unsafe {
-                ptr.as_ptr()
-                    .offset(self.layout.size() as isize)
-                    .write_bytes(0, new_layout.size() - self.layout.size())
-            };
-        }
-
         self.ptr = ptr;
         self.layout = new_layout;
+        self.len = self.len.min(new_size);
+    }
+}
+
+/// Allocate zero-filled memory with `layout`, aborting the process on failure.
+fn alloc_zeroed_or_abort(layout: Layout) -> NonNull<u8> {
+    if let Some(ptr) = NonNull::new(unsafe { alloc_zeroed(layout) }) {
+        ptr
+    } else {
+        // Abort the process
+        handle_alloc_error(layout);
     }
 }
 
@@ -61,16 +149,35 @@ impl Drop for Buffer {
     }
 }
 
-impl std::ops::Deref for Buffer {
+impl core::ops::Deref for Buffer {
     type Target = [u8];
 
     fn deref(&self) -> &[u8] {
-        unsafe { from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+        unsafe { from_raw_parts(self.ptr.as_ptr(), self.len) }
     }
 }
 
-impl std::ops::DerefMut for Buffer {
+impl core::ops::DerefMut for Buffer {
     fn deref_mut(&mut self) -> &mut [u8] {
-        unsafe { from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+        unsafe { from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+/// Writes to the buffer starting at [`Buffer::position`], e.g., to stream the
+/// output of an image decoder. Writes beyond the end of the buffer are
+/// truncated.
+#[cfg(feature = "backends")]
+impl std::io::Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let cursor = self.cursor;
+        let dst = &mut self[cursor..];
+        let len = core::cmp::min(buf.len(), dst.len());
+        dst[..len].copy_from_slice(&buf[..len]);
+        self.cursor += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
     }
 }
@@ -8,30 +8,79 @@
 use cocoa::{
     appkit::{self, NSOpenGLContext, NSOpenGLPixelFormat},
     base::{id, nil},
+    foundation::NSRect,
 };
+use log::warn;
+use objc::{msg_send, runtime::Class, sel, sel_impl};
 use owning_ref::OwningRefMut;
+use raw_window_handle::{RawDisplayHandle, RawWindowHandle};
 use std::{
     cell::{Cell, RefCell},
-    ops::{Deref, DerefMut},
+    ops::DerefMut,
+    time::Duration,
 };
-use winit::{platform::macos::WindowExtMacOS, window::Window};
+use winit::{event::Event, platform::macos::WindowExtMacOS, window::Window};
 
 use super::{
-    align::Align, buffer::Buffer, cglffi as gl, objcutils::IdRef, Config, Format, ImageInfo,
-    NullContextImpl,
+    align::Align,
+    buffer::Buffer,
+    cglffi as gl,
+    objcutils::IdRef,
+    surface::ConfigField,
+    surface::{NullContextImpl, NullSubSurfaceImpl},
+    AcquireResult, ColorSpace, Config, Format, ImageInfo, MergeStrategy, SurfaceError, Transform,
 };
 
 #[derive(Debug)]
 pub struct SurfaceImpl {
+    ns_view: id,
     gl_context: IdRef,
     gl_tex: gl::GLuint,
+    /// `NSVisualEffectView` placed behind the content, created by
+    /// `set_blur_behind`.
+    blur_view: RefCell<Option<IdRef>>,
+    /// [`Config::content_protection`]
+    content_protection: Cell<bool>,
+    /// The current value of `NSOpenGLCPSwapInterval`
+    swap_interval: Cell<bool>,
     image: RefCell<Buffer>,
+    /// [`Config::no_clear_on_resize`]
+    no_clear_on_resize: Cell<bool>,
     image_info: Cell<ImageInfo>,
-    scanline_align: Align,
+    scanline_align: Cell<Align>,
+    transform: Cell<Transform>,
 }
 
 impl SurfaceImpl {
     pub(crate) unsafe fn new(window: &Window, _: &NullContextImpl, config: &Config) -> Self {
+        Self::from_ns_view(window.ns_view() as id, window.ns_window() as id, config)
+    }
+
+    pub(crate) unsafe fn from_raw_handles(
+        window: RawWindowHandle,
+        _display: RawDisplayHandle,
+        _: &NullContextImpl,
+        config: &Config,
+    ) -> Result<Self, SurfaceError> {
+        match window {
+            RawWindowHandle::AppKit(handle) if !handle.ns_view.is_null() => {
+                let ns_view = handle.ns_view as id;
+                let ns_window = if handle.ns_window.is_null() {
+                    msg_send![ns_view, window]
+                } else {
+                    handle.ns_window as id
+                };
+                if ns_window == nil {
+                    // The view must be in a window
+                    return Err(SurfaceError::UnsupportedHandle);
+                }
+                Ok(Self::from_ns_view(ns_view, ns_window, config))
+            }
+            _ => Err(SurfaceError::UnsupportedHandle),
+        }
+    }
+
+    unsafe fn from_ns_view(ns_view: id, ns_window: id, config: &Config) -> Self {
         let scanline_align = Align::new(config.scanline_align).unwrap();
 
         // Create `NSOpenGLPixelFormat`
@@ -57,18 +106,26 @@ impl SurfaceImpl {
         .non_nil()
         .expect("could not create a OpenGL context");
 
-        gl_context.setView_(window.ns_view() as id);
+        gl_context.setView_(ns_view);
 
         gl_context.setValues_forParameter_(
-            &(config.vsync as i32),
+            &(swap_interval(config) as i32),
             appkit::NSOpenGLContextParameter::NSOpenGLCPSwapInterval,
         );
 
+        if config.color_space == ColorSpace::DisplayP3 {
+            set_window_color_space_display_p3(ns_window);
+        }
+
+        if config.content_protection {
+            set_window_sharing(ns_window, false);
+        }
+
         if !config.opaque {
             gl::CGLSetParameter(
                 gl_context.CGLContextObj() as *mut _,
                 gl::kCGLCPSurfaceOpacity,
-                &mut 0, // false (not opaque)
+                &0, // false (not opaque)
             );
         }
 
@@ -78,19 +135,28 @@ impl SurfaceImpl {
         gl::glGenTextures(1, &mut gl_tex);
 
         Self {
+            ns_view,
             gl_context,
             gl_tex,
+            blur_view: RefCell::new(None),
+            content_protection: Cell::new(config.content_protection),
+            swap_interval: Cell::new(swap_interval(config)),
             image: RefCell::new(Buffer::from_size_align(1, config.align).unwrap()),
-            image_info: Cell::new(ImageInfo::default()),
-            scanline_align,
+            no_clear_on_resize: Cell::new(config.no_clear_on_resize),
+            image_info: Cell::new(ImageInfo {
+                color_space: config.color_space,
+                ..ImageInfo::default()
+            }),
+            scanline_align: Cell::new(scanline_align),
+            transform: Cell::new(Transform::Identity),
         }
     }
 
     pub fn update_surface(&self, extent: [u32; 2], format: Format) {
         assert_ne!(extent[0], 0);
         assert_ne!(extent[1], 0);
-        assert!(extent[0] <= <i32>::max_value() as u32);
-        assert!(extent[1] <= <i32>::max_value() as u32);
+        assert!(extent[0] <= <i32>::MAX as u32);
+        assert!(extent[1] <= <i32>::MAX as u32);
 
         use std::convert::TryInto;
         let extent_usize: [usize; 2] = [
@@ -100,7 +166,7 @@ impl SurfaceImpl {
 
         let stride = extent_usize[0]
             .checked_mul(4)
-            .and_then(|x| self.scanline_align.align_up(x))
+            .and_then(|x| self.scanline_align.get().align_up(x))
             .expect("overflow");
 
         let size = stride.checked_mul(extent_usize[1]).expect("overflow");
@@ -133,18 +199,100 @@ impl SurfaceImpl {
             gl::glTexParameteri(gl::GL_TEXTURE_2D, gl::GL_TEXTURE_MAG_FILTER, gl::GL_LINEAR);
             gl::glTexParameteri(gl::GL_TEXTURE_2D, gl::GL_TEXTURE_MIN_FILTER, gl::GL_LINEAR);
 
-            image.resize(size);
+            if self.no_clear_on_resize.get() {
+                image.resize_no_clear(size);
+            } else {
+                image.resize(size);
+            }
         }
 
         self.image_info.set(ImageInfo {
             extent,
             stride,
             format,
+            ..self.image_info.get()
         });
     }
 
+    pub fn reconfigure(&self, old: &Config, new: &Config) -> Vec<ConfigField> {
+        let mut unsupported = Vec::new();
+
+        // `Buffer`'s alignment is fixed
+        if new.align != old.align {
+            unsupported.push(ConfigField::Align);
+        }
+
+        self.no_clear_on_resize.set(new.no_clear_on_resize);
+
+        unsafe {
+            if swap_interval(new) != swap_interval(old) {
+                self.set_swap_interval(swap_interval(new));
+            }
+
+            if new.opaque != old.opaque {
+                gl::CGLSetParameter(
+                    self.gl_context.CGLContextObj() as *mut _,
+                    gl::kCGLCPSurfaceOpacity,
+                    &(new.opaque as i32),
+                );
+            }
+
+            if new.color_space != old.color_space {
+                if new.color_space == ColorSpace::DisplayP3 {
+                    let ns_window: id = msg_send![self.ns_view, window];
+                    set_window_color_space_display_p3(ns_window);
+                } else if old.color_space == ColorSpace::DisplayP3 {
+                    // We don't know the window's original color space
+                    unsupported.push(ConfigField::ColorSpace);
+                }
+            }
+
+            if new.content_protection != old.content_protection {
+                let ns_window: id = msg_send![self.ns_view, window];
+                set_window_sharing(ns_window, !new.content_protection);
+                self.content_protection.set(new.content_protection);
+            }
+        }
+
+        if !unsupported.contains(&ConfigField::ColorSpace) {
+            self.image_info.set(ImageInfo {
+                color_space: new.color_space,
+                ..self.image_info.get()
+            });
+        }
+
+        unsupported
+    }
+
+    pub fn resize_swapchain(&self, _count: usize) -> Result<(), SurfaceError> {
+        // There's always exactly one image
+        Ok(())
+    }
+
+    pub fn set_scanline_align(&self, scanline_align: Align) {
+        self.scanline_align.set(scanline_align);
+    }
+
+    pub fn supports_transparency(&self) -> bool {
+        true
+    }
+
+    pub fn default_scanline_align(&self) -> Align {
+        // `GL_UNPACK_ROW_LENGTH` is specified in pixels
+        Align::new(4).unwrap()
+    }
+
+    pub fn refresh_rate(&self, _window: &Window) -> Option<u32> {
+        // Not implemented; `CVDisplayLink` could provide this
+        None
+    }
+
     pub fn supported_formats(&self) -> impl Iterator<Item = Format> + '_ {
-        [Format::Argb8888, Format::Xrgb8888].iter().cloned()
+        // `GL_BGRA` is the native order; `Xbgr8888` may be converted by the
+        // driver
+        [Format::Argb8888, Format::Xrgb8888, Format::Xbgr8888]
+            .iter()
+            .cloned()
     }
 
     pub fn image_info(&self) -> ImageInfo {
@@ -159,12 +307,42 @@ impl SurfaceImpl {
         true
     }
 
+    pub fn is_presenting(&self, i: usize) -> bool {
+        assert_eq!(i, 0);
+        false
+    }
+
+    pub fn is_locked(&self, i: usize) -> bool {
+        assert_eq!(i, 0);
+        self.image.try_borrow_mut().is_err()
+    }
+
     pub fn poll_next_image(&self) -> Option<usize> {
         // `present_image` will block instead, unfortunately.
         Some(0)
     }
 
-    pub fn lock_image(&self, i: usize) -> impl Deref<Target = [u8]> + DerefMut + '_ {
+    pub fn enable_ready_cb(&self) {
+        // Images are never in use by the presentation engine
+    }
+
+    pub fn is_ready_cb_scheduled(&self) -> bool {
+        false
+    }
+
+    pub fn acquire_with_timeout(&self, _timeout: Duration) -> AcquireResult {
+        AcquireResult::Ready(0)
+    }
+
+    pub fn handle_os_event<T>(&self, _event: &Event<T>) {}
+
+    pub fn detach(&self) {}
+
+    pub fn wait_all_released(&self, _timeout: Duration) -> bool {
+        true
+    }
+
+    pub fn lock_image(&self, i: usize) -> impl DerefMut<Target = [u8]> + '_ {
         assert_eq!(i, 0);
         OwningRefMut::new(self.image.borrow_mut()).map_mut(|p| &mut **p)
     }
@@ -184,6 +362,9 @@ impl SurfaceImpl {
             gl_context.makeCurrentContext();
             gl::glBindTexture(gl::GL_TEXTURE_2D, self.gl_tex);
 
+            // `glTexSubImage2D` copies from client memory before returning
+            // (we don't use `GL_APPLE_client_storage` or PBOs), so `image`
+            // can be reused right after this call without waiting on a fence.
             gl::glPixelStorei(gl::GL_UNPACK_ROW_LENGTH, (image_info.stride / 4) as _);
             gl::glTexSubImage2D(
                 gl::GL_TEXTURE_2D,
@@ -202,13 +383,18 @@ impl SurfaceImpl {
             gl::glClear(gl::GL_COLOR_BUFFER_BIT);
             gl::glEnable(gl::GL_TEXTURE_2D);
 
+            // Draw a triangle covering the whole viewport
+            let transform = self.transform.get();
             gl::glBegin(gl::GL_TRIANGLE_STRIP);
-            gl::glTexCoord2f(0.0, 0.0);
-            gl::glVertex2f(-1.0, 1.0);
-            gl::glTexCoord2f(2.0, 0.0);
-            gl::glVertex2f(3.0, 1.0);
-            gl::glTexCoord2f(0.0, 2.0);
-            gl::glVertex2f(-1.0, -3.0);
+            for &(uv, [x, y]) in &[
+                ([0.0, 0.0], [-1.0, 1.0]),
+                ([2.0, 0.0], [3.0, 1.0]),
+                ([0.0, 2.0], [-1.0, -3.0]),
+            ] {
+                let [s, t] = transform_texcoord(transform, uv);
+                gl::glTexCoord2f(s, t);
+                gl::glVertex2f(x, y);
+            }
             gl::glEnd();
 
             // According to my past observation, the following call is where
@@ -216,11 +402,266 @@ impl SurfaceImpl {
             gl_context.flushBuffer();
         }
     }
+
+    pub fn present_image_at(&self, i: usize, _offset: [i32; 2]) {
+        self.present_image(i);
+    }
+
+    pub fn present_image_immediately(&self, i: usize) {
+        if !self.swap_interval.get() {
+            return self.present_image(i);
+        }
+
+        unsafe {
+            self.set_swap_interval(false);
+            self.present_image(i);
+            self.set_swap_interval(true);
+        }
+    }
+
+    unsafe fn set_swap_interval(&self, swap_interval: bool) {
+        self.gl_context.setValues_forParameter_(
+            &(swap_interval as i32),
+            appkit::NSOpenGLContextParameter::NSOpenGLCPSwapInterval,
+        );
+        self.swap_interval.set(swap_interval);
+    }
+
+    pub fn present_image_uncommitted(&self, i: usize) {
+        self.present_image(i);
+    }
+
+    pub fn commit_present(&self) {}
+
+    pub fn try_present_image(&self, i: usize) -> Result<(), SurfaceError> {
+        self.present_image(i);
+        Ok(())
+    }
+
+    pub fn present_image_damaged(&self, i: usize, _damage: &[[u32; 4]]) {
+        self.present_image(i);
+    }
+
+    pub fn present_region(&self, i: usize, _src_rect: [u32; 4], _dst_pos: [u32; 2]) {
+        self.present_image(i);
+    }
+
+    pub fn copy_image(&self, src_i: usize, dst_i: usize) {
+        assert_eq!(src_i, 0);
+        assert_eq!(dst_i, 0);
+
+        // There's only one image, so there's nothing to copy
+        let _ = self
+            .image
+            .try_borrow_mut()
+            .expect("the image is currently locked");
+    }
+
+    pub fn set_transform(&self, transform: Transform) {
+        self.transform.set(transform);
+    }
+
+    pub fn transform(&self) -> Transform {
+        self.transform.get()
+    }
+
+    /// Capture the window using `CGWindowListCreateImage`. This requires the
+    /// screen recording permission on macOS 10.15 and later.
+    #[cfg(feature = "screen-capture")]
+    pub fn screen_capture(&self, extent: [u32; 2]) -> Option<Vec<u8>> {
+        use core_graphics::{
+            base::{kCGBitmapByteOrder32Little, kCGImageAlphaPremultipliedFirst},
+            color_space::CGColorSpace,
+            context::CGContext,
+            geometry::{CGPoint, CGRect, CGSize},
+            window::{
+                create_image, kCGWindowImageBoundsIgnoreFraming,
+                kCGWindowListOptionIncludingWindow, CGWindowID,
+            },
+        };
+
+        #[link(name = "CoreGraphics", kind = "framework")]
+        extern "C" {
+            static CGRectNull: CGRect;
+        }
+
+        let [width, height] = extent;
+        let mut out = vec![0u8; width as usize * height as usize * 4];
+        if width == 0 || height == 0 {
+            return Some(out);
+        }
+
+        let image = unsafe {
+            let ns_window: id = msg_send![self.ns_view, window];
+            let window_number: isize = msg_send![ns_window, windowNumber];
+            create_image(
+                CGRectNull,
+                kCGWindowListOptionIncludingWindow,
+                window_number as CGWindowID,
+                kCGWindowImageBoundsIgnoreFraming,
+            )?
+        };
+
+        let context = CGContext::create_bitmap_context(
+            Some(out.as_mut_ptr() as *mut _),
+            width as usize,
+            height as usize,
+            8,
+            width as usize * 4,
+            &CGColorSpace::create_device_rgb(),
+            kCGImageAlphaPremultipliedFirst | kCGBitmapByteOrder32Little,
+        );
+
+        // The captured image includes the title bar. Since Core Graphics'
+        // origin is at the bottom-left corner, aligning the bottom edges
+        // leaves only the content area.
+        context.draw_image(
+            CGRect::new(
+                &CGPoint::new(0.0, 0.0),
+                &CGSize::new(image.width() as _, image.height() as _),
+            ),
+            &image,
+        );
+        drop(context);
+
+        Some(out)
+    }
+
+    /// Label the texture for OpenGL debuggers such as Xcode's frame capture.
+    ///
+    /// Legacy OpenGL contexts on macOS don't support `GL_KHR_debug`'s
+    /// `glObjectLabel`, but `GL_EXT_debug_label` provides an equivalent.
+    #[cfg(debug_assertions)]
+    pub fn set_debug_label(&self, label: &str) {
+        unsafe {
+            self.gl_context.makeCurrentContext();
+
+            let extensions = gl::glGetString(gl::GL_EXTENSIONS);
+            if extensions.is_null() {
+                return;
+            }
+            let extensions = std::ffi::CStr::from_ptr(extensions as *const _).to_bytes();
+            if !extensions
+                .split(|&b| b == b' ')
+                .any(|ext| ext == b"GL_EXT_debug_label")
+            {
+                return;
+            }
+
+            gl::glLabelObjectEXT(
+                gl::GL_TEXTURE,
+                self.gl_tex,
+                label.len() as _,
+                label.as_ptr() as *const _,
+            );
+        }
+    }
+
+    pub fn set_damage_merge_strategy(&self, _strategy: MergeStrategy) {}
+
+    pub fn set_input_region(&self, _region: Option<&[[u32; 4]]>) {}
+
+    pub fn create_subsurface(
+        &self,
+        _rect: [u32; 4],
+        _config: &Config,
+    ) -> Option<NullSubSurfaceImpl> {
+        None
+    }
+
+    pub fn is_content_protection_active(&self) -> bool {
+        self.content_protection.get()
+    }
+
+    pub fn set_blur_behind(&self, enabled: bool) {
+        let mut blur_view = self.blur_view.borrow_mut();
+        if blur_view.is_some() == enabled {
+            return;
+        }
+
+        unsafe {
+            if let Some(view) = blur_view.take() {
+                let () = msg_send![*view, removeFromSuperview];
+                return;
+            }
+
+            // `NSVisualEffectView` is available since OS X 10.10
+            let class = if let Some(class) = Class::get("NSVisualEffectView") {
+                class
+            } else {
+                warn!("`NSVisualEffectView` is not available");
+                return;
+            };
+
+            let bounds: NSRect = msg_send![self.ns_view, bounds];
+            let view: id = msg_send![class, alloc];
+            let view = match IdRef::new(msg_send![view, initWithFrame: bounds]).non_nil() {
+                Some(view) => view,
+                None => {
+                    warn!("could not create `NSVisualEffectView`");
+                    return;
+                }
+            };
+
+            // `NSVisualEffectBlendingModeBehindWindow`
+            let () = msg_send![*view, setBlendingMode: 0isize];
+            // `NSVisualEffectStateActive`
+            let () = msg_send![*view, setState: 1isize];
+            // `NSViewWidthSizable | NSViewHeightSizable`
+            let () = msg_send![*view, setAutoresizingMask: 18usize];
+            // `NSWindowBelow`
+            let () = msg_send![self.ns_view, addSubview: *view positioned: -1isize relativeTo: nil];
+
+            *blur_view = Some(view);
+        }
+    }
+}
+
+/// Set `NSWindow.sharingType`. Disabling sharing prevents other applications
+/// from capturing the window contents.
+unsafe fn set_window_sharing(ns_window: id, enabled: bool) {
+    // `NSWindowSharingReadOnly` or `NSWindowSharingNone`
+    let sharing_type: usize = if enabled { 1 } else { 0 };
+    let () = msg_send![ns_window, setSharingType: sharing_type];
+}
+
+unsafe fn set_window_color_space_display_p3(ns_window: id) {
+    let class = Class::get("NSColorSpace").unwrap();
+
+    // `displayP3ColorSpace` is available since macOS 10.12
+    let available: bool = msg_send![class, respondsToSelector: sel!(displayP3ColorSpace)];
+    if !available {
+        warn!("`NSColorSpace.displayP3ColorSpace` is not available");
+        return;
+    }
+
+    let color_space: id = msg_send![class, displayP3ColorSpace];
+    let () = msg_send![ns_window, setColorSpace: color_space];
+}
+
+/// Get the value of `NSOpenGLCPSwapInterval` for `config`. `hint_fast_path`
+/// disables vsync because `flushBuffer` is where the blocking occurs.
+fn swap_interval(config: &Config) -> bool {
+    config.vsync && !config.hint_fast_path
+}
+
+/// Map a point in the viewport (`[0, 0]` = top left, `[1, 1]` = bottom right)
+/// to the corresponding texture coordinates.
+fn transform_texcoord(transform: Transform, [u, v]: [f32; 2]) -> [f32; 2] {
+    match transform {
+        Transform::Identity => [u, v],
+        Transform::Rotate90 => [v, 1.0 - u],
+        Transform::Rotate180 => [1.0 - u, 1.0 - v],
+        Transform::Rotate270 => [1.0 - v, u],
+        Transform::FlipHorizontal => [1.0 - u, v],
+        Transform::FlipVertical => [u, 1.0 - v],
+    }
 }
 
-fn translate_format(format: Format) -> (gl::GLenum, gl::GLenum, gl::GLenum) {
+pub(crate) fn translate_format(format: Format) -> (gl::GLenum, gl::GLenum, gl::GLenum) {
     match format {
         Format::Argb8888 => (gl::GL_RGBA, gl::GL_BGRA, gl::GL_UNSIGNED_BYTE),
         Format::Xrgb8888 => (gl::GL_RGB, gl::GL_BGRA, gl::GL_UNSIGNED_INT_8_8_8_8_REV),
+        Format::Xbgr8888 => (gl::GL_RGB, gl::GL_RGBA, gl::GL_UNSIGNED_BYTE),
     }
 }
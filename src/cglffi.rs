@@ -1,5 +1,5 @@
 //! OpenGL functions imported from the `OpenGL` system framework.
-use std::os::raw::{c_float, c_int, c_uint, c_void};
+use std::os::raw::{c_char, c_float, c_int, c_uint, c_void};
 
 pub type GLfloat = c_float;
 pub type GLint = c_int;
@@ -22,6 +22,9 @@ pub const GL_RGB: GLenum = 0x1907;
 pub const GL_UNSIGNED_BYTE: GLenum = 0x1401;
 pub const GL_UNSIGNED_INT_8_8_8_8_REV: GLenum = 0x8367;
 pub const GL_UNPACK_ROW_LENGTH: GLenum = 0x0CF2;
+pub const GL_EXTENSIONS: GLenum = 0x1F03;
+/// The object type of textures for `glLabelObjectEXT`
+pub const GL_TEXTURE: GLenum = 0x1702;
 
 pub type CGLContextObj = *mut c_void;
 pub type CGLContextParameter = c_int;
@@ -68,6 +71,10 @@ extern "C" {
     pub fn glBindTexture(target: GLenum, texture: GLuint);
     pub fn glTexParameteri(target: GLenum, pname: GLenum, param: GLint);
     pub fn glPixelStorei(pname: GLenum, param: GLint);
+    pub fn glGetString(name: GLenum) -> *const u8;
+
+    // `GL_EXT_debug_label`
+    pub fn glLabelObjectEXT(ty: GLenum, object: GLuint, length: GLsizei, label: *const c_char);
 
     pub fn CGLSetParameter(
         ctx: CGLContextObj,
@@ -0,0 +1,375 @@
+//! iOS backend
+//!
+//! The contents are presented by setting a `CGImage` to the `contents`
+//! property of the `UIView`'s backing `CALayer`.
+use core_graphics::{
+    base::{
+        kCGBitmapByteOrder32Big, kCGBitmapByteOrder32Little, kCGImageAlphaNoneSkipFirst,
+        kCGImageAlphaNoneSkipLast, kCGImageAlphaPremultipliedFirst, kCGRenderingIntentDefault,
+    },
+    color_space::{kCGColorSpaceSRGB, CGColorSpace},
+    data_provider::CGDataProvider,
+    image::{CGImage, CGImageRef},
+};
+use objc::{class, msg_send, sel, sel_impl};
+use owning_ref::OwningRefMut;
+use raw_window_handle::{RawDisplayHandle, RawWindowHandle};
+use std::{
+    cell::{Cell, RefCell},
+    ops::DerefMut,
+    os::raw::c_void,
+    sync::Arc,
+    time::Duration,
+};
+use winit::{event::Event, platform::ios::WindowExtIOS, window::Window};
+
+use super::{
+    align::Align,
+    buffer::Buffer,
+    objcutils::{id, IdRef},
+    surface::{ConfigField, NullContextImpl, NullSubSurfaceImpl},
+    transform, AcquireResult, ColorSpace, Config, Format, ImageInfo, MergeStrategy, SurfaceError,
+    Transform,
+};
+
+#[link(name = "CoreGraphics", kind = "framework")]
+extern "C" {
+    // Available since iOS 9.3
+    static kCGColorSpaceDisplayP3: *const c_void;
+    // Available since iOS 10.0
+    static kCGColorSpaceLinearSRGB: *const c_void;
+}
+
+#[derive(Debug)]
+pub struct SurfaceImpl {
+    /// The `CALayer` backing the window's `UIView`.
+    layer: IdRef,
+    image: RefCell<Buffer>,
+    /// [`Config::no_clear_on_resize`]
+    no_clear_on_resize: Cell<bool>,
+    image_info: Cell<ImageInfo>,
+    scanline_align: Cell<Align>,
+    transform: Cell<Transform>,
+    /// Stores the transformed image if `transform != Identity`
+    transformed_image: RefCell<Vec<u8>>,
+}
+
+impl SurfaceImpl {
+    pub(crate) unsafe fn new(window: &Window, _: &NullContextImpl, config: &Config) -> Self {
+        Self::from_ui_view(window.ui_view() as id, config)
+    }
+
+    pub(crate) unsafe fn from_raw_handles(
+        window: RawWindowHandle,
+        _display: RawDisplayHandle,
+        _: &NullContextImpl,
+        config: &Config,
+    ) -> Result<Self, SurfaceError> {
+        match window {
+            RawWindowHandle::UiKit(handle) if !handle.ui_view.is_null() => {
+                Ok(Self::from_ui_view(handle.ui_view as id, config))
+            }
+            _ => Err(SurfaceError::UnsupportedHandle),
+        }
+    }
+
+    unsafe fn from_ui_view(ui_view: id, config: &Config) -> Self {
+        let layer: id = msg_send![ui_view, layer];
+
+        if config.opaque {
+            let () = msg_send![layer, setOpaque: true];
+        }
+
+        Self {
+            layer: IdRef::retain(layer),
+            image: RefCell::new(Buffer::from_size_align(1, config.align).unwrap()),
+            no_clear_on_resize: Cell::new(config.no_clear_on_resize),
+            image_info: Cell::new(ImageInfo {
+                color_space: config.color_space,
+                ..ImageInfo::default()
+            }),
+            scanline_align: Cell::new(Align::new(config.scanline_align).unwrap()),
+            transform: Cell::new(Transform::Identity),
+            transformed_image: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn update_surface(&self, extent: [u32; 2], format: Format) {
+        assert_ne!(extent[0], 0);
+        assert_ne!(extent[1], 0);
+
+        let stride = (extent[0] as usize)
+            .checked_mul(4)
+            .and_then(|x| self.scanline_align.get().align_up(x))
+            .expect("overflow");
+
+        let size = stride.checked_mul(extent[1] as usize).expect("overflow");
+
+        let mut image = self.image.borrow_mut();
+        if self.no_clear_on_resize.get() {
+            image.resize_no_clear(size);
+        } else {
+            image.resize(size);
+        }
+
+        self.image_info.set(ImageInfo {
+            extent,
+            stride,
+            format,
+            ..self.image_info.get()
+        });
+    }
+
+    pub fn reconfigure(&self, old: &Config, new: &Config) -> Vec<ConfigField> {
+        let mut unsupported = Vec::new();
+
+        // `Buffer`'s alignment is fixed
+        if new.align != old.align {
+            unsupported.push(ConfigField::Align);
+        }
+
+        if new.opaque != old.opaque {
+            let () = unsafe { msg_send![*self.layer, setOpaque: new.opaque] };
+        }
+
+        self.no_clear_on_resize.set(new.no_clear_on_resize);
+
+        // `color_space` is applied by `present_image`
+        self.image_info.set(ImageInfo {
+            color_space: new.color_space,
+            ..self.image_info.get()
+        });
+
+        unsupported
+    }
+
+    pub fn resize_swapchain(&self, _count: usize) -> Result<(), SurfaceError> {
+        // There's always exactly one image
+        Ok(())
+    }
+
+    pub fn set_scanline_align(&self, scanline_align: Align) {
+        self.scanline_align.set(scanline_align);
+    }
+
+    pub fn supports_transparency(&self) -> bool {
+        true
+    }
+
+    pub fn default_scanline_align(&self) -> Align {
+        // `CGImage` accepts any stride
+        Align::new(4).unwrap()
+    }
+
+    pub fn refresh_rate(&self, _window: &Window) -> Option<u32> {
+        // Not implemented; `UIScreen::maximumFramesPerSecond` could provide this
+        None
+    }
+
+    pub fn supported_formats(&self) -> impl Iterator<Item = Format> + '_ {
+        [Format::Argb8888, Format::Xrgb8888, Format::Xbgr8888]
+            .iter()
+            .cloned()
+    }
+
+    pub fn image_info(&self) -> ImageInfo {
+        self.image_info.get()
+    }
+
+    pub fn num_images(&self) -> usize {
+        1
+    }
+
+    pub fn does_preserve_image(&self) -> bool {
+        true
+    }
+
+    pub fn is_presenting(&self, i: usize) -> bool {
+        assert_eq!(i, 0);
+        false
+    }
+
+    pub fn is_locked(&self, i: usize) -> bool {
+        assert_eq!(i, 0);
+        self.image.try_borrow_mut().is_err()
+    }
+
+    pub fn poll_next_image(&self) -> Option<usize> {
+        Some(0)
+    }
+
+    pub fn enable_ready_cb(&self) {
+        // Images are never in use by the presentation engine
+    }
+
+    pub fn is_ready_cb_scheduled(&self) -> bool {
+        false
+    }
+
+    pub fn acquire_with_timeout(&self, _timeout: Duration) -> AcquireResult {
+        AcquireResult::Ready(0)
+    }
+
+    pub fn handle_os_event<T>(&self, _event: &Event<T>) {}
+
+    pub fn detach(&self) {}
+
+    pub fn wait_all_released(&self, _timeout: Duration) -> bool {
+        true
+    }
+
+    pub fn lock_image(&self, i: usize) -> impl DerefMut<Target = [u8]> + '_ {
+        assert_eq!(i, 0);
+        OwningRefMut::new(self.image.borrow_mut()).map_mut(|p| &mut **p)
+    }
+
+    pub fn present_image(&self, i: usize) {
+        assert_eq!(i, 0);
+
+        let image_info = self.image_info.get();
+        let image = self
+            .image
+            .try_borrow()
+            .expect("the image is currently locked");
+
+        let mut transformed_image = self.transformed_image.borrow_mut();
+        let (image, image_info) = transform::apply(
+            self.transform.get(),
+            &image,
+            &image_info,
+            &mut transformed_image,
+        );
+
+        // Core Animation may read the contents at any time, so give it a copy
+        let size = image_info.stride * image_info.extent[1] as usize;
+        let provider = CGDataProvider::from_buffer(Arc::new(image[..size].to_vec()));
+
+        let bitmap_info = match image_info.format {
+            Format::Argb8888 => kCGImageAlphaPremultipliedFirst | kCGBitmapByteOrder32Little,
+            Format::Xrgb8888 => kCGImageAlphaNoneSkipFirst | kCGBitmapByteOrder32Little,
+            Format::Xbgr8888 => kCGImageAlphaNoneSkipLast | kCGBitmapByteOrder32Big,
+        };
+
+        let cg_image = CGImage::new(
+            image_info.extent[0] as usize,
+            image_info.extent[1] as usize,
+            8,
+            32,
+            image_info.stride,
+            &color_space(image_info.color_space),
+            bitmap_info,
+            &provider,
+            false,
+            kCGRenderingIntentDefault,
+        );
+        let cg_image: &CGImageRef = &cg_image;
+
+        unsafe {
+            // Disable the implicit animation
+            let () = msg_send![class!(CATransaction), begin];
+            let () = msg_send![class!(CATransaction), setDisableActions: true];
+            let () = msg_send![*self.layer, setContents: cg_image as *const CGImageRef as id];
+            let () = msg_send![class!(CATransaction), commit];
+        }
+    }
+
+    pub fn present_image_at(&self, i: usize, _offset: [i32; 2]) {
+        self.present_image(i);
+    }
+
+    pub fn present_image_immediately(&self, i: usize) {
+        // Setting `CALayer::contents` doesn't wait for the vertical blank
+        self.present_image(i);
+    }
+
+    pub fn present_image_uncommitted(&self, i: usize) {
+        self.present_image(i);
+    }
+
+    pub fn commit_present(&self) {}
+
+    pub fn try_present_image(&self, i: usize) -> Result<(), SurfaceError> {
+        self.present_image(i);
+        Ok(())
+    }
+
+    pub fn present_image_damaged(&self, i: usize, _damage: &[[u32; 4]]) {
+        self.present_image(i);
+    }
+
+    pub fn present_region(&self, i: usize, _src_rect: [u32; 4], _dst_pos: [u32; 2]) {
+        self.present_image(i);
+    }
+
+    pub fn copy_image(&self, src_i: usize, dst_i: usize) {
+        assert_eq!(src_i, 0);
+        assert_eq!(dst_i, 0);
+
+        // There's only one image, so there's nothing to copy
+        let _ = self
+            .image
+            .try_borrow_mut()
+            .expect("the image is currently locked");
+    }
+
+    pub fn set_transform(&self, transform: Transform) {
+        self.transform.set(transform);
+    }
+
+    pub fn transform(&self) -> Transform {
+        self.transform.get()
+    }
+
+    #[cfg(feature = "screen-capture")]
+    pub fn screen_capture(&self, _extent: [u32; 2]) -> Option<Vec<u8>> {
+        None
+    }
+
+    #[cfg(debug_assertions)]
+    pub fn set_debug_label(&self, label: &str) {
+        // `NSUTF8StringEncoding`
+        const UTF8_ENCODING: usize = 4;
+
+        // The layer name is shown by Xcode's view debugger
+        unsafe {
+            let name: id = msg_send![class!(NSString), alloc];
+            let name: id = msg_send![
+                name,
+                initWithBytes: label.as_ptr() as *const c_void
+                length: label.len()
+                encoding: UTF8_ENCODING
+            ];
+            let name = IdRef::new(name);
+            let () = msg_send![*self.layer, setName: *name];
+        }
+    }
+
+    pub fn set_damage_merge_strategy(&self, _strategy: MergeStrategy) {}
+
+    pub fn set_input_region(&self, _region: Option<&[[u32; 4]]>) {}
+
+    pub fn create_subsurface(
+        &self,
+        _rect: [u32; 4],
+        _config: &Config,
+    ) -> Option<NullSubSurfaceImpl> {
+        None
+    }
+
+    pub fn set_blur_behind(&self, _enabled: bool) {}
+
+    pub fn is_content_protection_active(&self) -> bool {
+        false
+    }
+}
+
+fn color_space(color_space: ColorSpace) -> CGColorSpace {
+    let name = unsafe {
+        match color_space {
+            ColorSpace::Srgb => kCGColorSpaceSRGB,
+            ColorSpace::DisplayP3 => kCGColorSpaceDisplayP3 as _,
+            ColorSpace::LinearSrgb => kCGColorSpaceLinearSRGB as _,
+        }
+    };
+    CGColorSpace::create_with_name(name).unwrap_or_else(CGColorSpace::create_device_rgb)
+}
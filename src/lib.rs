@@ -15,17 +15,34 @@
 //!
 //!  - Partial update - The application can redraw a portion, but `swsurface`
 //!    always sends entire the windowfor now
-//!  - Support for platforms other than: macOS, Windows, X11, Wayland
+//!  - Support for platforms other than: macOS, iOS, Windows, X11, Wayland
 //!  - X11: Support for color depths other than 24
 //!  - X11: Transparency
 //!  - Multi-threaded rendering (`Send`-able `Surface`)
 //!  - Color management - we'll try to stick to sRGB for now
 //!
-use std::ops::{Deref, DerefMut};
-use winit::{
-    event_loop::EventLoop,
-    window::{Window, WindowId},
-};
+//! # `no_std` support
+//!
+//! The platform backends are provided by the default feature `backends`. By
+//! disabling it and enabling `core-only` instead, this crate only provides
+//! the platform-independent types such as [`Align`], [`Buffer`], [`Format`],
+//! and [`ImageInfo`] under `#![no_std]` + `alloc` without depending on
+//! `winit`:
+//!
+//! ```toml
+//! swsurface = { version = "0.1", default-features = false, features = ["core-only"] }
+//! ```
+//!
+#![cfg_attr(not(feature = "backends"), no_std)]
+// The helpers in this crate are only used by the backends
+#![cfg_attr(not(feature = "backends"), allow(dead_code))]
+
+#[cfg(not(any(feature = "backends", feature = "core-only")))]
+compile_error!("either `backends` (enabled by default) or `core-only` must be enabled");
+
+extern crate alloc;
+
+use core::{cmp, fmt};
 
 /// Configuration for a [`Surface`].
 #[derive(Debug, Clone, Copy)]
@@ -66,6 +83,92 @@ pub struct Config {
     ///
     /// Defaults to `true`.
     pub opaque: bool,
+
+    /// The color space in which the application renders the contents.
+    ///
+    /// This value is reported via [`ImageInfo::color_space`]. It's merely a
+    /// hint for the presentation engine, which may ignore it. See
+    /// [`ColorSpace`] for details.
+    ///
+    /// Defaults to `ColorSpace::Srgb`.
+    pub color_space: ColorSpace,
+
+    /// Specifies what to do if `opaque == false` but the backend can't honor
+    /// per-pixel alpha, e.g., on X11 without an ARGB visual.
+    ///
+    /// Defaults to `TransparentFallback::Opaque`.
+    pub transparent_fallback: TransparentFallback,
+
+    /// Prefer speed over correctness when presenting images, e.g., during
+    /// resize transitions.
+    ///
+    /// This flag is merely a hint and may be ignored. When honored, artifacts
+    /// such as tearing or momentarily incorrect sizes may occur.
+    ///
+    ///  - macOS: Presents without waiting for the vertical blank, overriding
+    ///    [`Config::vsync`].
+    ///  - Windows: Uses `SetDIBitsToDevice` instead of `StretchDIBits`.
+    ///
+    /// Defaults to `false`.
+    pub hint_fast_path: bool,
+
+    /// Prevent the window contents from being captured by other
+    /// applications, e.g., to display DRM-protected content.
+    ///
+    ///  - Windows: Uses `SetWindowDisplayAffinity`. `WDA_EXCLUDEFROMCAPTURE`
+    ///    (Windows 10 version 2004 and later) is preferred over `WDA_MONITOR`.
+    ///  - macOS: Sets `NSWindow.sharingType` to `NSWindowSharingNone`.
+    ///  - Other platforms: Ignored.
+    ///
+    /// Use [`Surface::is_content_protection_active`] to check if the setting
+    /// was applied. Defaults to `false`.
+    pub content_protection: bool,
+
+    /// Let the backend choose the stride of swapchain images instead of
+    /// deriving it from [`Config::scanline_align`].
+    ///
+    /// When set, `scanline_align` is ignored, and [`ImageInfo::stride`]
+    /// becomes backend-dependent. The backend chooses the layout it can
+    /// present without copying, which is currently the tightest stride
+    /// allowed by the platform on all backends. Like `scanline_align`, this
+    /// takes effect on the next call to `update_surface`.
+    ///
+    /// Defaults to `false`.
+    pub prefer_native_stride: bool,
+
+    /// Request that swapchain images be placed in memory shared with the
+    /// presentation engine to avoid copying them on presentation.
+    ///
+    /// This flag is merely a hint and may be ignored.
+    ///
+    ///  - Wayland: Images are always in shared memory, so this is ignored.
+    ///  - X11: Uses MIT-SHM if the server supports it for this client (e.g.,
+    ///    not on a remote display) and [`Config::align`] doesn't exceed the
+    ///    page size. Presentation waits until the server has read the image.
+    ///    A change made by `Surface::reconfigure` takes effect on the next
+    ///    call to `update_surface`.
+    ///  - Other platforms: Ignored. The presentation engine copies the image
+    ///    regardless of where it is.
+    ///
+    /// Defaults to `false`.
+    pub prefer_shared_memory: bool,
+
+    /// Don't zero-fill swapchain images when `update_surface` enlarges them.
+    ///
+    /// Set this if the application repaints the whole image every frame. The
+    /// contents of an image after resizing are unspecified (but never
+    /// uninitialized memory); they may contain stale pixels from before.
+    ///
+    ///  - Wayland: Ignored. Images are in shared memory, which the kernel
+    ///    zero-fills without the application touching it.
+    ///  - Other platforms: Images keep their allocations when shrunk. When an
+    ///    image grows beyond its allocation, new zeroed memory is requested
+    ///    from the allocator, which usually gets it from the kernel without
+    ///    filling it. On X11, this doesn't apply to images in MIT-SHM segments
+    ///    (see [`Config::prefer_shared_memory`]).
+    ///
+    /// Defaults to `false`.
+    pub no_clear_on_resize: bool,
 }
 
 impl Config {
@@ -83,6 +186,13 @@ impl Default for Config {
             align: 128,
             scanline_align: 128,
             opaque: true,
+            color_space: ColorSpace::Srgb,
+            transparent_fallback: TransparentFallback::Opaque,
+            hint_fast_path: false,
+            content_protection: false,
+            prefer_native_stride: false,
+            prefer_shared_memory: false,
+            no_clear_on_resize: false,
         }
     }
 }
@@ -91,6 +201,9 @@ impl Default for Config {
 ///
 /// A backend may support only a subset of these formats. For each platform,
 /// formats marked with **mandatory** are always supporterd.
+///
+/// Backends list the formats they can present without conversion first in
+/// `supported_formats()`.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Format {
     /// 32-bit ARGB format.
@@ -105,6 +218,227 @@ pub enum Format {
     ///  - Wayland `xrgb8888` (`1`) (**mandatory**)
     ///
     Xrgb8888,
+
+    /// 32-bit BGR format. The channels are stored in the order R, G, B, X in
+    /// memory, so this matches RGBA buffers except for the ignored alpha
+    /// channel.
+    ///
+    ///  - Wayland `xbgr8888` (`0x34324258`) if advertised by `wl_shm`
+    ///  - macOS
+    ///  - iOS
+    ///
+    Xbgr8888,
+}
+
+impl Format {
+    /// Get the number of bytes per pixel.
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            Format::Argb8888 | Format::Xrgb8888 | Format::Xbgr8888 => 4,
+        }
+    }
+
+    /// Get the value of the corresponding Wayland `wl_shm::format`
+    /// enumerant.
+    ///
+    /// Unlike [`Format::to_wl_shm`], this is available on all platforms.
+    pub fn wl_shm_code(self) -> Option<u32> {
+        match self {
+            Format::Argb8888 => Some(0),
+            Format::Xrgb8888 => Some(1),
+            Format::Xbgr8888 => Some(u32::from_le_bytes(self.fourcc()?)),
+        }
+    }
+
+    /// Get the corresponding DRM FourCC code (`DRM_FORMAT_*` from
+    /// `<drm_fourcc.h>`) as four ASCII characters, e.g., `*b"AR24"`.
+    ///
+    /// The code as a `u32` is obtained by `u32::from_le_bytes`.
+    pub fn fourcc(self) -> Option<[u8; 4]> {
+        match self {
+            Format::Argb8888 => Some(*b"AR24"),
+            Format::Xrgb8888 => Some(*b"XR24"),
+            Format::Xbgr8888 => Some(*b"XB24"),
+        }
+    }
+
+    /// Get the value of `BITMAPINFOHEADER::biBitCount` for describing an
+    /// image in this format as a GDI device-independent bitmap with
+    /// `BI_RGB`. Returns `None` if GDI can't represent the format.
+    pub fn gdi_bitcount(self) -> Option<u16> {
+        match self {
+            // `BI_RGB` stores the channels in the order B, G, R, X
+            Format::Argb8888 | Format::Xrgb8888 => Some(32),
+            Format::Xbgr8888 => None,
+        }
+    }
+
+    /// Convert an RGBA color to a pixel value in this format.
+    fn encode_rgba(self, [r, g, b, a]: [u8; 4]) -> [u8; 4] {
+        // All formats are little-endian
+        match self {
+            Format::Argb8888 => [b, g, r, a],
+            Format::Xrgb8888 => [b, g, r, 0xff],
+            Format::Xbgr8888 => [r, g, b, 0xff],
+        }
+    }
+
+    /// Convert a pixel value in this format to an RGBA color.
+    fn decode_rgba(self, [c0, g, c2, a]: [u8; 4]) -> [u8; 4] {
+        match self {
+            Format::Argb8888 => [c2, g, c0, a],
+            Format::Xrgb8888 => [c2, g, c0, 0xff],
+            Format::Xbgr8888 => [c0, g, c2, 0xff],
+        }
+    }
+}
+
+/// Prints the DRM-style name of the format, e.g., `ARGB8888`.
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Format::Argb8888 => "ARGB8888",
+            Format::Xrgb8888 => "XRGB8888",
+            Format::Xbgr8888 => "XBGR8888",
+        })
+    }
+}
+
+#[cfg(all(
+    feature = "backends",
+    any(
+        target_os = "linux",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    )
+))]
+impl Format {
+    /// Convert a Wayland `wl_shm` pixel format to `Format`. Returns `None` if
+    /// there's no corresponding `Format`.
+    pub fn from_wl_shm(format: wayland_client::protocol::wl_shm::Format) -> Option<Self> {
+        use wayland_client::protocol::wl_shm::Format as ShmFormat;
+        match format {
+            ShmFormat::Argb8888 => Some(Format::Argb8888),
+            ShmFormat::Xrgb8888 => Some(Format::Xrgb8888),
+            ShmFormat::Xbgr8888 => Some(Format::Xbgr8888),
+            _ => None,
+        }
+    }
+
+    /// Convert `Format` to the corresponding Wayland `wl_shm` pixel format.
+    pub fn to_wl_shm(self) -> wayland_client::protocol::wl_shm::Format {
+        use wayland_client::protocol::wl_shm::Format as ShmFormat;
+        match self {
+            Format::Argb8888 => ShmFormat::Argb8888,
+            Format::Xrgb8888 => ShmFormat::Xrgb8888,
+            Format::Xbgr8888 => ShmFormat::Xbgr8888,
+        }
+    }
+}
+
+#[cfg(all(feature = "backends", target_os = "macos"))]
+impl Format {
+    /// Get the OpenGL internal format, format, and type (in this order) for
+    /// uploading an image in this format to a texture by `glTexImage2D`.
+    ///
+    /// These are the values the macOS backend uses for its own texture. The
+    /// values are `GLenum`s and can be passed to any OpenGL binding.
+    pub fn to_gl_format(self) -> (u32, u32, u32) {
+        let (ifmt, fmt, ty) = cgl::translate_format(self);
+        (ifmt as u32, fmt as u32, ty as u32)
+    }
+}
+
+/// Specifies the color space of swapchain images.
+///
+/// This crate doesn't perform color management by itself. The color space
+/// only tags the contents so that the presentation engine can interpret them
+/// correctly where possible. Elsewhere, it's merely advisory metadata and the
+/// contents are presented as-is.
+///
+///  - macOS: `DisplayP3` sets the color space of the window. (`Srgb` leaves
+///    it unchanged.)
+///  - iOS: Sets the color space of presented images.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ColorSpace {
+    /// sRGB.
+    #[default]
+    Srgb,
+    /// Display P3, which uses the transfer function of sRGB.
+    DisplayP3,
+    /// sRGB primaries with a linear transfer function.
+    LinearSrgb,
+}
+
+/// Specifies the behavior when a transparent surface is requested (see
+/// [`Config::opaque`]) but the backend can't honor per-pixel alpha.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum TransparentFallback {
+    /// Fail the construction of the surface with
+    /// `SurfaceError::TransparencyUnsupported`.
+    Error,
+    /// Present the contents as if `opaque == true`.
+    #[default]
+    Opaque,
+    /// Make the pixels whose alpha value is zero fully transparent and the
+    /// others fully opaque by setting the window shape. This is only
+    /// supported by X11 (requires `libXfixes`). Elsewhere, this is
+    /// equivalent to `Opaque`.
+    ShapeMask,
+}
+
+/// Specifies how the damaged regions of an image are coalesced before they
+/// are sent to the presentation engine.
+///
+/// Fewer rectangles reduce the protocol overhead at the cost of updating
+/// areas that didn't change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MergeStrategy {
+    /// Send the rectangles as they are.
+    None,
+    /// Merge all rectangles into their bounding box.
+    BoundingBox,
+    /// Greedily merge pairs of rectangles until there are at most the
+    /// specified number of them. Each step merges the pair whose bounding
+    /// box adds the least area. `MaxRects(0)` is treated as `MaxRects(1)`.
+    MaxRects(usize),
+}
+
+impl Default for MergeStrategy {
+    fn default() -> Self {
+        MergeStrategy::MaxRects(16)
+    }
+}
+
+/// Specifies a transformation applied to the contents of a surface when
+/// presenting them.
+///
+/// Rotations are clockwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Transform {
+    #[default]
+    Identity,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    /// Mirror the contents horizontally (i.e., about the vertical axis).
+    FlipHorizontal,
+    /// Mirror the contents vertically (i.e., about the horizontal axis).
+    FlipVertical,
+}
+
+impl Transform {
+    /// Get the size of an image after applying this transformation to an
+    /// image of size `extent`.
+    pub fn transform_extent(self, extent: [u32; 2]) -> [u32; 2] {
+        match self {
+            Transform::Rotate90 | Transform::Rotate270 => [extent[1], extent[0]],
+            _ => extent,
+        }
+    }
 }
 
 /// Describes the format of a swapchain image.
@@ -118,6 +452,8 @@ pub struct ImageInfo {
     pub stride: usize,
     /// The pixel format.
     pub format: Format,
+    /// The color space.
+    pub color_space: ColorSpace,
 }
 
 impl Default for ImageInfo {
@@ -126,339 +462,961 @@ impl Default for ImageInfo {
             extent: [0, 0],
             stride: 0,
             format: Format::Argb8888,
+            color_space: ColorSpace::Srgb,
         }
     }
 }
 
-/// A software-rendered window.
-///
-/// This is a safe wrapper around [`Surface`] and [`winit::window::Window`].
-/// For each method, only a synopsis is provided here. See `Surface`'s
-/// documentation for a full documentation.
-#[derive(Debug)]
-pub struct SwWindow {
-    surface: Option<Surface>,
-    window: Option<Window>,
-}
+impl ImageInfo {
+    /// Get the number of pixels in the image.
+    pub fn area(&self) -> u64 {
+        self.extent[0] as u64 * self.extent[1] as u64
+    }
 
-impl SwWindow {
-    /// Construct a `SwWindow` by wrapping an existing `Window`.
-    pub fn new(window: Window, context: &Context, config: &Config) -> Self {
-        Self {
-            surface: Some(unsafe { Surface::new(&window, context, config) }),
-            window: Some(window),
+    /// Check if the image is large enough to contain `other` in both
+    /// dimensions.
+    pub fn covers(&self, other: &ImageInfo) -> bool {
+        self.extent[0] >= other.extent[0] && self.extent[1] >= other.extent[1]
+    }
+
+    /// Clip the rectangle `rect` (`[x, y, width, height]`) by the image's
+    /// extent. Returns `None` if the result is empty.
+    pub fn clip_rect(&self, [x, y, width, height]: [u32; 4]) -> Option<[u32; 4]> {
+        let [size_w, size_h] = self.extent;
+        let width = cmp::min(width, size_w.saturating_sub(x));
+        let height = cmp::min(height, size_h.saturating_sub(y));
+        if width == 0 || height == 0 {
+            None
+        } else {
+            Some([x, y, width, height])
         }
     }
 
-    /// Detach the surface and get the wrapped [`winit::window::Window`].
-    pub fn into_window(mut self) -> Window {
-        // Deconstruct the surface first
-        drop(self.surface.take());
+    /// Describe the sub-rectangle `rect` (`[x, y, width, height]`) of the
+    /// image as an image of its own. Returns the `ImageInfo` of the
+    /// sub-rectangle, which has the same stride and format as `self`, and the
+    /// byte offset of its first pixel in the image.
+    ///
+    /// This can be used to let a child renderer draw into a part of a locked
+    /// image as if it were a whole image by passing `&mut image[offset..]`.
+    /// Note that this slice may be shorter than `extent[1] * stride` because
+    /// the last row of the sub-rectangle doesn't necessarily extend to the end
+    /// of the parent's row.
+    ///
+    /// Panics if `rect` is empty or not entirely inside the image.
+    pub fn subregion(&self, [x, y, width, height]: [u32; 4]) -> (ImageInfo, usize) {
+        assert!(width != 0 && height != 0, "the region is empty");
+        assert!(
+            x.checked_add(width).is_some_and(|x| x <= self.extent[0])
+                && y.checked_add(height).is_some_and(|y| y <= self.extent[1]),
+            "the region {:?} is out of bounds",
+            [x, y, width, height]
+        );
 
-        self.window.take().unwrap()
+        let offset = y as usize * self.stride + x as usize * self.format.bytes_per_pixel();
+        let image_info = ImageInfo {
+            extent: [width, height],
+            ..*self
+        };
+        (image_info, offset)
     }
 
-    /// Split the `Window` apart from the `Surface`.
+    /// Panic if `self` describes a memory layout different from `current`,
+    /// e.g., because `self` was obtained before `update_surface` resized the
+    /// surface and `current` is the latest `Surface::image_info()`.
     ///
-    /// **Unsafety:** The `Surface` must be dropped before the `Window`.
-    pub unsafe fn split(mut self) -> (Surface, Window) {
-        (self.surface.take().unwrap(), self.window.take().unwrap())
+    /// `extent`, `stride`, and `format` are compared. `color_space` is not.
+    pub fn assert_compatible(&self, current: &ImageInfo) {
+        assert!(
+            self.extent == current.extent
+                && self.stride == current.stride
+                && self.format == current.format,
+            "stale `ImageInfo` ({:?}, but the current one is {:?}); \
+             call `image_info` again after `update_surface`",
+            self,
+            current
+        );
     }
 
-    /// Get a reference to the wrapped [`winit::window::Window`].
-    pub fn window(&self) -> &Window {
-        self.window.as_ref().unwrap()
+    /// Get the number of padding bytes at the end of each row, i.e.,
+    /// `stride - extent[0] * format.bytes_per_pixel()`.
+    pub fn stride_padding(&self) -> usize {
+        self.stride - self.extent[0] as usize * self.format.bytes_per_pixel()
     }
 
-    /// Update the properties of the surface.
-    pub fn update_surface(&self, extent: [u32; 2], format: Format) {
-        self.surface
-            .as_ref()
-            .unwrap()
-            .update_surface(extent, format);
+    /// Check if the rows are tightly packed, i.e., `stride_padding() == 0`.
+    ///
+    /// If this returns `true`, the image data can be treated as a flat array
+    /// of `extent[0] * extent[1]` pixels. Otherwise, the padding must be
+    /// skipped, e.g., by using [`ImageInfo::pixel_iter`].
+    pub fn is_compact(&self) -> bool {
+        self.stride_padding() == 0
     }
 
-    /// Update the properties of the surface. The surface size is automatically
-    /// derived based on the window size.
-    pub fn update_surface_to_fit(&self, format: Format) {
-        self.surface
-            .as_ref()
-            .unwrap()
-            .update_surface_to_fit(self.window.as_ref().unwrap(), format);
+    /// Get the width divided by the height.
+    ///
+    /// Returns `1.0` if the height is zero (e.g., if `update_surface` hasn't
+    /// been called yet).
+    pub fn aspect_ratio(&self) -> f64 {
+        let [width, height] = self.extent;
+        if height == 0 {
+            1.0
+        } else {
+            width as f64 / height as f64
+        }
     }
 
-    /// Enumerate supported pixel formats.
-    pub fn supported_formats(&self) -> impl Iterator<Item = Format> + '_ {
-        self.surface.as_ref().unwrap().supported_formats()
+    /// Check if the image is wider than it is tall.
+    pub fn is_landscape(&self) -> bool {
+        self.extent[0] > self.extent[1]
     }
 
-    /// Get the `ImageInfo` describing the current swapchain images.
-    pub fn image_info(&self) -> ImageInfo {
-        self.surface.as_ref().unwrap().image_info()
+    /// Check if the image is taller than it is wide.
+    pub fn is_portrait(&self) -> bool {
+        self.extent[1] > self.extent[0]
     }
 
-    /// Get the number of swapchain images.
-    pub fn num_images(&self) -> usize {
-        self.surface.as_ref().unwrap().num_images()
+    /// Check if the image's width and height are equal.
+    pub fn is_square(&self) -> bool {
+        self.extent[0] == self.extent[1]
     }
 
-    /// Get a flag indicating whether swapchain images preserve their contents
-    /// when their indices are used again.
-    pub fn does_preserve_image(&self) -> bool {
-        self.surface.as_ref().unwrap().does_preserve_image()
+    /// Get an iterator over the pixels of the image `data` described by
+    /// `self`, yielding `(x, y, [r, g, b, a])` in row-major order.
+    ///
+    /// The pixels are converted to RGBA regardless of [`ImageInfo::format`].
+    /// Alpha is `0xff` for formats without an alpha channel.
+    ///
+    /// Panics if `data` is smaller than `stride * extent[1]` bytes.
+    pub fn pixel_iter<'a>(&self, data: &'a [u8]) -> impl Iterator<Item = (u32, u32, [u8; 4])> + 'a {
+        self.check_data_len(data.len());
+
+        let ImageInfo {
+            extent: [width, height],
+            stride,
+            format,
+            ..
+        } = *self;
+        let row_len = width as usize * format.bytes_per_pixel();
+
+        (0..height).flat_map(move |y| {
+            data[y as usize * stride..][..row_len]
+                .chunks_exact(4)
+                .zip(0..)
+                .map(move |(p, x)| (x, y, format.decode_rgba([p[0], p[1], p[2], p[3]])))
+        })
     }
 
-    /// Get the index of the next available swapchain image. Blocks the current
-    /// thread.
-    pub fn poll_next_image(&self) -> Option<usize> {
-        self.surface.as_ref().unwrap().poll_next_image()
+    /// Get an iterator over the pixels of the image `data` described by
+    /// `self`, yielding `(x, y, pixel)` in row-major order.
+    ///
+    /// `pixel` dereferences to the RGBA color of the pixel. Modifications are
+    /// written back in the native byte order of [`ImageInfo::format`] when
+    /// `pixel` is dropped.
+    ///
+    /// Panics if `data` is smaller than `stride * extent[1]` bytes.
+    pub fn pixel_iter_mut<'a>(
+        &self,
+        data: &'a mut [u8],
+    ) -> impl Iterator<Item = (u32, u32, PixelMut<'a>)> + 'a {
+        self.check_data_len(data.len());
+
+        let ImageInfo {
+            extent: [width, height],
+            stride,
+            format,
+            ..
+        } = *self;
+        let row_len = width as usize * format.bytes_per_pixel();
+
+        data.chunks_mut(cmp::max(stride, 1))
+            .take(height as usize)
+            .zip(0..)
+            .flat_map(move |(row, y)| {
+                row[..row_len]
+                    .chunks_exact_mut(4)
+                    .zip(0..)
+                    .map(move |(p, x)| (x, y, PixelMut::new(p, format)))
+            })
     }
 
-    /// Lock a swapchain image at index `i` to access its contents.
-    pub fn lock_image(&self, i: usize) -> impl Deref<Target = [u8]> + DerefMut + '_ {
-        self.surface.as_ref().unwrap().lock_image(i)
+    fn check_data_len(&self, len: usize) {
+        let size = self.stride * self.extent[1] as usize;
+        assert!(
+            len >= size,
+            "the image data is too small ({} < {} bytes)",
+            len,
+            size
+        );
     }
+}
+
+/// Prints a concise summary, e.g., `1920x1080 stride=7680 ARGB8888`.
+impl fmt::Display for ImageInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}x{} stride={} {}",
+            self.extent[0], self.extent[1], self.stride, self.format
+        )
+    }
+}
+
+/// A mutable view of a pixel, returned by [`ImageInfo::pixel_iter_mut`].
+///
+/// Dereferences to the RGBA color of the pixel. The color is converted back
+/// to the image's pixel format and written to the image when dropped.
+#[derive(Debug)]
+pub struct PixelMut<'a> {
+    rgba: [u8; 4],
+    data: &'a mut [u8],
+    format: Format,
+}
+
+impl<'a> PixelMut<'a> {
+    fn new(data: &'a mut [u8], format: Format) -> Self {
+        Self {
+            rgba: format.decode_rgba([data[0], data[1], data[2], data[3]]),
+            data,
+            format,
+        }
+    }
+}
+
+impl core::ops::Deref for PixelMut<'_> {
+    type Target = [u8; 4];
+
+    fn deref(&self) -> &Self::Target {
+        &self.rgba
+    }
+}
 
-    /// Enqueue the presentation of a swapchain image at index `i`.
-    pub fn present_image(&self, i: usize) {
-        self.surface.as_ref().unwrap().present_image(i)
+impl core::ops::DerefMut for PixelMut<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.rgba
     }
 }
 
-impl Drop for SwWindow {
+impl Drop for PixelMut<'_> {
     fn drop(&mut self) {
-        // Deconstruct the surface first
-        drop(self.surface.take());
+        self.data
+            .copy_from_slice(&self.format.encode_rgba(self.rgba));
+    }
+}
+
+/// `ImageInfo`s are ordered by [`ImageInfo::covers`]. Two `ImageInfo`s with
+/// the same extent but otherwise different are not comparable.
+impl PartialOrd for ImageInfo {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        if self == other {
+            Some(cmp::Ordering::Equal)
+        } else if self.extent == other.extent {
+            None
+        } else if self.covers(other) {
+            Some(cmp::Ordering::Greater)
+        } else if other.covers(self) {
+            Some(cmp::Ordering::Less)
+        } else {
+            None
+        }
     }
 }
 
 // --------------------------------------------------------------------------
 // Backend implementations
+//
+// The backends are excluded from `core-only` builds, which disable the
+// `backends` feature.
 
-#[cfg(target_os = "windows")]
+#[cfg(feature = "backends")]
+mod surface;
+#[cfg(all(feature = "backends", feature = "image"))]
+pub use self::surface::ImageBufferGuard;
+#[cfg(feature = "backends")]
+pub use self::surface::{
+    AcquireResult, Backend, Context, ContextBuilder, Drawable, ImageFrame, LockedImage,
+    PresentGroup, RegionGuard, SubSurface, Surface, SurfaceError, SwSurface, SwWindow, SwWindowSet,
+};
+
+#[cfg(all(feature = "backends", target_os = "windows"))]
 mod windows;
-#[cfg(target_os = "windows")]
+#[cfg(all(feature = "backends", target_os = "windows"))]
 use self::windows::SurfaceImpl;
-#[cfg(target_os = "windows")]
-type ContextImpl = NullContextImpl;
+#[cfg(all(feature = "backends", target_os = "windows"))]
+type ContextImpl = surface::NullContextImpl;
+#[cfg(all(feature = "backends", target_os = "windows"))]
+type SubSurfaceImpl = surface::NullSubSurfaceImpl;
 
-#[cfg(any(target_os = "ios", target_os = "macos"))]
+#[cfg(all(feature = "backends", target_os = "macos"))]
 mod cglffi;
-#[cfg(any(target_os = "ios", target_os = "macos"))]
+#[cfg(all(feature = "backends", any(target_os = "ios", target_os = "macos")))]
 mod objcutils;
 
-#[cfg(target_os = "macos")]
+#[cfg(all(feature = "backends", target_os = "macos"))]
 mod cgl;
-#[cfg(target_os = "macos")]
+#[cfg(all(feature = "backends", target_os = "macos"))]
 use self::cgl::SurfaceImpl;
-#[cfg(target_os = "macos")]
-type ContextImpl = NullContextImpl;
-
-#[cfg(any(
-    target_os = "linux",
-    target_os = "dragonfly",
-    target_os = "freebsd",
-    target_os = "netbsd",
-    target_os = "openbsd"
+#[cfg(all(feature = "backends", target_os = "macos"))]
+type ContextImpl = surface::NullContextImpl;
+#[cfg(all(feature = "backends", target_os = "macos"))]
+type SubSurfaceImpl = surface::NullSubSurfaceImpl;
+
+#[cfg(all(feature = "backends", target_os = "ios"))]
+mod ios;
+#[cfg(all(feature = "backends", target_os = "ios"))]
+use self::ios::SurfaceImpl;
+#[cfg(all(feature = "backends", target_os = "ios"))]
+type ContextImpl = surface::NullContextImpl;
+#[cfg(all(feature = "backends", target_os = "ios"))]
+type SubSurfaceImpl = surface::NullSubSurfaceImpl;
+
+#[cfg(all(
+    feature = "backends",
+    any(
+        target_os = "linux",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    )
 ))]
 mod unix;
-#[cfg(any(
-    target_os = "linux",
-    target_os = "dragonfly",
-    target_os = "freebsd",
-    target_os = "netbsd",
-    target_os = "openbsd"
+#[cfg(all(
+    feature = "backends",
+    any(
+        target_os = "linux",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    )
 ))]
-use self::unix::{ContextImpl, SurfaceImpl};
+use self::unix::{ContextImpl, SubSurfaceImpl, SurfaceImpl};
 
 // --------------------------------------------------------------------------
 // Helper types
 
 mod align;
 mod buffer;
+// The macOS backend does the transformation using OpenGL
+#[cfg(all(feature = "backends", not(target_os = "macos")))]
+mod transform;
 
-// --------------------------------------------------------------------------
+pub use self::{
+    align::{Align, AlignErr},
+    buffer::Buffer,
+};
+
+/// Calculate the difference `a - b` of rectangles `[x, y, width, height]`.
+/// Returns up to four rectangles.
+#[cfg(all(
+    feature = "backends",
+    any(
+        target_os = "linux",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    )
+))]
+fn rect_difference(a: [u32; 4], b: [u32; 4]) -> Vec<[u32; 4]> {
+    let [ax, ay, aw, ah] = a;
+    let [bx, by, bw, bh] = b;
+    let (ax1, ay1) = (ax + aw, ay + ah);
+    let (bx1, by1) = (bx + bw, by + bh);
+
+    if aw == 0 || ah == 0 {
+        return Vec::new();
+    }
+    if bw == 0 || bh == 0 || bx >= ax1 || bx1 <= ax || by >= ay1 || by1 <= ay {
+        // They don't intersect
+        return vec![a];
+    }
+
+    let mut out = Vec::with_capacity(4);
 
-#[allow(dead_code)]
-pub struct ContextBuilder<'a, T: 'static> {
-    event_loop: &'a EventLoop<T>,
-    ready_cb: ReadyCb,
+    // The rows above and below `b`
+    if by > ay {
+        out.push([ax, ay, aw, by - ay]);
+    }
+    if by1 < ay1 {
+        out.push([ax, by1, aw, ay1 - by1]);
+    }
+
+    // The columns on the left and right of `b`
+    let (y0, y1) = (cmp::max(ay, by), cmp::min(ay1, by1));
+    if bx > ax {
+        out.push([ax, y0, bx - ax, y1 - y0]);
+    }
+    if bx1 < ax1 {
+        out.push([bx1, y0, ax1 - bx1, y1 - y0]);
+    }
+
+    out
 }
 
-type ReadyCb = Box<dyn Fn(WindowId)>;
+/// Coalesce rectangles `[x, y, width, height]` according to `strategy`.
+#[cfg(all(
+    feature = "backends",
+    any(
+        target_os = "linux",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    )
+))]
+fn merge_rects(rects: &mut Vec<[u32; 4]>, strategy: MergeStrategy) {
+    let max_len = match strategy {
+        MergeStrategy::None => return,
+        MergeStrategy::BoundingBox => 1,
+        MergeStrategy::MaxRects(n) => cmp::max(n, 1),
+    };
 
-impl<'a, T: 'static> ContextBuilder<'a, T> {
-    /// Construct a `ContextBuilder`.
-    pub fn new(event_loop: &'a EventLoop<T>) -> Self {
-        Self {
-            event_loop,
-            ready_cb: Box::new(|_| {}),
-        }
+    fn union([ax, ay, aw, ah]: [u32; 4], [bx, by, bw, bh]: [u32; 4]) -> [u32; 4] {
+        let (x, y) = (cmp::min(ax, bx), cmp::min(ay, by));
+        let x1 = cmp::max(ax + aw, bx + bw);
+        let y1 = cmp::max(ay + ah, by + bh);
+        [x, y, x1 - x, y1 - y]
     }
 
-    /// Specify the function to be called when a swapchain image becomes
-    /// available.
-    pub fn with_ready_cb(self, cb: impl Fn(WindowId) + 'static) -> Self {
-        if ContextImpl::TAKES_READY_CB {
-            Self {
-                ready_cb: Box::new(cb),
-                ..self
-            }
-        } else {
-            self
+    fn area([_, _, w, h]: [u32; 4]) -> u64 {
+        w as u64 * h as u64
+    }
+
+    if max_len == 1 {
+        if let Some(first) = rects.first().cloned() {
+            let bbox = rects.iter().fold(first, |a, &b| union(a, b));
+            rects.clear();
+            rects.push(bbox);
         }
+        return;
     }
 
-    /// Build a `Context`.
-    pub fn build(self) -> Context {
-        Context {
-            inner: ContextImpl::new(self),
+    while rects.len() > max_len {
+        // Find the pair that adds the least area when merged
+        let mut best = (0, 1, u64::MAX);
+        for i in 0..rects.len() {
+            for j in i + 1..rects.len() {
+                let (a, b) = (rects[i], rects[j]);
+                let cost = area(union(a, b)).saturating_sub(area(a) + area(b));
+                if cost < best.2 {
+                    best = (i, j, cost);
+                }
+            }
         }
+
+        let (i, j, _) = best;
+        let b = rects.swap_remove(j);
+        rects[i] = union(rects[i], b);
     }
 }
 
-/// The global data for [`Surface`], constructed using [`ContextBuilder`].
-#[derive(Debug)]
-pub struct Context {
-    inner: ContextImpl,
+fn for_each_scanline_mut(
+    image_info: &ImageInfo,
+    data: &mut [u8],
+    mut f: impl FnMut(u32, &mut [u8]),
+) {
+    let [size_w, size_h] = image_info.extent;
+    if size_h == 0 {
+        return;
+    }
+
+    let row_len = size_w as usize * image_info.format.bytes_per_pixel();
+
+    for (y, row) in data
+        .chunks_mut(image_info.stride)
+        .take(size_h as usize)
+        .enumerate()
+    {
+        f(y as u32, &mut row[..row_len]);
+    }
 }
 
-/// For backends that don't require `ContextImpl`, this type is aliased as
-/// `ContextImpl`.
-#[allow(dead_code)]
-#[derive(Debug)]
-struct NullContextImpl;
+/// Copy the pixels inside `rect` (`[x, y, width, height]`) of the image
+/// `data` to `out` without padding between rows.
+fn read_region(
+    image_info: &ImageInfo,
+    data: &[u8],
+    [x, y, width, height]: [u32; 4],
+    out: &mut [u8],
+) {
+    let [size_w, size_h] = image_info.extent;
+    assert!(
+        x as u64 + width as u64 <= size_w as u64 && y as u64 + height as u64 <= size_h as u64,
+        "the region is out of bounds"
+    );
 
-#[allow(dead_code)]
-impl NullContextImpl {
-    const TAKES_READY_CB: bool = false;
+    let bpp = image_info.format.bytes_per_pixel();
+    let row_len = width as usize * bpp;
+    assert_eq!(
+        out.len(),
+        row_len * height as usize,
+        "the output buffer length doesn't match the region"
+    );
+    if row_len == 0 {
+        return;
+    }
 
-    fn new<T: 'static>(_: ContextBuilder<'_, T>) -> Self {
-        Self {}
+    let x_offset = x as usize * bpp;
+    for (src_row, out_row) in data
+        .chunks(image_info.stride)
+        .skip(y as usize)
+        .zip(out.chunks_exact_mut(row_len))
+    {
+        out_row.copy_from_slice(&src_row[x_offset..][..row_len]);
     }
 }
 
-// --------------------------------------------------------------------------
+/// Convert a refresh rate measured in mHz to the interval between frames.
+fn frame_interval(refresh_mhz: u32) -> core::time::Duration {
+    core::time::Duration::from_nanos(1_000_000_000_000 / refresh_mhz as u64)
+}
 
-/// A software-rendered surface that is implicitly associated with the
-/// underlying window (like `glutin::RawContext`).
-#[derive(Debug)]
-pub struct Surface {
-    inner: SurfaceImpl,
+/// Resample the image `src` to fill the image `dst` using nearest-neighbor
+/// sampling.
+fn scale_nearest(src: &[u8], src_info: &ImageInfo, dst: &mut [u8], dst_info: &ImageInfo) {
+    let bpp = dst_info.format.bytes_per_pixel();
+    debug_assert_eq!(src_info.format.bytes_per_pixel(), bpp);
+
+    let [src_w, src_h] = src_info.extent;
+    let [dst_w, dst_h] = dst_info.extent;
+
+    for y in 0..dst_h {
+        let src_y = (y as u64 * src_h as u64 / dst_h as u64) as usize;
+        let src_row = &src[src_y * src_info.stride..];
+        let dst_row = &mut dst[y as usize * dst_info.stride..][..dst_w as usize * bpp];
+
+        for (x, pixel) in dst_row.chunks_exact_mut(bpp).enumerate() {
+            let src_x = (x as u64 * src_w as u64 / dst_w as u64) as usize;
+            pixel.copy_from_slice(&src_row[src_x * bpp..][..bpp]);
+        }
+    }
 }
 
-impl Surface {
-    /// Construct and attach a surface to the specified window.
-    ///
-    /// **Unsafety:** The constructed `Surface` must be dropped before `window`.
-    pub unsafe fn new(window: &Window, context: &Context, config: &Config) -> Self {
-        Self {
-            inner: SurfaceImpl::new(window, &context.inner, config),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{vec, vec::Vec};
+
+    #[test]
+    fn pixel_iter() {
+        let image_info = ImageInfo {
+            extent: [2, 2],
+            stride: 12,
+            format: Format::Argb8888,
+            ..ImageInfo::default()
+        };
+        let mut data: Vec<u8> = (0..24).collect();
+
+        let pixels: Vec<_> = image_info.pixel_iter(&data).collect();
+        assert_eq!(
+            pixels,
+            [
+                (0, 0, [2, 1, 0, 3]),
+                (1, 0, [6, 5, 4, 7]),
+                (0, 1, [14, 13, 12, 15]),
+                (1, 1, [18, 17, 16, 19]),
+            ]
+        );
+
+        for (x, y, mut pixel) in image_info.pixel_iter_mut(&mut data) {
+            *pixel = [x as u8, y as u8, 0x80, 0x40];
         }
+        assert_eq!(data[..8], [0x80, 0, 0, 0x40, 0x80, 0, 1, 0x40]);
+        assert_eq!(data[8..12], [8, 9, 10, 11]);
+
+        let image_info = ImageInfo {
+            format: Format::Xrgb8888,
+            ..image_info
+        };
+        assert_eq!(
+            image_info.pixel_iter(&data).nth(3),
+            Some((1, 1, [1, 1, 0x80, 0xff]))
+        );
     }
 
-    /// Update the properties of the surface.
-    ///
-    /// After resizing a window, you must call this method irregardless of
-    /// whether you want to change the image size or not. Also, you must call
-    /// this method at least once before calling other methods.
-    ///
-    /// The result of a mismatching image size is implementation-dependent.
-    /// In general, you should use `update_surface_to_fit`.
-    ///
-    /// Panics if:
-    ///  - `format` is not in `supported_formats()`.
-    ///  - One of `extent`'s elements is zero.
-    ///  - One or more swapchain images are locked.
-    pub fn update_surface(&self, extent: [u32; 2], format: Format) {
-        self.inner.update_surface(extent, format);
+    #[test]
+    fn xbgr8888_byte_order() {
+        assert_eq!(Format::Xbgr8888.encode_rgba([1, 2, 3, 4]), [1, 2, 3, 0xff]);
+        assert_eq!(Format::Xbgr8888.decode_rgba([1, 2, 3, 4]), [1, 2, 3, 0xff]);
+        assert_eq!(Format::Xrgb8888.decode_rgba([1, 2, 3, 4]), [3, 2, 1, 0xff]);
     }
 
-    /// Update the properties of the surface. The surface size is automatically
-    /// derived based on the window size.
-    ///
-    /// This internally calls `update_surface`.
-    pub fn update_surface_to_fit(&self, window: &Window, format: Format) {
-        let (size_w, size_h) = window.inner_size().into();
+    #[test]
+    #[should_panic(expected = "stale `ImageInfo`")]
+    fn stale_image_info() {
+        let cached = ImageInfo {
+            extent: [4, 4],
+            stride: 16,
+            format: Format::Argb8888,
+            ..ImageInfo::default()
+        };
+        // `update_surface` resized the surface after `cached` was obtained
+        let current = ImageInfo {
+            extent: [8, 4],
+            stride: 32,
+            ..cached
+        };
+        cached.assert_compatible(&current);
+    }
 
-        self.update_surface([size_w, size_h], format);
+    #[test]
+    fn image_info_compatible() {
+        let cached = ImageInfo {
+            extent: [4, 4],
+            stride: 16,
+            format: Format::Argb8888,
+            ..ImageInfo::default()
+        };
+        cached.assert_compatible(&ImageInfo {
+            color_space: ColorSpace::DisplayP3,
+            ..cached
+        });
     }
 
-    /// Enumerate supported pixel formats.
-    pub fn supported_formats(&self) -> impl Iterator<Item = Format> + '_ {
-        self.inner.supported_formats()
+    #[test]
+    fn image_info_subregion() {
+        let image_info = ImageInfo {
+            extent: [4, 3],
+            stride: 20,
+            format: Format::Argb8888,
+            ..ImageInfo::default()
+        };
+
+        let (sub, offset) = image_info.subregion([1, 2, 3, 1]);
+        assert_eq!(sub.extent, [3, 1]);
+        assert_eq!(sub.stride, 20);
+        assert_eq!(offset, 44);
+
+        let (sub, offset) = image_info.subregion([0, 0, 4, 3]);
+        assert_eq!(sub, image_info);
+        assert_eq!(offset, 0);
     }
 
-    /// Get the `ImageInfo` describing the current swapchain images.
-    pub fn image_info(&self) -> ImageInfo {
-        self.inner.image_info()
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn image_info_subregion_out_of_bounds() {
+        let image_info = ImageInfo {
+            extent: [4, 3],
+            stride: 16,
+            ..ImageInfo::default()
+        };
+        image_info.subregion([2, 0, 3, 1]);
     }
 
-    /// Get the number of swapchain images.
-    ///
-    /// This value is automatically calculated when `update_surface` is called.
-    ///
-    /// This value does not reflect the actual number of buffers that stand
-    /// between the application and the display hardware. It's only useful
-    /// when `does_preserve_image() == true` and the application wants to
-    /// track dirty regions in each swapchain image.
-    pub fn num_images(&self) -> usize {
-        self.inner.num_images()
+    #[test]
+    fn display() {
+        use alloc::string::ToString;
+
+        let image_info = ImageInfo {
+            extent: [1920, 1080],
+            stride: 7680,
+            format: Format::Xbgr8888,
+            ..ImageInfo::default()
+        };
+        assert_eq!(image_info.to_string(), "1920x1080 stride=7680 XBGR8888");
+        assert_eq!(Format::Argb8888.to_string(), "ARGB8888");
     }
 
-    /// Get a flag indicating whether swapchain images preserve their contents
-    /// when their indices are used again.
-    ///
-    /// If this function returns `true`, the application can optimize rendering
-    /// by only updating the dirty portions.
-    pub fn does_preserve_image(&self) -> bool {
-        self.inner.does_preserve_image()
+    #[test]
+    fn frame_interval_from_refresh_rate() {
+        use core::time::Duration;
+        assert_eq!(frame_interval(60_000), Duration::from_nanos(16_666_666));
+        assert_eq!(frame_interval(1_000), Duration::from_secs(1));
     }
 
-    /// Get the index of the next available swapchain image.
-    ///
-    /// Returns `None` if no image is available. In this case, the function
-    /// specified via [`ContextBuilder::with_ready_cb`] will be called when one
-    /// is ready. If you call `poll_next_image` for multiple times before the
-    /// callback function is called for the next time, and all of the calls to
-    /// `poll_next_image` returns `None`, then the callback function will be
-    /// called only once.
-    ///
-    /// The application cannot rely on image unavailability for metering the
-    /// rendering speed even if [`Config::vsync`] is enabled.
-    ///
-    /// `update_surface` may or may not cancel the deferred call to the
-    /// callback.
-    ///
-    /// If an image is returned, this method does not remove the image from
-    /// the set of avilable images. For example, if the application calls
-    /// `poll_next_image` repeatedly, it may return the same image index for
-    /// all of the calls.
-    pub fn poll_next_image(&self) -> Option<usize> {
-        self.inner.poll_next_image()
+    #[test]
+    fn buffer_resize() {
+        let mut buffer = Buffer::from_size_align(4, 4).unwrap();
+        buffer.copy_from_slice(&[1, 2, 3, 4]);
+
+        // The allocation is kept, and the stale contents are exposed again
+        buffer.resize_no_clear(2);
+        assert_eq!(buffer.capacity(), 4);
+        buffer.resize_no_clear(6);
+        assert_eq!(&buffer[..], [1, 2, 3, 4, 0, 0]);
+
+        // `resize` zero-fills the added bytes
+        buffer.resize(2);
+        buffer.resize(4);
+        assert_eq!(&buffer[..], [1, 2, 0, 0]);
     }
 
-    /// Lock a swapchain image at index `i` to access its contents.
-    ///
-    /// `i` must be the index of a swapchain image acquired by `poll_next_image`.
-    ///
-    /// Panics if the image is currently locked or not ready to be accessed by
-    /// the application.
-    ///
-    /// Given an `ImageInfo`, the length is calculated as:
-    /// `extent[1] * stride * 4`.
-    pub fn lock_image(&self, i: usize) -> impl Deref<Target = [u8]> + DerefMut + '_ {
-        self.inner.lock_image(i)
+    #[test]
+    fn format_codes() {
+        assert_eq!(Format::Argb8888.wl_shm_code(), Some(0));
+        assert_eq!(Format::Xrgb8888.wl_shm_code(), Some(1));
+        assert_eq!(Format::Xbgr8888.wl_shm_code(), Some(0x34324258));
+
+        assert_eq!(Format::Argb8888.fourcc(), Some(*b"AR24"));
+        assert_eq!(Format::Xrgb8888.fourcc(), Some(*b"XR24"));
+        assert_eq!(Format::Xbgr8888.fourcc(), Some(*b"XB24"));
+
+        assert_eq!(Format::Argb8888.gdi_bitcount(), Some(32));
+        assert_eq!(Format::Xrgb8888.gdi_bitcount(), Some(32));
+        assert_eq!(Format::Xbgr8888.gdi_bitcount(), None);
     }
 
-    /// Enqueue the presentation of a swapchain image at index `i`.
-    ///
-    /// This method removes the swapchain image at index `i` from the set of
-    /// available images and enqueues it for presentation.
-    ///
-    /// `i` must be the index of a swapchain image acquired by `poll_next_image`.
-    /// The image must not be locked by `lock_image`.
-    pub fn present_image(&self, i: usize) {
-        self.inner.present_image(i)
+    #[test]
+    #[should_panic(expected = "too small")]
+    fn pixel_iter_too_small() {
+        let image_info = ImageInfo {
+            extent: [2, 2],
+            stride: 8,
+            format: Format::Argb8888,
+            ..ImageInfo::default()
+        };
+        let _ = image_info.pixel_iter(&[0; 15]);
+    }
+
+    #[test]
+    fn stride_padding() {
+        let image_info = |extent, stride| ImageInfo {
+            extent,
+            stride,
+            format: Format::Argb8888,
+            ..ImageInfo::default()
+        };
+        assert_eq!(image_info([4, 4], 16).stride_padding(), 0);
+        assert_eq!(image_info([3, 4], 16).stride_padding(), 4);
+        assert_eq!(image_info([0, 0], 0).stride_padding(), 0);
+
+        assert!(image_info([4, 4], 16).is_compact());
+        assert!(!image_info([3, 4], 16).is_compact());
+    }
+
+    #[test]
+    fn aspect_ratio() {
+        let image_info = |extent| ImageInfo {
+            extent,
+            ..ImageInfo::default()
+        };
+        assert_eq!(image_info([4, 2]).aspect_ratio(), 2.0);
+        assert_eq!(image_info([0, 0]).aspect_ratio(), 1.0);
+        assert_eq!(image_info([4, 0]).aspect_ratio(), 1.0);
+
+        assert!(image_info([4, 2]).is_landscape());
+        assert!(image_info([2, 4]).is_portrait());
+        assert!(image_info([3, 3]).is_square());
+        assert!(!image_info([3, 3]).is_landscape() && !image_info([3, 3]).is_portrait());
+    }
+
+    #[test]
+    fn clip_rect() {
+        let image_info = ImageInfo {
+            extent: [4, 3],
+            stride: 16,
+            format: Format::Argb8888,
+            ..ImageInfo::default()
+        };
+        assert_eq!(image_info.clip_rect([1, 1, 2, 2]), Some([1, 1, 2, 2]));
+        assert_eq!(image_info.clip_rect([2, 1, 5, 5]), Some([2, 1, 2, 2]));
+        assert_eq!(image_info.clip_rect([4, 0, 1, 1]), None);
+        assert_eq!(image_info.clip_rect([0, 0, 0, 1]), None);
+        assert_eq!(
+            image_info.clip_rect([3, 2, u32::MAX, u32::MAX]),
+            Some([3, 2, 1, 1])
+        );
+    }
+
+    #[test]
+    fn image_info_covers() {
+        let image_info = |extent, stride| ImageInfo {
+            extent,
+            stride,
+            format: Format::Argb8888,
+            ..ImageInfo::default()
+        };
+        let a = image_info([4, 4], 16);
+        let b = image_info([2, 3], 8);
+        let c = image_info([5, 1], 20);
+
+        assert_eq!(a.area(), 16);
+        assert!(a.covers(&b));
+        assert!(!b.covers(&a));
+        assert!(!a.covers(&c));
+
+        assert!(a > b);
+        assert!(b < a);
+        assert_eq!(a.partial_cmp(&c), None);
+        assert_eq!(a.partial_cmp(&a), Some(cmp::Ordering::Equal));
+        assert_eq!(a.partial_cmp(&image_info([4, 4], 32)), None);
+    }
+
+    #[test]
+    fn scale_nearest_upscale() {
+        let src_info = ImageInfo {
+            extent: [2, 2],
+            stride: 12,
+            format: Format::Argb8888,
+            ..ImageInfo::default()
+        };
+        #[rustfmt::skip]
+        let src = [
+            1, 1, 1, 1, 2, 2, 2, 2, 9, 9, 9, 9,
+            3, 3, 3, 3, 4, 4, 4, 4, 9, 9, 9, 9,
+        ];
+
+        let dst_info = ImageInfo {
+            extent: [4, 3],
+            stride: 16,
+            format: Format::Argb8888,
+            ..ImageInfo::default()
+        };
+        let mut dst = [0u8; 16 * 3];
+
+        scale_nearest(&src, &src_info, &mut dst, &dst_info);
+
+        let pixels: Vec<u8> = dst.chunks(4).map(|p| p[0]).collect();
+        #[rustfmt::skip]
+        assert_eq!(pixels, vec![
+            1, 1, 2, 2,
+            1, 1, 2, 2,
+            3, 3, 4, 4,
+        ]);
+    }
+
+    #[test]
+    fn read_region_padded_stride() {
+        let image_info = ImageInfo {
+            extent: [3, 3],
+            stride: 16,
+            format: Format::Argb8888,
+            ..ImageInfo::default()
+        };
+        let data: Vec<u8> = (0..16 * 3).map(|i| i as u8).collect();
+
+        let mut out = [0u8; 2 * 2 * 4];
+        read_region(&image_info, &data, [1, 1, 2, 2], &mut out);
+
+        #[rustfmt::skip]
+        assert_eq!(out, [
+            20, 21, 22, 23, 24, 25, 26, 27,
+            36, 37, 38, 39, 40, 41, 42, 43,
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn read_region_out_of_bounds() {
+        let image_info = ImageInfo {
+            extent: [3, 3],
+            stride: 12,
+            format: Format::Argb8888,
+            ..ImageInfo::default()
+        };
+        read_region(&image_info, &[0; 36], [2, 0, 2, 1], &mut [0; 8]);
+    }
+
+    #[test]
+    fn scanlines_padded_stride() {
+        let image_info = ImageInfo {
+            extent: [3, 4],
+            stride: 16,
+            format: Format::Argb8888,
+            ..ImageInfo::default()
+        };
+        let mut data = vec![0u8; 16 * 4];
+
+        let mut rows = Vec::new();
+        for_each_scanline_mut(&image_info, &mut data, |y, row| {
+            assert_eq!(row.len(), 12);
+            for x in row.iter_mut() {
+                *x = y as u8 + 1;
+            }
+            rows.push(y);
+        });
+
+        assert_eq!(rows, vec![0, 1, 2, 3]);
+        for (y, row) in data.chunks(16).enumerate() {
+            assert_eq!(&row[..12], &[y as u8 + 1; 12][..]);
+            assert_eq!(&row[12..], &[0; 4][..]);
+        }
+    }
+
+    #[test]
+    #[cfg(all(
+        feature = "backends",
+        any(
+            target_os = "linux",
+            target_os = "dragonfly",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "openbsd"
+        )
+    ))]
+    fn merge_rects_strategies() {
+        let rects = vec![[0, 0, 2, 2], [10, 0, 2, 2], [1, 1, 2, 2]];
+
+        let mut r = rects.clone();
+        merge_rects(&mut r, MergeStrategy::None);
+        assert_eq!(r, rects);
+
+        let mut r = rects.clone();
+        merge_rects(&mut r, MergeStrategy::BoundingBox);
+        assert_eq!(r, [[0, 0, 12, 3]]);
+
+        // The two overlapping rectangles are merged first
+        let mut r = rects.clone();
+        merge_rects(&mut r, MergeStrategy::MaxRects(2));
+        assert_eq!(r, [[0, 0, 3, 3], [10, 0, 2, 2]]);
+
+        let mut r = Vec::new();
+        merge_rects(&mut r, MergeStrategy::BoundingBox);
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    #[cfg(all(
+        feature = "backends",
+        any(
+            target_os = "linux",
+            target_os = "dragonfly",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "openbsd"
+        )
+    ))]
+    fn rect_difference_scroll() {
+        // Scrolling up by 2 rows uncovers the bottom 2 rows
+        assert_eq!(
+            rect_difference([0, 2, 10, 8], [0, 0, 10, 8]),
+            vec![[0, 8, 10, 2]]
+        );
+        // Scrolling right by 3 columns uncovers the left 3 columns
+        assert_eq!(
+            rect_difference([0, 0, 10, 8], [3, 0, 10, 8]),
+            vec![[0, 0, 3, 8]]
+        );
+        assert_eq!(
+            rect_difference([0, 0, 4, 4], [1, 1, 2, 2]),
+            vec![[0, 0, 4, 1], [0, 3, 4, 1], [0, 1, 1, 2], [3, 1, 1, 2]]
+        );
+        assert_eq!(
+            rect_difference([0, 0, 4, 4], [4, 0, 4, 4]),
+            vec![[0, 0, 4, 4]]
+        );
+        assert!(rect_difference([0, 0, 4, 4], [0, 0, 4, 4]).is_empty());
+    }
+
+    #[test]
+    #[cfg(all(
+        feature = "backends",
+        any(
+            target_os = "linux",
+            target_os = "dragonfly",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "openbsd"
+        )
+    ))]
+    fn wl_shm_format_roundtrip() {
+        use wayland_client::protocol::wl_shm::Format as ShmFormat;
+
+        for &format in &[Format::Argb8888, Format::Xrgb8888, Format::Xbgr8888] {
+            assert_eq!(Format::from_wl_shm(format.to_wl_shm()), Some(format));
+        }
+        assert_eq!(Format::from_wl_shm(ShmFormat::Rgb565), None);
     }
 }
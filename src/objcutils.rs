@@ -1,15 +1,17 @@
 // Based on <https://github.com/rust-windowing/winit/blob/master/src/platform/macos/window.rs>
-use cocoa::{
-    base::{id, nil},
-    foundation::NSAutoreleasePool,
-};
-use objc::{msg_send, sel, sel_impl};
+use objc::{class, msg_send, runtime::Object, sel, sel_impl};
 use std::ops::Deref;
 
+#[allow(non_camel_case_types)]
+pub type id = *mut Object;
+#[allow(non_upper_case_globals)]
+pub const nil: id = 0 as id;
+
 #[derive(Debug)]
 pub struct IdRef(id);
 
 impl IdRef {
+    #[allow(dead_code)]
     pub fn new(i: id) -> IdRef {
         IdRef(i)
     }
@@ -22,6 +24,7 @@ impl IdRef {
         IdRef(i)
     }
 
+    #[allow(dead_code)]
     pub fn non_nil(self) -> Option<IdRef> {
         if self.0 == nil {
             None
@@ -43,7 +46,7 @@ impl Drop for IdRef {
 
 impl Deref for IdRef {
     type Target = id;
-    fn deref<'a>(&'a self) -> &'a id {
+    fn deref(&self) -> &id {
         &self.0
     }
 }
@@ -62,7 +65,7 @@ pub struct AutoreleasePool(id);
 
 impl AutoreleasePool {
     pub fn new() -> Self {
-        Self(unsafe { NSAutoreleasePool::new(nil) })
+        Self(unsafe { msg_send![class!(NSAutoreleasePool), new] })
     }
 }
 
@@ -0,0 +1,3806 @@
+//! Platform-independent frontend of the backends (`Surface`, `Context`, and
+//! the `winit` integration)
+use log::{debug, warn};
+use raw_window_handle::{RawDisplayHandle, RawWindowHandle};
+use std::{
+    cell::{Cell, RefCell},
+    cmp,
+    collections::HashMap,
+    fmt,
+    ops::DerefMut,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use winit::{
+    event::Event,
+    event_loop::{ControlFlow, EventLoop},
+    window::{Window, WindowId},
+};
+
+use super::{
+    align::Align, for_each_scanline_mut, frame_interval, read_region, scale_nearest, Config,
+    ContextImpl, Format, ImageInfo, MergeStrategy, SubSurfaceImpl, SurfaceImpl, Transform,
+    TransparentFallback,
+};
+
+/// A software-rendered window.
+///
+/// This is a safe wrapper around [`Surface`] and [`winit::window::Window`].
+/// For each method, only a synopsis is provided here. See `Surface`'s
+/// documentation for a full documentation.
+///
+/// `SwWindow` dereferences to `Surface`, so it can be passed to functions
+/// taking `&Surface`. `DerefMut` isn't provided because swapping the
+/// `Surface` out would detach it from the window it was created for.
+#[derive(Debug)]
+pub struct SwWindow {
+    surface: Option<Surface>,
+    window: Option<Window>,
+    /// Set by [`SwWindow::set_wait_released_on_drop`].
+    wait_released_on_drop: Cell<Option<Duration>>,
+}
+
+impl SwWindow {
+    /// Construct a `SwWindow` by wrapping an existing `Window`.
+    pub fn new(window: Window, context: &Context, config: &Config) -> Self {
+        Self {
+            surface: Some(unsafe { Surface::new(&window, context, config) }),
+            window: Some(window),
+            wait_released_on_drop: Cell::new(None),
+        }
+    }
+
+    /// Construct a transparent `SwWindow` by wrapping an existing `Window`.
+    ///
+    /// The surface is created with `opaque == false` and
+    /// `transparent_fallback == TransparentFallback::Error`, and is then
+    /// sized to fit the window using `Format::Argb8888`. `window` must have
+    /// been created with `WindowBuilder::with_transparent(true)`.
+    ///
+    /// Panics if the backend can't honor per-pixel alpha for `window`, e.g.,
+    /// on X11 without a compositing manager.
+    pub fn new_transparent(window: Window, context: &Context) -> Self {
+        let config = Config {
+            opaque: false,
+            transparent_fallback: TransparentFallback::Error,
+            ..Default::default()
+        };
+
+        let surface = match unsafe { Surface::try_new(&window, context, &config) } {
+            Ok(surface) => surface,
+            Err(SurfaceError::TransparencyUnsupported) => panic!(
+                "the window doesn't support transparency; make sure it was \
+                 created with `with_transparent(true)` and a compositing \
+                 manager is running"
+            ),
+            Err(e) => panic!("{}", e),
+        };
+        surface.update_surface_to_fit(&window, Format::Argb8888);
+
+        Self {
+            surface: Some(surface),
+            window: Some(window),
+            wait_released_on_drop: Cell::new(None),
+        }
+    }
+
+    /// Detach the surface and get the wrapped [`winit::window::Window`].
+    pub fn into_window(mut self) -> Window {
+        // Deconstruct the surface first
+        self.drop_surface();
+
+        self.window.take().unwrap()
+    }
+
+    /// Split the `Window` apart from the `Surface`.
+    ///
+    /// # Safety
+    ///
+    /// The `Surface` must be dropped before the `Window`.
+    pub unsafe fn split(mut self) -> (Surface, Window) {
+        (self.surface.take().unwrap(), self.window.take().unwrap())
+    }
+
+    /// Get a reference to the wrapped [`winit::window::Window`].
+    pub fn window(&self) -> &Window {
+        self.window.as_ref().unwrap()
+    }
+
+    /// Get a mutable reference to the wrapped [`winit::window::Window`].
+    pub fn window_mut(&mut self) -> &mut Window {
+        self.window.as_mut().unwrap()
+    }
+
+    /// Call `f` with shared references to the wrapped
+    /// [`winit::window::Window`] and [`Surface`].
+    pub fn with<R>(&self, f: impl FnOnce(&Window, &Surface) -> R) -> R {
+        f(self.window(), self)
+    }
+
+    /// Update the properties of the surface.
+    pub fn update_surface(&self, extent: [u32; 2], format: Format) {
+        (**self).update_surface(extent, format);
+    }
+
+    /// Apply a new `Config` to the surface without recreating it.
+    pub fn reconfigure(&self, config: &Config) -> Result<(), SurfaceError> {
+        (**self).reconfigure(config)
+    }
+
+    /// Change the number of swapchain images without recreating the surface.
+    pub fn resize_swapchain(&self, count: usize) -> Result<(), SurfaceError> {
+        (**self).resize_swapchain(count)
+    }
+
+    /// Enable or disable vertical synchronization at runtime.
+    pub fn set_vsync(&self, enable: bool) {
+        (**self).set_vsync(enable)
+    }
+
+    /// Get the current value of [`Config::vsync`].
+    pub fn vsync_enabled(&self) -> bool {
+        (**self).vsync_enabled()
+    }
+
+    /// Update the properties of the surface, overriding the scanline alignment.
+    pub fn update_surface_aligned(&self, extent: [u32; 2], format: Format, scanline_align: usize) {
+        (**self).update_surface_aligned(extent, format, scanline_align);
+    }
+
+    /// Update the properties of the surface. The surface size is automatically
+    /// derived based on the window size.
+    pub fn update_surface_to_fit(&self, format: Format) {
+        (**self).update_surface_to_fit(self.window(), format);
+    }
+
+    /// Update the properties of the surface, rescaling the existing contents
+    /// of the swapchain images to the new size.
+    pub fn update_surface_scaling_content(&self, extent: [u32; 2], format: Format) {
+        (**self).update_surface_scaling_content(extent, format);
+    }
+
+    /// Set the transformation applied to the surface contents when presenting
+    /// them.
+    pub fn set_transform(&self, transform: Transform) {
+        (**self).set_transform(transform)
+    }
+
+    /// Get the transformation applied to the surface contents when presenting
+    /// them.
+    pub fn transform(&self) -> Transform {
+        (**self).transform()
+    }
+
+    /// Enumerate supported pixel formats.
+    pub fn supported_formats(&self) -> impl Iterator<Item = Format> + '_ {
+        (**self).supported_formats()
+    }
+
+    /// Check if the window can display per-pixel transparency.
+    pub fn supports_transparency(&self) -> bool {
+        (**self).supports_transparency()
+    }
+
+    /// Get the scanline alignment natural to the backend.
+    pub fn default_scanline_align(&self) -> Align {
+        (**self).default_scanline_align()
+    }
+
+    /// Choose the first pixel format in `preference` that is supported.
+    pub fn best_format(&self, preference: &[Format]) -> Option<Format> {
+        (**self).best_format(preference)
+    }
+
+    /// Get the `ImageInfo` describing the current swapchain images.
+    pub fn image_info(&self) -> ImageInfo {
+        (**self).image_info()
+    }
+
+    /// Get the size (`[width, height]`) of the area the application should
+    /// render into, in the untransformed orientation.
+    ///
+    /// This is the extent of the swapchain images once `update_surface` has
+    /// been called. Before that, this is the size `update_surface_to_fit`
+    /// would choose, i.e., the window's physical size with
+    /// [`SwWindow::transform`] taken into account.
+    pub fn drawing_area(&self) -> [u32; 2] {
+        if self.initialized.get() {
+            self.image_info().extent
+        } else {
+            let (size_w, size_h) = self.window().inner_size().into();
+            self.transform().transform_extent([size_w, size_h])
+        }
+    }
+
+    /// Get the current size of the swapchain images, or `None` if the surface
+    /// is not initialized yet.
+    pub fn physical_size(&self) -> Option<[u32; 2]> {
+        (**self).physical_size()
+    }
+
+    /// Get the number of swapchain images.
+    pub fn num_images(&self) -> usize {
+        (**self).num_images()
+    }
+
+    /// Get a flag indicating whether swapchain images preserve their contents
+    /// when their indices are used again.
+    pub fn does_preserve_image(&self) -> bool {
+        (**self).does_preserve_image()
+    }
+
+    /// Get a flag indicating whether the swapchain image at index `i` is
+    /// currently locked.
+    pub fn is_locked(&self, i: usize) -> bool {
+        (**self).is_locked(i)
+    }
+
+    /// Get a flag indicating whether the swapchain image at index `i` is
+    /// currently in use by the presentation engine.
+    pub fn is_presenting(&self, i: usize) -> bool {
+        (**self).is_presenting(i)
+    }
+
+    /// Get the number of swapchain images currently in use by the
+    /// presentation engine.
+    pub fn num_images_presenting(&self) -> usize {
+        (**self).num_images_presenting()
+    }
+
+    /// Get the number of swapchain images that are neither locked nor in use
+    /// by the presentation engine.
+    pub fn num_images_ready(&self) -> usize {
+        (**self).num_images_ready()
+    }
+
+    /// Get the indices of all swapchain images that are currently not in use
+    /// by the presentation engine.
+    pub fn available_images(&self) -> impl Iterator<Item = usize> + '_ {
+        (**self).available_images()
+    }
+
+    /// Get the `ControlFlow` with which the event loop should wait for a
+    /// swapchain image to become available.
+    pub fn control_flow_until_ready(&self) -> ControlFlow {
+        (**self).control_flow_until_ready()
+    }
+
+    /// Get the index of the next available swapchain image. Blocks the current
+    /// thread.
+    pub fn poll_next_image(&self) -> Option<usize> {
+        (**self).poll_next_image()
+    }
+
+    /// Get the backend used by the surface.
+    pub fn backend(&self) -> Backend {
+        (**self).backend()
+    }
+
+    /// Get the refresh interval of the monitor the window is on.
+    pub fn target_frame_interval(&self) -> Option<Duration> {
+        (**self).target_frame_interval(self.window())
+    }
+
+    /// Get the index of the next available swapchain image, blocking the
+    /// current thread for up to `timeout` if none is available.
+    pub fn acquire_with_timeout(&self, timeout: Duration) -> AcquireResult {
+        (**self).acquire_with_timeout(timeout)
+    }
+
+    /// Let the backend observe an event received from `winit`'s event loop.
+    pub fn handle_os_event<T>(&self, event: &Event<T>) {
+        (**self).handle_os_event(event)
+    }
+
+    /// Wait until the presentation engine releases all swapchain images.
+    pub fn wait_all_released(&self, timeout: Duration) -> bool {
+        (**self).wait_all_released(timeout)
+    }
+
+    /// Make the destruction of the surface (by `drop` or `into_window`) call
+    /// [`Surface::wait_all_released`] with the specified timeout first.
+    /// `None` (the default) disables this.
+    ///
+    /// On Wayland, the buffer is detached from the surface before waiting
+    /// because the compositor keeps the last attached buffer until another
+    /// one is attached.
+    pub fn set_wait_released_on_drop(&self, timeout: Option<Duration>) {
+        self.wait_released_on_drop.set(timeout);
+    }
+
+    fn drop_surface(&mut self) {
+        if let Some(surface) = self.surface.take() {
+            if let Some(timeout) = self.wait_released_on_drop.get() {
+                // The window is going away, so stop showing the last image
+                // to let the presentation engine release it
+                surface.inner.detach();
+                surface.wait_all_released(timeout);
+            }
+        }
+    }
+
+    /// Lock a swapchain image at index `i` to access its contents.
+    pub fn lock_image(&self, i: usize) -> impl DerefMut<Target = [u8]> + '_ {
+        (**self).lock_image(i)
+    }
+
+    /// Lock a swapchain image at index `i`, returning `None` if it's
+    /// currently locked.
+    pub fn lock_image_timeout(
+        &self,
+        i: usize,
+        timeout: Duration,
+    ) -> Option<impl DerefMut<Target = [u8]> + '_> {
+        (**self).lock_image_timeout(i, timeout)
+    }
+
+    /// Lock a swapchain image at index `i` to stream pixel data into it.
+    pub fn lock_image_writer(&self, i: usize) -> LockedImage<'_> {
+        (**self).lock_image_writer(i)
+    }
+
+    /// Lock the rows of a swapchain image at index `i` covered by `rect`. The
+    /// image is presented when the returned guard is dropped.
+    pub fn lock_image_region(&self, i: usize, rect: [u32; 4]) -> RegionGuard<'_> {
+        (**self).lock_image_region(i, rect)
+    }
+
+    /// Lock a swapchain image at index `i` to access its contents as a slice
+    /// of `T`.
+    #[cfg(feature = "bytemuck")]
+    pub fn lock_image_as<T: bytemuck::Pod>(&self, i: usize) -> impl DerefMut<Target = [T]> + '_ {
+        (**self).lock_image_as(i)
+    }
+
+    /// Lock a swapchain image at index `i` for drawing with the `image` crate.
+    /// The image is presented when the returned guard is dropped.
+    #[cfg(feature = "image")]
+    pub fn borrow_as_image_buffer(&self, i: usize) -> ImageBufferGuard<'_> {
+        (**self).borrow_as_image_buffer(i)
+    }
+
+    /// Enqueue the presentation of a swapchain image at index `i`.
+    pub fn present_image(&self, i: usize) {
+        (**self).present_image(i)
+    }
+
+    /// Enqueue the presentation of a swapchain image at index `i`, returning
+    /// an error if the presentation engine failed to present it.
+    pub fn try_present_image(&self, i: usize) -> Result<(), SurfaceError> {
+        (**self).try_present_image(i)
+    }
+
+    /// Acquire and lock the next available swapchain image. The image is
+    /// presented when the returned guard is dropped or passed to
+    /// [`SwWindow::present_frame`].
+    pub fn acquire_frame(&self) -> Option<ImageFrame<'_>> {
+        (**self).acquire_frame()
+    }
+
+    /// Unlock and enqueue the presentation of a swapchain image acquired by
+    /// [`SwWindow::acquire_frame`].
+    pub fn present_frame(&self, frame: ImageFrame<'_>) {
+        (**self).present_frame(frame)
+    }
+
+    /// Acquire a swapchain image, fill it with black, and present it.
+    pub fn present_black_frame(&self) -> bool {
+        (**self).present_black_frame()
+    }
+
+    /// Enqueue the presentation of a swapchain image at index `i` after the
+    /// fence `acquire_fence` is signaled.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    ))]
+    pub fn present_image_with_sync(
+        &self,
+        i: usize,
+        acquire_fence: Option<std::os::unix::io::OwnedFd>,
+    ) -> Option<std::os::unix::io::OwnedFd> {
+        (**self).present_image_with_sync(i, acquire_fence)
+    }
+
+    /// Enqueue the presentation of a swapchain image at index `i` without
+    /// waiting for the vertical blank.
+    pub fn present_image_immediately(&self, i: usize) {
+        (**self).present_image_immediately(i)
+    }
+
+    /// Enqueue the presentation of a swapchain image at index `i`, displacing
+    /// the contents by `offset`.
+    pub fn present_image_at(&self, i: usize, offset: [i32; 2]) {
+        (**self).present_image_at(i, offset)
+    }
+
+    /// Enqueue the presentation of a swapchain image at index `i`, indicating
+    /// that the image differs from the previously presented one only by a
+    /// scroll.
+    pub fn present_region(&self, i: usize, src_rect: [u32; 4], dst_pos: [u32; 2]) {
+        (**self).present_region(i, src_rect, dst_pos)
+    }
+
+    /// Set the minimum interval between presentations.
+    pub fn set_present_rate_limit(&self, min_interval: Duration) {
+        (**self).set_present_rate_limit(min_interval)
+    }
+
+    /// Get the value previously set by `set_present_rate_limit`.
+    pub fn present_rate_limit(&self) -> Duration {
+        (**self).present_rate_limit()
+    }
+
+    /// Set the maximum number of swapchain images that can be in use by the
+    /// presentation engine at once.
+    pub fn set_max_frame_latency(&self, frames: u32) {
+        (**self).set_max_frame_latency(frames)
+    }
+
+    /// Get the value previously set by `set_max_frame_latency`.
+    pub fn max_frame_latency(&self) -> u32 {
+        (**self).max_frame_latency()
+    }
+
+    /// Read back the contents currently displayed in the window.
+    #[cfg(feature = "screen-capture")]
+    pub fn screen_capture(&self) -> Option<Vec<u8>> {
+        (**self).screen_capture(self.window())
+    }
+
+    /// Get the age of the contents of the swapchain image at index `i`,
+    /// measured in frames.
+    pub fn image_age(&self, i: usize) -> u32 {
+        (**self).image_age(i)
+    }
+
+    /// Check if `update_surface` was called after the swapchain image at
+    /// index `i` was last presented.
+    pub fn extent_changed_since_last_present(&self, i: usize) -> bool {
+        (**self).extent_changed_since_last_present(i)
+    }
+
+    /// Mark the contents of all swapchain images as unknown.
+    pub fn invalidate(&self, clear: bool) {
+        (**self).invalidate(clear)
+    }
+
+    /// Get the number of swapchain images currently in use by the
+    /// presentation engine.
+    pub fn pending_present_count(&self) -> usize {
+        (**self).pending_present_count()
+    }
+
+    /// Lock a swapchain image at index `i`, pass its contents to `f`, and
+    /// enqueue its presentation.
+    pub fn map_and_present(&self, i: usize, f: impl FnOnce(&mut [u8], ImageInfo)) {
+        (**self).map_and_present(i, f)
+    }
+
+    /// Wait until a swapchain image becomes available, and then render and
+    /// present it using `f`.
+    #[cfg(feature = "async")]
+    pub async fn acquire_and_render_async(&self, f: impl FnOnce(&mut [u8], ImageInfo)) {
+        (**self).acquire_and_render_async(f).await
+    }
+
+    /// Lock a swapchain image at index `i` and call `f` for each scanline.
+    pub fn map_scanlines_mut(&self, i: usize, f: impl FnMut(u32, &mut [u8])) {
+        (**self).map_scanlines_mut(i, f)
+    }
+
+    /// Copy an image from the memory pointed to by `src` to the swapchain
+    /// image at index `i` and enqueue its presentation.
+    ///
+    /// # Safety
+    ///
+    /// See [`Surface::copy_from_raw`].
+    pub unsafe fn copy_from_raw(&self, i: usize, src: *const u8, src_info: ImageInfo) {
+        (**self).copy_from_raw(i, src, src_info)
+    }
+
+    /// Copy the contents of the swapchain image at index `src_i` to the one at
+    /// index `dst_i`.
+    pub fn copy_image(&self, src_i: usize, dst_i: usize) {
+        (**self).copy_image(src_i, dst_i)
+    }
+
+    /// Copy the pixels inside `rect` of the swapchain image at index `i` to
+    /// `out` without padding between rows.
+    pub fn read_region(&self, i: usize, rect: [u32; 4], out: &mut [u8]) {
+        (**self).read_region(i, rect, out)
+    }
+
+    /// Fill the swapchain image at index `i` with a solid color.
+    pub fn clear_with_color(&self, i: usize, r: u8, g: u8, b: u8, a: u8) {
+        (**self).clear_with_color(i, r, g, b, a)
+    }
+
+    /// Enable or disable the blur effect applied to the content behind the
+    /// window.
+    pub fn set_blur_behind(&self, enabled: bool) {
+        (**self).set_blur_behind(enabled)
+    }
+
+    /// Get a flag indicating whether [`Config::content_protection`] is in
+    /// effect.
+    pub fn is_content_protection_active(&self) -> bool {
+        (**self).is_content_protection_active()
+    }
+
+    /// Create a [`SubSurface`] covering the region `rect` of the window.
+    pub fn create_subsurface(&self, rect: [u32; 4]) -> SubSurface<'_> {
+        (**self).create_subsurface(rect)
+    }
+
+    /// Set the region of the window that accepts pointer input.
+    pub fn set_input_region(&self, region: Option<&[[u32; 4]]>) {
+        (**self).set_input_region(region)
+    }
+
+    /// Copy the contents of the swapchain image at index `i` to a
+    /// [`softbuffer`] buffer.
+    #[cfg(feature = "softbuffer-compat")]
+    pub fn export_image_to_softbuffer<D, W>(
+        &self,
+        i: usize,
+        sb_buffer: &mut softbuffer::Buffer<'_, D, W>,
+    ) where
+        D: raw_window_handle_06::HasDisplayHandle,
+        W: raw_window_handle_06::HasWindowHandle,
+    {
+        (**self).export_image_to_softbuffer(i, sb_buffer)
+    }
+
+    /// Set how damaged regions are coalesced when presenting an image.
+    pub fn set_damage_merge_strategy(&self, strategy: MergeStrategy) {
+        (**self).set_damage_merge_strategy(strategy)
+    }
+
+    /// Attach a human-readable label to the surface for debugging.
+    pub fn set_debug_label(&self, label: impl Into<String>) {
+        (**self).set_debug_label(label)
+    }
+}
+
+/// Prints a concise summary, e.g., `SwWindow(id=WindowId(..),
+/// extent=1920x1080, format=ARGB8888, backend=wayland)`.
+impl fmt::Display for SwWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let image_info = self.image_info();
+        write!(
+            f,
+            "SwWindow(id={:?}, extent={}x{}, format={}, backend={})",
+            self.window().id(),
+            image_info.extent[0],
+            image_info.extent[1],
+            image_info.format,
+            self.backend()
+        )
+    }
+}
+
+impl std::ops::Deref for SwWindow {
+    type Target = Surface;
+
+    fn deref(&self) -> &Surface {
+        self.surface.as_ref().unwrap()
+    }
+}
+
+impl Drop for SwWindow {
+    fn drop(&mut self) {
+        // Deconstruct the surface first
+        self.drop_surface();
+    }
+}
+
+/// A [`Surface`] that keeps the underlying [`winit::window::Window`] alive.
+///
+/// Unlike [`Surface::new`], constructing this type is safe because it holds
+/// a strong reference to the window, which therefore outlives the surface.
+/// Use this when the window is shared with other parts of the application and
+/// [`SwWindow`] doesn't fit.
+#[derive(Debug)]
+pub struct SwSurface {
+    // Fields are dropped in the declaration order, so `surface` is dropped
+    // before `window`
+    surface: Surface,
+    window: Rc<Window>,
+}
+
+impl SwSurface {
+    /// Construct and attach a surface to the specified window.
+    pub fn new(window: Rc<Window>, context: &Context, config: &Config) -> Self {
+        Self {
+            surface: unsafe { Surface::new(&window, context, config) },
+            window,
+        }
+    }
+
+    /// Get a reference to the wrapped [`winit::window::Window`].
+    pub fn window(&self) -> &Rc<Window> {
+        &self.window
+    }
+
+    /// Get a reference to the wrapped [`Surface`].
+    pub fn surface(&self) -> &Surface {
+        &self.surface
+    }
+
+    /// Update the properties of the surface. The surface size is automatically
+    /// derived based on the window size.
+    pub fn update_surface_to_fit(&self, format: Format) {
+        self.surface.update_surface_to_fit(&self.window, format);
+    }
+}
+
+/// A collection of [`SwWindow`]s keyed by `WindowId`, which routes the
+/// notifications of the ready callback to the matching windows.
+///
+/// The ready callback is installed by [`SwWindowSet::wire_ready_cb`], which
+/// records the notified windows in the set. The application calls
+/// [`SwWindowSet::dispatch_ready`] from the event loop to redraw them.
+#[derive(Debug, Default)]
+pub struct SwWindowSet {
+    windows: HashMap<WindowId, SwWindow>,
+    /// The windows notified by the ready callback but not dispatched yet
+    pending: Rc<RefCell<Vec<WindowId>>>,
+}
+
+impl SwWindowSet {
+    /// Construct an empty `SwWindowSet`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Specify the ready callback of `builder` (see
+    /// [`ContextBuilder::with_ready_cb`]) so that the notified windows are
+    /// recorded in the set for [`SwWindowSet::dispatch_ready`]. `wake` is
+    /// called afterwards, e.g., to wake up the event loop.
+    ///
+    /// This replaces the ready callback previously specified for `builder`.
+    /// Like the ready callback, `wake` is never called on backends where
+    /// swapchain images are always available.
+    pub fn wire_ready_cb<'a, T>(
+        &self,
+        builder: ContextBuilder<'a, T>,
+        wake: impl Fn(WindowId) + 'static,
+    ) -> ContextBuilder<'a, T> {
+        let pending = Rc::clone(&self.pending);
+        builder.with_ready_cb(move |id| {
+            pending.borrow_mut().push(id);
+            wake(id);
+        })
+    }
+
+    /// Add a window to the set. Returns the window previously stored for the
+    /// same `WindowId`, if any.
+    pub fn insert(&mut self, window: SwWindow) -> Option<SwWindow> {
+        self.windows.insert(window.window().id(), window)
+    }
+
+    /// Remove the window identified by `id` from the set.
+    pub fn remove(&mut self, id: WindowId) -> Option<SwWindow> {
+        self.windows.remove(&id)
+    }
+
+    /// Get a reference to the window identified by `id`.
+    pub fn get(&self, id: WindowId) -> Option<&SwWindow> {
+        self.windows.get(&id)
+    }
+
+    /// Get the number of windows in the set.
+    pub fn len(&self) -> usize {
+        self.windows.len()
+    }
+
+    /// Check if the set contains no windows.
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty()
+    }
+
+    /// Get an iterator over the windows in the set, in arbitrary order.
+    pub fn iter(&self) -> impl Iterator<Item = &SwWindow> + '_ {
+        self.windows.values()
+    }
+
+    /// Call `redraw` with the window identified by `id`. Returns `false` if
+    /// the set doesn't contain such a window.
+    pub fn ready(&self, id: WindowId, redraw: impl FnOnce(&SwWindow)) -> bool {
+        if let Some(window) = self.windows.get(&id) {
+            redraw(window);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Call `redraw` for each window notified by the ready callback since the
+    /// last call, at most once per window. Returns the number of calls.
+    ///
+    /// Notifications for the windows not in the set are discarded. `redraw`
+    /// may cause further notifications, which are left for the next call.
+    pub fn dispatch_ready(&self, mut redraw: impl FnMut(&SwWindow)) -> usize {
+        let pending = std::mem::take(&mut *self.pending.borrow_mut());
+        pending
+            .iter()
+            .enumerate()
+            .filter(|&(i, id)| !pending[..i].contains(id))
+            .filter(|&(_, &id)| self.ready(id, &mut redraw))
+            .count()
+    }
+}
+
+#[allow(dead_code)]
+pub struct ContextBuilder<'a, T: 'static> {
+    /// `None` if the builder was constructed by `with_display_handle`.
+    pub(crate) event_loop: Option<&'a EventLoop<T>>,
+    pub(crate) display_handle: Option<RawDisplayHandle>,
+    pub(crate) ready_cb: ReadyCb,
+    pub(crate) error_handler: ErrorHandler,
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    ))]
+    pub(crate) wayland_seat_cb: Option<super::unix::WaylandSeatCb>,
+    /// `true` if the builder was constructed by `with_wayland_from_env`.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    ))]
+    pub(crate) wayland_from_env: bool,
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    ))]
+    pub(crate) log_target: Option<&'static str>,
+    present_hooks: Option<PresentHooks>,
+}
+
+pub(crate) type ReadyCb = Box<dyn Fn(WindowId)>;
+
+pub(crate) type ErrorHandler = Box<dyn Fn(SurfaceError)>;
+
+/// The wakers of the futures returned by
+/// [`Surface::acquire_and_render_async`] waiting for the ready callback of
+/// the specified window.
+#[cfg(feature = "async")]
+type ReadyWakers = Rc<RefCell<Vec<(WindowId, std::task::Waker)>>>;
+
+/// The functions specified by [`ContextBuilder::with_present_hooks`].
+struct PresentHooks {
+    pre: Box<dyn Fn(WindowId, usize)>,
+    post: Box<dyn Fn(WindowId, usize)>,
+}
+
+impl fmt::Debug for PresentHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PresentHooks").finish()
+    }
+}
+
+/// The ready callback shared by a `Context` and its `Surface`s, which call
+/// it when the limit set by [`Surface::set_present_rate_limit`] expires.
+struct SharedReadyCb(ReadyCb);
+
+impl fmt::Debug for SharedReadyCb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedReadyCb").finish()
+    }
+}
+
+impl<'a, T: 'static> ContextBuilder<'a, T> {
+    /// Construct a `ContextBuilder`.
+    pub fn new(event_loop: &'a EventLoop<T>) -> Self {
+        Self::new_inner(Some(event_loop), None)
+    }
+
+    /// Construct a `ContextBuilder` for an existing display connection not
+    /// owned by `winit`, e.g., the one created by another GUI framework.
+    ///
+    /// `T` is unused in this case, so you can specify anything, e.g.,
+    /// `ContextBuilder::<()>::with_display_handle(handle)`.
+    ///
+    /// The backend is chosen based on the variant of `handle`. Panics when
+    /// the `Context` is built if `handle` is not supported by the current
+    /// platform. On Windows and macOS, `handle` is not used at all.
+    ///
+    /// # Safety
+    ///
+    /// The display connection referenced by `handle` must outlive the
+    /// constructed `Context` and all `Surface`s created from it.
+    pub unsafe fn with_display_handle(handle: RawDisplayHandle) -> Self {
+        Self::new_inner(None, Some(handle))
+    }
+
+    fn new_inner(
+        event_loop: Option<&'a EventLoop<T>>,
+        display_handle: Option<RawDisplayHandle>,
+    ) -> Self {
+        Self {
+            event_loop,
+            display_handle,
+            ready_cb: Box::new(|_| {}),
+            error_handler: Box::new(|e| warn!("{}", e)),
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "dragonfly",
+                target_os = "freebsd",
+                target_os = "netbsd",
+                target_os = "openbsd"
+            ))]
+            wayland_seat_cb: None,
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "dragonfly",
+                target_os = "freebsd",
+                target_os = "netbsd",
+                target_os = "openbsd"
+            ))]
+            wayland_from_env: false,
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "dragonfly",
+                target_os = "freebsd",
+                target_os = "netbsd",
+                target_os = "openbsd"
+            ))]
+            log_target: None,
+            present_hooks: None,
+        }
+    }
+
+    /// Construct a `ContextBuilder` that opens a new Wayland connection
+    /// specified by the environment (`$WAYLAND_DISPLAY`) using
+    /// `wl_display_connect`, for applications not using `winit`'s event loop.
+    ///
+    /// `T` is unused in this case, so you can specify anything. The created
+    /// `Context` owns the connection and disconnects it when the `Context` and
+    /// all `Surface`s created from it are dropped. Since nothing dispatches
+    /// the events of the connection by itself, the application should use
+    /// [`Surface::acquire_with_timeout`] to wait for swapchain images.
+    ///
+    /// Panics when the `Context` is built if the connection fails.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    ))]
+    pub fn with_wayland_from_env() -> Self {
+        Self {
+            wayland_from_env: true,
+            ..Self::new_inner(None, None)
+        }
+    }
+
+    /// Specify the function to be called when a swapchain image becomes
+    /// available.
+    pub fn with_ready_cb(self, cb: impl Fn(WindowId) + 'static) -> Self {
+        if ContextImpl::TAKES_READY_CB {
+            Self {
+                ready_cb: Box::new(cb),
+                ..self
+            }
+        } else {
+            self
+        }
+    }
+
+    /// Specify the function to be called with the `wl_seat` global bound by
+    /// the Wayland backend.
+    ///
+    /// The function receives the newly bound seat and must implement it
+    /// (e.g., by `NewProxy::implement_closure`) to receive its events, such as
+    /// `capabilities`, which indicates whether the seat has a pointer or
+    /// keyboard. The application can then use the seat to receive input
+    /// events over the same Wayland connection. The function is called at most
+    /// once, when the `Context` is built. It isn't called if the X11 backend
+    /// is chosen or the server does not advertise `wl_seat`.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    ))]
+    pub fn with_wayland_seat(
+        self,
+        cb: impl FnOnce(
+                wayland_client::NewProxy<wayland_client::protocol::wl_seat::WlSeat>,
+            ) -> wayland_client::protocol::wl_seat::WlSeat
+            + 'static,
+    ) -> Self {
+        Self {
+            wayland_seat_cb: Some(Box::new(cb)),
+            ..self
+        }
+    }
+
+    /// Specify the functions to be called synchronously right before and
+    /// after a [`Surface`] created from the context presents an image.
+    ///
+    /// Both functions receive the `WindowId` of the surface's window and the
+    /// index of the presented image. They are called by all presentation
+    /// methods of `Surface` (including [`PresentGroup`]) on all backends,
+    /// which makes them suitable for instrumentation, e.g., timing the
+    /// presentation or opening a tracing span. The post-present function is
+    /// called even if the presentation failed.
+    ///
+    /// Surfaces created by [`Surface::from_raw_handles`] report
+    /// `WindowId::dummy()`.
+    pub fn with_present_hooks(
+        self,
+        pre: impl Fn(WindowId, usize) + 'static,
+        post: impl Fn(WindowId, usize) + 'static,
+    ) -> Self {
+        Self {
+            present_hooks: Some(PresentHooks {
+                pre: Box::new(pre),
+                post: Box::new(post),
+            }),
+            ..self
+        }
+    }
+
+    /// Specify the function to be called when a non-fatal error occurs while
+    /// processing events in the background, e.g., when the compositor
+    /// releases a swapchain image that wasn't presented.
+    ///
+    /// The function is called on the event loop's thread while events are
+    /// dispatched. The `Context` and [`Surface`]s created from it remain
+    /// usable. By default, the errors are logged as warnings.
+    ///
+    /// Currently, only the Wayland backend reports errors this way.
+    pub fn with_error_handler(self, cb: impl Fn(SurfaceError) + 'static) -> Self {
+        Self {
+            error_handler: Box::new(cb),
+            ..self
+        }
+    }
+
+    /// Specify the target of the log messages emitted by the Wayland backend
+    /// on behalf of the `Context` and [`Surface`]s created from it, e.g.,
+    /// `"swsurface::window_1"`.
+    ///
+    /// This makes it possible to tell apart and filter the log messages of
+    /// multiple `Context`s. By default, the module path of the backend is
+    /// used, as with other `log` macro invocations.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    ))]
+    pub fn with_log_target(self, name: &'static str) -> Self {
+        Self {
+            log_target: Some(name),
+            ..self
+        }
+    }
+
+    /// Build a `Context`.
+    pub fn build(mut self) -> Context {
+        let present_hooks = self.present_hooks.take().map(Rc::new);
+
+        // Wake up the futures waiting for the ready callback before calling
+        // the application's one
+        #[cfg(feature = "async")]
+        let ready_wakers = {
+            let ready_wakers: ReadyWakers = Rc::default();
+            let ready_cb = std::mem::replace(&mut self.ready_cb, Box::new(|_| {}));
+            self.ready_cb = Box::new({
+                let ready_wakers = Rc::clone(&ready_wakers);
+                move |id| {
+                    let wakers: Vec<_> = {
+                        let mut ready_wakers = ready_wakers.borrow_mut();
+                        let (woken, rest) = ready_wakers.drain(..).partition(|(i, _)| *i == id);
+                        *ready_wakers = rest;
+                        woken
+                    };
+                    for (_, waker) in wakers {
+                        waker.wake();
+                    }
+                    ready_cb(id);
+                }
+            });
+            ready_wakers
+        };
+
+        let ready_cb = Rc::new(SharedReadyCb(std::mem::replace(
+            &mut self.ready_cb,
+            Box::new(|_| {}),
+        )));
+        self.ready_cb = Box::new({
+            let ready_cb = Rc::clone(&ready_cb);
+            move |id| (ready_cb.0)(id)
+        });
+
+        Context {
+            inner: ContextImpl::new(self),
+            num_surfaces: Arc::new(AtomicUsize::new(0)),
+            present_hooks,
+            ready_cb,
+            #[cfg(feature = "async")]
+            ready_wakers,
+        }
+    }
+}
+
+/// The global data for [`Surface`], constructed using [`ContextBuilder`].
+///
+/// Cloning a `Context` is cheap; the clones share the underlying data. All
+/// [`Surface`]s created from any of the clones share the same ready callback
+/// and other functions specified via `ContextBuilder`, and are counted by
+/// [`Context::total_surfaces`] together. A display connection owned by the
+/// `Context` (see `ContextBuilder::with_wayland_from_env`) is closed when
+/// the last clone and `Surface` are dropped.
+#[derive(Debug, Clone)]
+pub struct Context {
+    inner: ContextImpl,
+    num_surfaces: Arc<AtomicUsize>,
+    present_hooks: Option<Rc<PresentHooks>>,
+    ready_cb: Rc<SharedReadyCb>,
+    #[cfg(feature = "async")]
+    ready_wakers: ReadyWakers,
+}
+
+/// Identifies the backend used by a [`Context`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Backend {
+    Wayland,
+    X11,
+    /// Windows GDI.
+    Windows,
+    /// macOS OpenGL.
+    MacOS,
+    /// iOS Core Animation.
+    Ios,
+    /// A backend that doesn't display anything.
+    Headless,
+    /// A backend not listed here. For example, `Other("reference")` is the
+    /// reference backend selected by setting the environment variable
+    /// `SWSURFACE_BACKEND` to `reference` (Unix only), which emulates the
+    /// Wayland backend without displaying anything.
+    Other(&'static str),
+}
+
+/// Prints the name of the backend in lowercase, e.g., `wayland`.
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Backend::Wayland => "wayland",
+            Backend::X11 => "x11",
+            Backend::Windows => "windows",
+            Backend::MacOS => "macos",
+            Backend::Ios => "ios",
+            Backend::Headless => "headless",
+            Backend::Other(name) => name,
+        })
+    }
+}
+
+impl Context {
+    /// Get the backend used by this `Context` and [`Surface`]s created from it.
+    pub fn backend(&self) -> Backend {
+        self.inner.backend()
+    }
+
+    /// Get the number of existing [`Surface`]s created from this `Context`.
+    ///
+    /// This is intended to be used for detecting leaks.
+    pub fn total_surfaces(&self) -> usize {
+        self.num_surfaces.load(Ordering::Relaxed)
+    }
+
+    /// Send buffered requests to the display server.
+    ///
+    /// The Wayland backend batches protocol messages, which are normally
+    /// sent when the event loop runs. Applications that drive their own
+    /// Wayland dispatch can call this after [`Surface::present_image`] to
+    /// make sure the compositor receives the new contents promptly.
+    ///
+    /// This is a no-op on other backends.
+    pub fn flush(&self) {
+        self.inner.flush();
+    }
+
+    /// Dispatch the events already read from the display server without
+    /// blocking. Returns the number of dispatched events.
+    ///
+    /// On Wayland, this calls `wl_display_dispatch_pending` on the default
+    /// event queue, which delivers the `release` events of swapchain images
+    /// (and may call the event handlers of other objects, including the ones
+    /// owned by `winit`). Applications driving their own event loop (e.g.,
+    /// with a context created by `ContextBuilder::with_wayland_from_env`)
+    /// should call this after reading events from the connection's file
+    /// descriptor. Returns `0` on error.
+    ///
+    /// This is a no-op returning `0` on other backends.
+    pub fn dispatch_pending(&self) -> usize {
+        self.inner.dispatch_pending()
+    }
+}
+
+/// For backends that don't require `ContextImpl`, this type is aliased as
+/// `ContextImpl`.
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub(crate) struct NullContextImpl;
+
+#[allow(dead_code)]
+impl NullContextImpl {
+    const TAKES_READY_CB: bool = false;
+
+    fn new<T: 'static>(_: ContextBuilder<'_, T>) -> Self {
+        Self {}
+    }
+
+    fn backend(&self) -> Backend {
+        if cfg!(target_os = "windows") {
+            Backend::Windows
+        } else if cfg!(target_os = "macos") {
+            Backend::MacOS
+        } else if cfg!(target_os = "ios") {
+            Backend::Ios
+        } else {
+            unreachable!()
+        }
+    }
+
+    fn flush(&self) {}
+
+    fn dispatch_pending(&self) -> usize {
+        0
+    }
+}
+
+/// For backends that don't support native subsurfaces, this type is aliased
+/// as `SubSurfaceImpl`. It's uninhabited.
+#[allow(dead_code)]
+#[derive(Debug)]
+pub(crate) enum NullSubSurfaceImpl {}
+
+#[allow(dead_code)]
+impl NullSubSurfaceImpl {
+    fn surface(&self) -> &SurfaceImpl {
+        match *self {}
+    }
+}
+
+// --------------------------------------------------------------------------
+
+/// The result of [`Surface::acquire_with_timeout`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AcquireResult {
+    /// The swapchain image at the specified index is ready to be accessed by
+    /// the application.
+    Ready(usize),
+    /// No swapchain image became available before the timeout elapsed.
+    Timeout,
+    /// The connection to the windowing system was lost. No swapchain image
+    /// will become available anymore.
+    Lost,
+}
+
+/// A [`Config`] field that a backend can't change without recreating the
+/// surface, reported by `SurfaceImpl::reconfigure`.
+// Not every backend reports every field
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConfigField {
+    ImageCount,
+    Align,
+    ColorSpace,
+}
+
+impl ConfigField {
+    /// Get the field name reported by [`SurfaceError::RequiresRecreation`].
+    fn name(self) -> &'static str {
+        match self {
+            ConfigField::ImageCount => "image_count",
+            ConfigField::Align => "align",
+            ConfigField::ColorSpace => "color_space",
+        }
+    }
+}
+
+/// An error returned by [`Surface`]'s fallible methods.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SurfaceError {
+    /// Some options cannot be changed without recreating the surface.
+    /// Contains the names of the offending [`Config`] fields.
+    RequiresRecreation(Vec<&'static str>),
+    /// The presentation engine failed to present the image, e.g., because of
+    /// a display mode switch. The surface remains usable, and the application
+    /// may try again later.
+    PresentFailed,
+    /// A transparent surface was requested, but the backend can't honor
+    /// per-pixel alpha. See [`Config::transparent_fallback`].
+    TransparencyUnsupported,
+    /// The given raw window or display handle is of a kind not supported by
+    /// the current backend or doesn't match the `Context`.
+    UnsupportedHandle,
+    /// The presentation engine released the swapchain image at the contained
+    /// index, which wasn't in use by it. Reported via
+    /// [`ContextBuilder::with_error_handler`].
+    UnexpectedRelease(usize),
+    /// The swapchain image at the contained index is locked or in use by the
+    /// presentation engine, so it can't be removed.
+    ImageInUse(usize),
+}
+
+impl fmt::Display for SurfaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SurfaceError::RequiresRecreation(fields) => write!(
+                f,
+                "changing the following options requires recreating the surface: {}",
+                fields.join(", ")
+            ),
+            SurfaceError::PresentFailed => write!(f, "failed to present the image"),
+            SurfaceError::TransparencyUnsupported => {
+                write!(f, "the backend doesn't support transparent surfaces")
+            }
+            SurfaceError::UnsupportedHandle => {
+                write!(f, "the window or display handle is not supported")
+            }
+            SurfaceError::UnexpectedRelease(i) => write!(
+                f,
+                "swapchain image {} was released without being presented",
+                i
+            ),
+            SurfaceError::ImageInUse(i) => write!(f, "swapchain image {} is in use", i),
+        }
+    }
+}
+
+impl std::error::Error for SurfaceError {}
+
+/// A software-rendered surface that is implicitly associated with the
+/// underlying window (like `glutin::RawContext`).
+#[derive(Debug)]
+pub struct Surface {
+    inner: SurfaceImpl,
+    num_surfaces: Arc<AtomicUsize>,
+    /// [`Context::backend`]
+    backend: Backend,
+    /// The window the surface is attached to. `WindowId::dummy()` if
+    /// unknown.
+    window_id: WindowId,
+    /// [`ContextBuilder::with_present_hooks`]
+    present_hooks: Option<Rc<PresentHooks>>,
+    /// [`ContextBuilder::with_ready_cb`]
+    ready_cb: Rc<SharedReadyCb>,
+    /// Shared with `Context`
+    #[cfg(feature = "async")]
+    ready_wakers: ReadyWakers,
+    /// The `Config` currently in effect.
+    config: Cell<Config>,
+    /// `true` if `update_surface` has been called at least once.
+    initialized: Cell<bool>,
+    /// The number of calls to `present_image` so far.
+    present_count: Cell<u64>,
+    /// The value of `present_count` when each swapchain image was last
+    /// presented. `0` means the contents are unknown.
+    last_presents: RefCell<Vec<u64>>,
+    /// Set by [`Surface::invalidate`]. If `true`, the next presentation
+    /// updates the whole image.
+    full_damage: Cell<bool>,
+    /// The minimum interval between presentations. See
+    /// [`Surface::set_present_rate_limit`].
+    present_rate_limit: Cell<Duration>,
+    /// The time when the last presentation was enqueued.
+    last_present_time: Cell<Option<Instant>>,
+    /// `true` if `poll_next_image` returned `None` because of
+    /// `present_rate_limit`. `handle_os_event` calls the ready callback when
+    /// the limit expires.
+    rate_limited: Cell<bool>,
+    /// See [`Surface::set_max_frame_latency`].
+    max_frame_latency: Cell<u32>,
+    /// Set by [`Surface::set_debug_label`].
+    #[cfg(debug_assertions)]
+    debug_label: RefCell<Option<String>>,
+}
+
+impl Drop for Surface {
+    fn drop(&mut self) {
+        self.num_surfaces.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Surface {
+    /// Construct and attach a surface to the specified window.
+    ///
+    /// Panics if `config.opaque == false`, the backend can't honor per-pixel
+    /// alpha, and `config.transparent_fallback` is
+    /// `TransparentFallback::Error`. Use [`Surface::try_new`] to handle this
+    /// case.
+    ///
+    /// # Safety
+    ///
+    /// The constructed `Surface` must be dropped before `window`.
+    pub unsafe fn new(window: &Window, context: &Context, config: &Config) -> Self {
+        Self::try_new(window, context, config).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Construct and attach a surface to the specified window. Returns
+    /// `Err(SurfaceError::TransparencyUnsupported)` if `config.opaque ==
+    /// false`, the backend can't honor per-pixel alpha, and
+    /// `config.transparent_fallback` is `TransparentFallback::Error`.
+    ///
+    /// # Safety
+    ///
+    /// The constructed `Surface` must be dropped before `window`.
+    pub unsafe fn try_new(
+        window: &Window,
+        context: &Context,
+        config: &Config,
+    ) -> Result<Self, SurfaceError> {
+        let inner = SurfaceImpl::new(window, &context.inner, config);
+        Self::from_impl(inner, window.id(), context, config)
+    }
+
+    /// Construct and attach a surface to a window not created by `winit`,
+    /// given its raw window and display handles. The backend is chosen based
+    /// on the handle variants.
+    ///
+    /// Returns `Err(SurfaceError::UnsupportedHandle)` if the handles are not
+    /// supported by the current platform or don't match the backend used by
+    /// `context`. Returns `Err(SurfaceError::TransparencyUnsupported)` under
+    /// the same condition as [`Surface::try_new`].
+    ///
+    /// Since there is no `winit::window::Window` associated with the
+    /// surface, the ready callback specified by
+    /// [`ContextBuilder::with_ready_cb`] receives `WindowId::dummy()` for this
+    /// surface.
+    ///
+    /// # Safety
+    ///
+    ///  - `window` and `display` must refer to valid objects of the platform's
+    ///    windowing system, and they must remain valid until the constructed
+    ///    `Surface` is dropped.
+    ///  - `display` must be the connection `window` was created with. On
+    ///    Wayland, it must also be the connection `context` was created with.
+    ///  - The constructed `Surface` must only be used by the thread owning
+    ///    the window if the platform requires so (e.g., macOS and iOS).
+    ///
+    pub unsafe fn from_raw_handles(
+        window: RawWindowHandle,
+        display: RawDisplayHandle,
+        context: &Context,
+        config: &Config,
+    ) -> Result<Self, SurfaceError> {
+        let inner = SurfaceImpl::from_raw_handles(window, display, &context.inner, config)?;
+        Self::from_impl(inner, WindowId::dummy(), context, config)
+    }
+
+    fn from_impl(
+        inner: SurfaceImpl,
+        window_id: WindowId,
+        context: &Context,
+        config: &Config,
+    ) -> Result<Self, SurfaceError> {
+        if !config.opaque
+            && config.transparent_fallback == TransparentFallback::Error
+            && !inner.supports_transparency()
+        {
+            return Err(SurfaceError::TransparencyUnsupported);
+        }
+
+        if config.prefer_native_stride {
+            inner.set_scanline_align(inner.default_scanline_align());
+        }
+
+        context.num_surfaces.fetch_add(1, Ordering::Relaxed);
+
+        Ok(Self {
+            inner,
+            num_surfaces: Arc::clone(&context.num_surfaces),
+            backend: context.backend(),
+            window_id,
+            present_hooks: context.present_hooks.clone(),
+            ready_cb: Rc::clone(&context.ready_cb),
+            #[cfg(feature = "async")]
+            ready_wakers: Rc::clone(&context.ready_wakers),
+            config: Cell::new(*config),
+            initialized: Cell::new(false),
+            present_count: Cell::new(0),
+            last_presents: RefCell::new(Vec::new()),
+            full_damage: Cell::new(false),
+            present_rate_limit: Cell::new(Duration::from_secs(0)),
+            max_frame_latency: Cell::new(u32::MAX),
+            last_present_time: Cell::new(None),
+            rate_limited: Cell::new(false),
+            #[cfg(debug_assertions)]
+            debug_label: RefCell::new(None),
+        })
+    }
+
+    /// Update the properties of the surface.
+    ///
+    /// After resizing a window, you must call this method irregardless of
+    /// whether you want to change the image size or not. Also, you must call
+    /// this method at least once before accessing swapchain images.
+    ///
+    /// The result of a mismatching image size is implementation-dependent.
+    /// In general, you should use `update_surface_to_fit`.
+    ///
+    /// Panics if:
+    ///  - `format` is not in `supported_formats()`.
+    ///  - One of `extent`'s elements is zero.
+    ///  - One or more swapchain images are locked.
+    pub fn update_surface(&self, extent: [u32; 2], format: Format) {
+        self.inner.update_surface(extent, format);
+        self.initialized.set(true);
+
+        // The old contents are not valid anymore. (On macOS, this also covers
+        // the `NSOpenGLContext::update` call made by `update_surface`.)
+        self.reset_image_ages();
+    }
+
+    /// Forget when the swapchain images were last presented.
+    fn reset_image_ages(&self) {
+        let mut last_presents = self.last_presents.borrow_mut();
+        last_presents.clear();
+        last_presents.resize(self.num_images(), 0);
+    }
+
+    /// Mark the contents of all swapchain images as unknown so that the
+    /// application renders the next frame from scratch.
+    ///
+    /// Afterwards, [`Surface::image_age`] returns `0` and
+    /// [`Surface::extent_changed_since_last_present`] returns `true` for each
+    /// image until it's presented again, as if `update_surface` was called.
+    /// In addition, the next presentation updates the whole image, even if
+    /// it's done by a method specifying the updated region, such as
+    /// [`Surface::present_region`]. Use this when the preserved contents may
+    /// have become invalid for reasons this crate can't detect, e.g., after
+    /// the window moved to a monitor with a different DPI.
+    ///
+    /// If `clear` is `true`, the images not in use by the presentation engine
+    /// are also zero-filled.
+    ///
+    /// Panics if `update_surface` hasn't been called yet or, if `clear` is
+    /// `true`, one or more swapchain images are locked.
+    pub fn invalidate(&self, clear: bool) {
+        self.assert_initialized();
+        self.reset_image_ages();
+        self.full_damage.set(true);
+
+        if clear {
+            for i in 0..self.num_images() {
+                if !self.inner.is_presenting(i) {
+                    self.inner.lock_image(i).fill(0);
+                }
+            }
+        }
+    }
+
+    /// Apply a new `Config` to the surface without recreating it.
+    ///
+    /// The options that can be changed in place are applied immediately.
+    /// If some options can't be changed in place, they are left unchanged and
+    /// this method returns `SurfaceError::RequiresRecreation` listing them.
+    /// The remaining options are applied regardless. The contents of the
+    /// swapchain images are preserved, and the images may be locked or in use
+    /// by the presentation engine during this call.
+    ///
+    /// `scanline_align`, `prefer_native_stride`, and `prefer_shared_memory`
+    /// take effect on the next call to `update_surface`.
+    ///
+    /// Panics if `config.scanline_align` is not a power of two.
+    ///
+    /// # Backend support
+    ///
+    ///  - `image_count` is applied by [`Surface::resize_swapchain`], which
+    ///    fails if an image to be removed is locked or in use by the
+    ///    presentation engine. It's ignored by the backends that always use a
+    ///    single swapchain image (all but Wayland).
+    ///  - `align` can't be changed except on Wayland, where it's ignored.
+    ///  - `color_space` can't be changed from `DisplayP3` on macOS.
+    ///  - `vsync` and `opaque` are only used by macOS and can always be
+    ///    changed.
+    ///
+    pub fn reconfigure(&self, config: &Config) -> Result<(), SurfaceError> {
+        let old_config = self.config.get();
+
+        // Don't revert an alignment set by `update_surface_aligned` unless
+        // the application asked for a different one
+        if config.scanline_align != old_config.scanline_align
+            || config.prefer_native_stride != old_config.prefer_native_stride
+        {
+            let scanline_align = if config.prefer_native_stride {
+                self.inner.default_scanline_align()
+            } else {
+                Align::new(config.scanline_align).unwrap()
+            };
+            self.inner.set_scanline_align(scanline_align);
+        }
+
+        let mut unsupported = self.inner.reconfigure(&old_config, config);
+
+        if config.image_count != old_config.image_count
+            && self.resize_swapchain(config.image_count).is_err()
+        {
+            // An image to be removed is in use
+            unsupported.push(ConfigField::ImageCount);
+        }
+
+        // Keep the old values of the options that weren't changed
+        let mut new_config = *config;
+        for &field in unsupported.iter() {
+            match field {
+                ConfigField::ImageCount => new_config.image_count = old_config.image_count,
+                ConfigField::Align => new_config.align = old_config.align,
+                ConfigField::ColorSpace => new_config.color_space = old_config.color_space,
+            }
+        }
+        self.config.set(new_config);
+
+        if unsupported.is_empty() {
+            Ok(())
+        } else {
+            Err(SurfaceError::RequiresRecreation(
+                unsupported.into_iter().map(ConfigField::name).collect(),
+            ))
+        }
+    }
+
+    /// Change the number of swapchain images without recreating the surface,
+    /// e.g., to switch between double and triple buffering in response to a
+    /// frame pacing problem. [`Surface::num_images`] reflects the new count
+    /// afterward.
+    ///
+    /// Images are added or removed at the end, so the indices of the
+    /// remaining images stay valid. The new images' contents are unknown (see
+    /// [`Surface::image_age`]). Returns `Err(SurfaceError::ImageInUse(i))` if
+    /// an image to be removed is locked or in use by the presentation engine,
+    /// in which case nothing is changed.
+    ///
+    /// The backends that always use a single swapchain image (all but Wayland)
+    /// ignore this.
+    ///
+    /// Panics if `count` is zero.
+    pub fn resize_swapchain(&self, count: usize) -> Result<(), SurfaceError> {
+        self.inner.resize_swapchain(count)?;
+
+        self.last_presents.borrow_mut().resize(self.num_images(), 0);
+        self.config.set(Config {
+            image_count: count,
+            ..self.config.get()
+        });
+
+        Ok(())
+    }
+
+    /// Enable or disable vertical synchronization at runtime. This is a
+    /// shorthand for calling `reconfigure` with a modified
+    /// [`Config::vsync`].
+    ///
+    /// # Backend support
+    ///
+    ///  - macOS: Updates the swap interval of the OpenGL context.
+    ///  - Other platforms: Only the value returned by `vsync_enabled` changes.
+    ///    The Wayland backend doesn't wait for frame callbacks, and the other
+    ///    backends can't synchronize with the display.
+    ///
+    pub fn set_vsync(&self, enable: bool) {
+        let config = Config {
+            vsync: enable,
+            ..self.config.get()
+        };
+        // `vsync` can always be changed in place
+        self.reconfigure(&config).unwrap();
+    }
+
+    /// Get the current value of [`Config::vsync`].
+    pub fn vsync_enabled(&self) -> bool {
+        self.config.get().vsync
+    }
+
+    /// Update the properties of the surface, overriding the scanline alignment
+    /// specified by [`Config::scanline_align`].
+    ///
+    /// The new alignment is used for this and all subsequent allocations
+    /// (including the ones done by `update_surface`). It's stored in
+    /// [`Config::scanline_align`] (with `prefer_native_stride` cleared), so it
+    /// persists through `reconfigure` unless the application specifies a
+    /// different one.
+    ///
+    /// This internally calls `update_surface`, so the same preconditions
+    /// apply. Additionally, panics if `scanline_align` is not a power of two.
+    pub fn update_surface_aligned(&self, extent: [u32; 2], format: Format, scanline_align: usize) {
+        let align = Align::new(scanline_align).unwrap();
+        self.inner.set_scanline_align(align);
+        self.config.set(Config {
+            scanline_align,
+            prefer_native_stride: false,
+            ..self.config.get()
+        });
+        self.update_surface(extent, format);
+    }
+
+    /// Update the properties of the surface. The surface size is automatically
+    /// derived based on the window size and [`Surface::transform`].
+    ///
+    /// This internally calls `update_surface`.
+    pub fn update_surface_to_fit(&self, window: &Window, format: Format) {
+        let (size_w, size_h) = window.inner_size().into();
+
+        // `Rotate90` and `Rotate270` are the inverse of each other
+        let extent = self.transform().transform_extent([size_w, size_h]);
+
+        self.update_surface(extent, format);
+    }
+
+    /// Set the transformation applied to the surface contents when presenting
+    /// them.
+    ///
+    /// The application renders the contents in the untransformed orientation.
+    /// For example, with `Transform::Rotate90`, `extent` passed to
+    /// `update_surface` should be the window size with its width and height
+    /// swapped. `update_surface_to_fit` takes this into account. Call
+    /// `update_surface` again after changing the transformation.
+    ///
+    /// On Wayland, the transformation is delegated to the compositor. On
+    /// macOS, it's done by OpenGL. Elsewhere, it's done in software, which
+    /// incurs an extra copy on every call to `present_image`.
+    pub fn set_transform(&self, transform: Transform) {
+        self.inner.set_transform(transform)
+    }
+
+    /// Get the transformation applied to the surface contents when presenting
+    /// them.
+    pub fn transform(&self) -> Transform {
+        self.inner.transform()
+    }
+
+    /// Update the properties of the surface, rescaling the existing contents
+    /// of the swapchain images to the new size.
+    ///
+    /// This can be used to keep something on the screen during an interactive
+    /// resize instead of presenting a blank image. The contents are rescaled
+    /// using nearest-neighbor sampling on a best-effort basis: the contents of
+    /// the swapchain images currently in use by the presentation engine are
+    /// not preserved. This is much costlier than `update_surface`.
+    ///
+    /// This internally calls `update_surface`, so the same preconditions
+    /// apply.
+    pub fn update_surface_scaling_content(&self, extent: [u32; 2], format: Format) {
+        let old_info = self.image_info();
+
+        // Take a snapshot of every image we can access
+        let old_contents: Vec<Option<Vec<u8>>> = (0..self.num_images())
+            .map(|i| {
+                if !self.initialized.get() || self.inner.is_presenting(i) {
+                    None
+                } else {
+                    let size = old_info.stride * old_info.extent[1] as usize;
+                    Some(self.inner.lock_image(i)[..size].to_vec())
+                }
+            })
+            .collect();
+
+        self.update_surface(extent, format);
+
+        let new_info = self.image_info();
+        for (i, old_content) in old_contents.iter().enumerate() {
+            if let Some(old_content) = old_content {
+                let mut image = self.inner.lock_image(i);
+                scale_nearest(old_content, &old_info, &mut image, &new_info);
+            }
+        }
+    }
+
+    /// Enumerate supported pixel formats.
+    pub fn supported_formats(&self) -> impl Iterator<Item = Format> + '_ {
+        self.inner.supported_formats()
+    }
+
+    /// Check if the window can display per-pixel transparency, i.e., if the
+    /// alpha channel of `Format::Argb8888` images is honored when
+    /// [`Config::opaque`] is `false`.
+    ///
+    /// If this returns `false`, a non-opaque surface is handled according to
+    /// [`Config::transparent_fallback`], and the application might want to
+    /// choose an opaque background or different decorations instead.
+    ///
+    /// # Backend support
+    ///
+    ///  - macOS, iOS, and Wayland: Always `true`.
+    ///  - X11: `true` if the window was created with an ARGB visual (e.g., by
+    ///    `WindowBuilder::with_transparent`).
+    ///  - Windows: `true` if desktop composition is enabled, which is always
+    ///    the case on Windows 8 and later.
+    ///
+    pub fn supports_transparency(&self) -> bool {
+        self.inner.supports_transparency()
+    }
+
+    /// Get the scanline alignment natural to the backend, i.e., the one used
+    /// by [`Config::prefer_native_stride`].
+    ///
+    /// This can be used as [`Config::scanline_align`] (via [`Align::get`])
+    /// when reconfiguring this surface or creating other surfaces from the
+    /// same `Context`.
+    pub fn default_scanline_align(&self) -> Align {
+        self.inner.default_scanline_align()
+    }
+
+    /// Choose the first pixel format in `preference` that is included in
+    /// `supported_formats()`.
+    ///
+    /// Returns `None` if none of them are supported.
+    pub fn best_format(&self, preference: &[Format]) -> Option<Format> {
+        let supported: Vec<Format> = self.supported_formats().collect();
+        preference
+            .iter()
+            .cloned()
+            .find(|fmt| supported.contains(fmt))
+    }
+
+    /// Get the `ImageInfo` describing the current swapchain images.
+    ///
+    /// The returned value is unspecified (currently, `extent` is `[0, 0]`)
+    /// if `update_surface` hasn't been called yet. Use `physical_size` to
+    /// check whether the surface is initialized.
+    ///
+    /// The returned value is a snapshot. A cached `ImageInfo` must be
+    /// refreshed after every call to `update_surface` because the extent and
+    /// stride may change. [`ImageInfo::assert_compatible`] can be used to
+    /// check a cached value.
+    pub fn image_info(&self) -> ImageInfo {
+        self.inner.image_info()
+    }
+
+    /// Get the current size of the swapchain images.
+    ///
+    /// Returns `None` if `update_surface` hasn't been called yet. Otherwise,
+    /// this is equal to `image_info().extent`.
+    pub fn physical_size(&self) -> Option<[u32; 2]> {
+        if self.initialized.get() {
+            Some(self.image_info().extent)
+        } else {
+            None
+        }
+    }
+
+    /// Get the number of swapchain images.
+    ///
+    /// This value is automatically calculated when `update_surface` is called.
+    ///
+    /// This value does not reflect the actual number of buffers that stand
+    /// between the application and the display hardware. It's only useful
+    /// when `does_preserve_image() == true` and the application wants to
+    /// track dirty regions in each swapchain image.
+    pub fn num_images(&self) -> usize {
+        self.inner.num_images()
+    }
+
+    /// Get a flag indicating whether swapchain images preserve their contents
+    /// when their indices are used again.
+    ///
+    /// If this function returns `true`, the application can optimize rendering
+    /// by only updating the dirty portions.
+    pub fn does_preserve_image(&self) -> bool {
+        self.inner.does_preserve_image()
+    }
+
+    /// Get a flag indicating whether the swapchain image at index `i` is
+    /// currently locked by a guard returned by `lock_image` or a similar
+    /// method.
+    ///
+    /// Returns `false` if `update_surface` hasn't been called yet. Panics if
+    /// `i` is out of range.
+    pub fn is_locked(&self, i: usize) -> bool {
+        self.initialized.get() && self.inner.is_locked(i)
+    }
+
+    /// Get a flag indicating whether the swapchain image at index `i` is
+    /// currently in use by the presentation engine. Such an image can't be
+    /// locked or presented until it's released.
+    ///
+    /// Backends having only one swapchain image always return `false`.
+    ///
+    /// Returns `false` if `update_surface` hasn't been called yet. Panics if
+    /// `i` is out of range.
+    pub fn is_presenting(&self, i: usize) -> bool {
+        self.initialized.get() && self.inner.is_presenting(i)
+    }
+
+    /// Get the indices of all swapchain images that are currently not in use
+    /// by the presentation engine.
+    ///
+    /// Unlike [`Surface::poll_next_image`], this enumerates every available
+    /// image, so the application can lock several images at once and render
+    /// into them in parallel. Each image still has to be presented
+    /// individually.
+    ///
+    /// This method doesn't schedule a call to the callback function specified
+    /// by [`ContextBuilder::with_ready_cb`]. Yields nothing if
+    /// `update_surface` hasn't been called yet.
+    pub fn available_images(&self) -> impl Iterator<Item = usize> + '_ {
+        let num_images = if self.initialized.get() {
+            self.inner.num_images()
+        } else {
+            0
+        };
+        (0..num_images).filter(move |&i| !self.inner.is_presenting(i))
+    }
+
+    /// Get the number of swapchain images currently in use by the
+    /// presentation engine (see [`Surface::is_presenting`]).
+    ///
+    /// This is the same as [`Surface::pending_present_count`] and is provided
+    /// for symmetry with `num_images_ready`.
+    pub fn num_images_presenting(&self) -> usize {
+        self.pending_present_count()
+    }
+
+    /// Get the number of swapchain images ready to be acquired by the
+    /// application, i.e., the ones neither in use by the presentation engine
+    /// nor locked (see [`Surface::is_locked`]).
+    ///
+    /// Each image is in exactly one of the three states, so
+    /// `num_images_ready() + num_images_presenting()` plus the number of
+    /// locked images equals `num_images()`. This can help diagnose frame
+    /// pacing problems, e.g., an application holding onto images for too
+    /// long or the presentation engine releasing them late.
+    ///
+    /// Returns `0` if `update_surface` hasn't been called yet.
+    pub fn num_images_ready(&self) -> usize {
+        self.available_images()
+            .filter(|&i| !self.inner.is_locked(i))
+            .count()
+    }
+
+    /// Get the index of the next available swapchain image.
+    ///
+    /// Returns `None` if no image is available. In this case, the function
+    /// specified via [`ContextBuilder::with_ready_cb`] will be called when one
+    /// is ready. If you call `poll_next_image` for multiple times before the
+    /// callback function is called for the next time, and all of the calls to
+    /// `poll_next_image` returns `None`, then the callback function will be
+    /// called only once.
+    ///
+    /// The application cannot rely on image unavailability for metering the
+    /// rendering speed even if [`Config::vsync`] is enabled.
+    ///
+    /// `update_surface` may or may not cancel the deferred call to the
+    /// callback.
+    ///
+    /// If an image is returned, this method does not remove the image from
+    /// the set of avilable images. For example, if the application calls
+    /// `poll_next_image` repeatedly, it may return the same image index for
+    /// all of the calls.
+    ///
+    /// Returns `None` without scheduling a call to the callback function if
+    /// `update_surface` hasn't been called yet.
+    ///
+    /// Also returns `None` on all backends if the minimum interval set by
+    /// [`Surface::set_present_rate_limit`] hasn't elapsed since the last
+    /// presentation. In this case, the callback function is called by
+    /// [`Surface::handle_os_event`] once the interval has elapsed.
+    pub fn poll_next_image(&self) -> Option<usize> {
+        if !self.initialized.get() {
+            return None;
+        }
+
+        if self.present_deadline().is_some() {
+            self.rate_limited.set(true);
+            return None;
+        }
+
+        if self.pending_present_count() >= self.max_frame_latency.get() as usize {
+            self.inner.enable_ready_cb();
+            return None;
+        }
+
+        self.inner.poll_next_image()
+    }
+
+    /// Get the `ControlFlow` with which the event loop should wait for a
+    /// swapchain image to become available.
+    ///
+    /// Returns `ControlFlow::Wait` if a call to the callback function
+    /// specified by [`ContextBuilder::with_ready_cb`] is scheduled, i.e.,
+    /// [`Surface::poll_next_image`] has returned `None` and no image has
+    /// become available since then. The application should redraw when the
+    /// callback function is called (e.g., by requesting a redraw from the
+    /// callback function). Otherwise, returns `ControlFlow::Poll`. This is
+    /// always the case on backends where `poll_next_image` doesn't return
+    /// `None` (Windows, macOS, iOS, and X11).
+    ///
+    /// Returns `ControlFlow::WaitUntil(deadline)` if `poll_next_image` has
+    /// returned `None` because of [`Surface::set_present_rate_limit`], where
+    /// `deadline` is when the minimum interval elapses.
+    ///
+    /// Returns `ControlFlow::Poll` if `update_surface` hasn't been called
+    /// yet.
+    pub fn control_flow_until_ready(&self) -> ControlFlow {
+        if !self.initialized.get() {
+            return ControlFlow::Poll;
+        }
+
+        if self.rate_limited.get() {
+            if let Some(deadline) = self.present_deadline() {
+                return ControlFlow::WaitUntil(deadline);
+            }
+        }
+
+        if self.inner.is_ready_cb_scheduled() {
+            ControlFlow::Wait
+        } else {
+            ControlFlow::Poll
+        }
+    }
+
+    /// Get the backend used by the surface, which is the same as that of the
+    /// `Context` it was created from.
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Get the refresh interval of the monitor `window` is on, which can be
+    /// used to schedule animation frames, e.g., with
+    /// `ControlFlow::WaitUntil(Instant::now() + interval)`.
+    ///
+    /// Returns `None` if the refresh rate is unknown. Currently, it's only
+    /// known on Wayland, where it's derived from the current mode of
+    /// `wl_output` if all outputs share the same refresh rate.
+    pub fn target_frame_interval(&self, window: &Window) -> Option<Duration> {
+        self.inner.refresh_rate(window).map(frame_interval)
+    }
+
+    /// Get the index of the next available swapchain image, blocking the
+    /// current thread for up to `timeout` if none is available.
+    ///
+    /// Like [`Surface::poll_next_image`], this method schedules a call to the
+    /// callback function specified by [`ContextBuilder::with_ready_cb`] if no
+    /// image is available on return.
+    ///
+    /// On Wayland, this method reads and dispatches events from the default
+    /// event queue while waiting, which may cause the event handlers of
+    /// other Wayland objects (including the ones owned by `winit`) to be
+    /// called. Backends having only one swapchain image always return
+    /// `AcquireResult::Ready(0)` immediately.
+    ///
+    /// Panics if `update_surface` hasn't been called yet.
+    pub fn acquire_with_timeout(&self, timeout: Duration) -> AcquireResult {
+        self.assert_initialized();
+        self.inner.acquire_with_timeout(timeout)
+    }
+
+    /// Let the backend observe an event received from `winit`'s event loop.
+    ///
+    /// Backends presenting images asynchronously may depend on window events
+    /// (e.g., completion or invalidation notifications) to advance their
+    /// internal state. Applications should forward every event they receive
+    /// for the surface's window to this method so that such backends work
+    /// correctly. Events for other windows are ignored.
+    ///
+    /// None of the current backends needs this. However, this method calls
+    /// the ready callback once the minimum interval set by
+    /// [`Surface::set_present_rate_limit`] elapses if `poll_next_image` has
+    /// returned `None` because of it. [`Surface::control_flow_until_ready`]
+    /// makes the event loop wake up at that time.
+    pub fn handle_os_event<T>(&self, event: &Event<T>) {
+        self.inner.handle_os_event(event);
+
+        if self.rate_limited.get() && self.present_deadline().is_none() {
+            self.rate_limited.set(false);
+            (self.ready_cb.0)(self.window_id);
+        }
+    }
+
+    /// Block the current thread for up to `timeout` until the presentation
+    /// engine releases all swapchain images. Returns `true` if no image is in
+    /// use by the presentation engine on return.
+    ///
+    /// Call this before dropping the surface to make sure the backing memory
+    /// of the swapchain images isn't freed while the compositor may still be
+    /// reading it.
+    ///
+    /// On Wayland, this method reads and dispatches events from the default
+    /// event queue while waiting in the same way as
+    /// [`Surface::acquire_with_timeout`]. The `release` events are delivered
+    /// regardless of whether the application's event loop is running, but
+    /// they may take up to one frame interval to arrive, so `timeout` should
+    /// be at least as long as that. Returns `false` if the connection is
+    /// lost. Note that the compositor keeps the last presented image until
+    /// another one is presented, so this usually times out while the surface
+    /// is showing an image. [`SwWindow::set_wait_released_on_drop`] detaches
+    /// the image first. Other backends always return `true` immediately.
+    ///
+    /// Returns `true` if `update_surface` hasn't been called yet.
+    pub fn wait_all_released(&self, timeout: Duration) -> bool {
+        !self.initialized.get() || self.inner.wait_all_released(timeout)
+    }
+
+    /// Lock a swapchain image at index `i` to access its contents.
+    ///
+    /// `i` must be the index of a swapchain image acquired by `poll_next_image`.
+    ///
+    /// Panics if `update_surface` hasn't been called yet, or the image is
+    /// currently locked or not ready to be accessed by the application.
+    ///
+    /// Given an `ImageInfo`, the length is calculated as:
+    /// `extent[1] * stride * 4`.
+    pub fn lock_image(&self, i: usize) -> impl DerefMut<Target = [u8]> + '_ {
+        self.assert_initialized();
+        self.inner.lock_image(i)
+    }
+
+    /// Lock a swapchain image at index `i` like `lock_image`, but return
+    /// `None` instead of panicking if the image is currently locked.
+    ///
+    /// This is useful for code paths that can't tell whether the image is
+    /// already locked elsewhere. `Surface` isn't `Sync`, so no other thread
+    /// can release the lock while this method is running. Therefore, the
+    /// lock is attempted only once, and `timeout` is currently ignored.
+    ///
+    /// The other preconditions of `lock_image` still apply.
+    pub fn lock_image_timeout(
+        &self,
+        i: usize,
+        _timeout: Duration,
+    ) -> Option<impl DerefMut<Target = [u8]> + '_> {
+        if self.is_locked(i) {
+            None
+        } else {
+            Some(self.lock_image(i))
+        }
+    }
+
+    /// Lock a swapchain image at index `i` to stream pixel data into it
+    /// through [`std::io::Write`], e.g., from an image decoder.
+    ///
+    /// The data is written as is, starting at the beginning of the image
+    /// (see [`LockedImage::seek_to`]). Note that the rows are `stride` bytes
+    /// apart, so the data must include the padding between rows unless the
+    /// scanline alignment is `4` (see `update_surface_aligned`).
+    ///
+    /// The same preconditions as `lock_image` apply.
+    pub fn lock_image_writer(&self, i: usize) -> LockedImage<'_> {
+        LockedImage {
+            guard: Box::new(self.lock_image(i)),
+            cursor: 0,
+        }
+    }
+
+    /// Lock the rows of a swapchain image at index `i` covered by `rect`
+    /// (`[x, y, width, height]`) to update the region. The image is presented
+    /// when the returned guard is dropped.
+    ///
+    /// The guard dereferences to the full rows from `y` to `y + height`
+    /// because rows are `stride` bytes apart. [`RegionGuard::image_info`]
+    /// describes this slice, and [`RegionGuard::rect`] indicates the pixels
+    /// the application is supposed to update. `rect` is clipped by the image
+    /// (see [`ImageInfo::clip_rect`]).
+    ///
+    /// The same preconditions as `lock_image` apply. Additionally, panics if
+    /// `rect` is empty or entirely outside the image.
+    ///
+    /// # Backend support
+    ///
+    ///  - X11: Only `rect` is transferred to the window unless a transform is
+    ///    set (see [`Surface::set_transform`]).
+    ///  - Wayland: Only `rect` is marked as damaged. The compositor may still
+    ///    read the other pixels, so they must be valid as well, e.g., by using
+    ///    an image whose contents are preserved (see [`Surface::image_age`]).
+    ///  - Other platforms: The whole image is presented, so the pixels outside
+    ///    `rect` must be valid as well.
+    pub fn lock_image_region(&self, i: usize, rect: [u32; 4]) -> RegionGuard<'_> {
+        let image_info = self.image_info();
+        let rect = image_info
+            .clip_rect(rect)
+            .expect("the region is empty or out of bounds");
+
+        RegionGuard {
+            surface: self,
+            i,
+            guard: Some(Box::new(self.lock_image(i))),
+            rect,
+            image_info: ImageInfo {
+                extent: [image_info.extent[0], rect[3]],
+                ..image_info
+            },
+        }
+    }
+
+    /// Lock a swapchain image at index `i` to access its contents as a slice
+    /// of `T`, e.g., `u32`.
+    ///
+    /// This requires the `bytemuck` feature.
+    ///
+    /// The same preconditions as `lock_image` apply. Additionally, panics if
+    /// the image's length is not a multiple of `size_of::<T>()` or its base
+    /// address is not sufficiently aligned for `T` (see [`Config::align`]).
+    #[cfg(feature = "bytemuck")]
+    pub fn lock_image_as<T: bytemuck::Pod>(&self, i: usize) -> impl DerefMut<Target = [T]> + '_ {
+        let guard = self.lock_image(i);
+
+        // Fail-fast instead of panicking on every dereference
+        let _: &[T] = bytemuck::try_cast_slice(&guard[..])
+            .unwrap_or_else(|e| panic!("cannot cast the image to `[T]`: {:?}", e));
+
+        CastGuard {
+            guard,
+            _phantom: std::marker::PhantomData,
+        }
+    }
+
+    /// Lock a swapchain image at index `i` for drawing with the `image` crate.
+    /// The image is presented when the returned guard is dropped.
+    ///
+    /// This requires the `image` feature.
+    ///
+    /// [`ImageBufferGuard::image_buffer`] views the image as
+    /// `image::Rgba<u8>` pixels, but [`Format::Argb8888`] and
+    /// [`Format::Xrgb8888`] store the channels in the order B, G, R, A in
+    /// memory. The application must swap the red and blue channels when
+    /// writing pixels, e.g., write `Rgba([b, g, r, a])` for the color
+    /// `[r, g, b, a]`. This isn't necessary for [`Format::Xbgr8888`].
+    ///
+    /// The same preconditions as `lock_image` apply. Additionally, panics if
+    /// the scanlines have padding (i.e., `stride != extent[0] * 4`), which
+    /// `image::ImageBuffer` can't represent. Use `update_surface_aligned` with
+    /// a scanline alignment of `4` to avoid it.
+    #[cfg(feature = "image")]
+    pub fn borrow_as_image_buffer(&self, i: usize) -> ImageBufferGuard<'_> {
+        let image_info = self.image_info();
+        assert_eq!(
+            image_info.stride,
+            image_info.extent[0] as usize * 4,
+            "the image has padding between scanlines"
+        );
+
+        ImageBufferGuard {
+            surface: self,
+            i,
+            guard: Some(Box::new(self.lock_image(i))),
+            image_info,
+        }
+    }
+
+    /// Enqueue the presentation of a swapchain image at index `i`.
+    ///
+    /// This method removes the swapchain image at index `i` from the set of
+    /// available images and enqueues it for presentation.
+    ///
+    /// `i` must be the index of a swapchain image acquired by `poll_next_image`.
+    /// The image must not be locked by `lock_image`.
+    ///
+    /// Panics if `update_surface` hasn't been called yet.
+    ///
+    /// Presentation failures are ignored. Use [`Surface::try_present_image`]
+    /// to detect them.
+    pub fn present_image(&self, i: usize) {
+        self.assert_initialized();
+        self.call_pre_present_hook(i);
+        self.inner.present_image(i);
+        self.call_post_present_hook(i);
+        self.record_present(i);
+    }
+
+    /// Enqueue the presentation of a swapchain image at index `i`, telling
+    /// the backend that only the rectangles in `damage` have changed.
+    fn present_image_damaged(&self, i: usize, damage: &[[u32; 4]]) {
+        if self.full_damage.get() {
+            debug!("The surface was invalidated; presenting the whole image");
+            return self.present_image(i);
+        }
+
+        self.assert_initialized();
+        self.call_pre_present_hook(i);
+        self.inner.present_image_damaged(i, damage);
+        self.call_post_present_hook(i);
+        self.record_present(i);
+    }
+
+    /// Enqueue the presentation of a swapchain image at index `i`, returning
+    /// `Err(SurfaceError::PresentFailed)` if the presentation engine failed
+    /// to present it.
+    ///
+    /// The same preconditions as `present_image` apply.
+    ///
+    /// # Backend support
+    ///
+    ///  - Windows: Fails if `GetDC` or `StretchDIBits` fails.
+    ///  - Other platforms: Never fails.
+    ///
+    pub fn try_present_image(&self, i: usize) -> Result<(), SurfaceError> {
+        self.assert_initialized();
+        self.call_pre_present_hook(i);
+        let result = self.inner.try_present_image(i);
+        self.call_post_present_hook(i);
+        result?;
+        self.record_present(i);
+        Ok(())
+    }
+
+    /// Acquire the next available swapchain image by `poll_next_image` and
+    /// lock it by `lock_image`. Returns `None` if no image is available.
+    ///
+    /// The image is presented when the returned guard is dropped. Passing the
+    /// guard to [`Surface::present_frame`] does the same but makes the
+    /// presentation explicit. Either way, the guard is consumed, so the
+    /// image can't be accessed after it's presented.
+    ///
+    /// Panics if `update_surface` hasn't been called yet.
+    pub fn acquire_frame(&self) -> Option<ImageFrame<'_>> {
+        let i = self.poll_next_image()?;
+        Some(ImageFrame {
+            surface: self,
+            i,
+            guard: Some(Box::new(self.lock_image(i))),
+            image_info: self.image_info(),
+        })
+    }
+
+    /// Unlock and enqueue the presentation of a swapchain image acquired by
+    /// [`Surface::acquire_frame`].
+    ///
+    /// Panics if `frame` was acquired from a different `Surface`.
+    pub fn present_frame(&self, mut frame: ImageFrame<'_>) {
+        assert!(
+            std::ptr::eq(self, frame.surface),
+            "the frame belongs to a different surface"
+        );
+
+        // Unlock the image first. The frame won't present the image again
+        // when dropped because `guard` is `None`.
+        drop(frame.guard.take());
+        self.present_image(frame.i);
+    }
+
+    /// Acquire a swapchain image, fill it with black, and present it, e.g.,
+    /// to blank the window before a shutdown or mode switch. The image is
+    /// filled with transparent black instead if [`Config::opaque`] is
+    /// `false`. Returns `false` if no image became available in a short
+    /// period of time, in which case nothing is presented.
+    ///
+    /// This uses `acquire_with_timeout` to wait for an image, so the same
+    /// remarks apply. How soon the black frame appears on the screen depends
+    /// on [`Config::vsync`] and the backend in the same way as
+    /// `present_image`:
+    ///
+    ///  - macOS with `vsync` enabled: This method blocks until the next
+    ///    vertical blank.
+    ///  - Wayland: The frame is displayed when the compositor repaints the
+    ///    window, regardless of `vsync`.
+    ///  - Other platforms: The frame is displayed immediately, regardless of
+    ///    `vsync`.
+    ///
+    /// Panics if `update_surface` hasn't been called yet or the acquired image
+    /// is locked.
+    pub fn present_black_frame(&self) -> bool {
+        let i = match self.acquire_with_timeout(Duration::from_millis(100)) {
+            AcquireResult::Ready(i) => i,
+            AcquireResult::Timeout | AcquireResult::Lost => return false,
+        };
+
+        let a = if self.config.get().opaque { 0xff } else { 0 };
+        self.clear_with_color(i, 0, 0, 0, a);
+
+        self.present_image(i);
+        true
+    }
+
+    /// Enqueue the presentation of a swapchain image at index `i` after the
+    /// sync file `acquire_fence` (e.g., exported from a GPU job writing into
+    /// the image) is signaled. Returns a sync file that is signaled when the
+    /// presentation engine has finished reading the image, if available.
+    ///
+    /// This method takes the ownership of `acquire_fence` and closes it. The
+    /// returned file descriptor is owned by the caller.
+    ///
+    /// Explicit synchronization (`zwp_linux_explicit_synchronization_v1`)
+    /// only supports DMA-BUF buffers, and swapchain images are never DMA-BUF
+    /// buffers. Therefore, this method currently blocks the current thread
+    /// until `acquire_fence` is signaled, presents the image by
+    /// `present_image`, and always returns `None`. The image becomes
+    /// available again in the usual way (see [`Surface::poll_next_image`]).
+    ///
+    /// The same preconditions as `present_image` apply.
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    ))]
+    pub fn present_image_with_sync(
+        &self,
+        i: usize,
+        acquire_fence: Option<std::os::unix::io::OwnedFd>,
+    ) -> Option<std::os::unix::io::OwnedFd> {
+        if let Some(fd) = acquire_fence {
+            super::unix::wait_and_close_fence(fd);
+        }
+        self.present_image(i);
+        None
+    }
+
+    /// Enqueue the presentation of a swapchain image at index `i` without
+    /// waiting for the vertical blank, e.g., for an emergency redraw in an
+    /// error dialog.
+    ///
+    /// This ignores [`Config::vsync`] for this presentation only, so it may
+    /// cause a single frame of tearing. The same preconditions as
+    /// `present_image` apply.
+    ///
+    /// # Backend support
+    ///
+    ///  - macOS: Temporarily sets `NSOpenGLCPSwapInterval` to `0`.
+    ///  - Other platforms: Equivalent to `present_image` because presentation
+    ///    never waits for the vertical blank.
+    ///
+    pub fn present_image_immediately(&self, i: usize) {
+        self.assert_initialized();
+        self.call_pre_present_hook(i);
+        self.inner.present_image_immediately(i);
+        self.call_post_present_hook(i);
+        self.record_present(i);
+    }
+
+    /// Enqueue the presentation of a swapchain image at index `i`, displacing
+    /// the contents by `offset` (`[x, y]`).
+    ///
+    /// This is useful for cheap content-shifting animations. The same
+    /// preconditions as `present_image` apply.
+    ///
+    /// # Backend support
+    ///
+    ///  - Wayland: `offset` is passed to `wl_surface::attach`, which moves
+    ///    the surface relative to its current position. The offset is thus
+    ///    cumulative, and it may move the window itself.
+    ///  - X11: The image is drawn at `offset` in the window. The area not
+    ///    covered by the image keeps its previous contents, so a large offset
+    ///    may require presenting the image again without an offset to clean
+    ///    it up.
+    ///  - Other platforms: Equivalent to `present_image`.
+    ///
+    pub fn present_image_at(&self, i: usize, offset: [i32; 2]) {
+        self.assert_initialized();
+        self.call_pre_present_hook(i);
+        self.inner.present_image_at(i, offset);
+        self.call_post_present_hook(i);
+        self.record_present(i);
+    }
+
+    /// Enqueue the presentation of a swapchain image at index `i`, indicating
+    /// that the image differs from the previously presented one only by a
+    /// scroll.
+    ///
+    /// The image must be identical to the previously presented image except
+    /// that the contents of `src_rect` (`[x, y, width, height]`) were moved to
+    /// `dst_pos`, and the area of `src_rect` uncovered by the move was
+    /// redrawn. [`Surface::image_age`] and [`Surface::copy_image`] are useful
+    /// for preparing such an image. This allows the backend to move the
+    /// region on the server side and transfer only the redrawn area.
+    ///
+    /// The same preconditions as `present_image` apply. If either rectangle
+    /// extends beyond the image (e.g., because it was computed before a
+    /// resize), the moved region is shrunk so that both rectangles fit in the
+    /// image. If nothing remains, this is equivalent to `present_image`. This
+    /// is also equivalent to `present_image` for the first presentation after
+    /// [`Surface::invalidate`].
+    ///
+    /// # Backend support
+    ///
+    ///  - Wayland: Only the source and destination rectangles are damaged.
+    ///  - X11: Uses `XCopyArea` to move the region and `XPutImage` to transfer
+    ///    the redrawn area. Falls back to `present_image` if the surface has
+    ///    a transformation (see [`Surface::set_transform`]).
+    ///  - Other platforms: Equivalent to `present_image`.
+    ///
+    pub fn present_region(&self, i: usize, src_rect: [u32; 4], dst_pos: [u32; 2]) {
+        self.assert_initialized();
+
+        // Shrink the moved region until both rectangles fit in the image
+        let image_info = self.image_info();
+        let clipped = image_info
+            .clip_rect(src_rect)
+            .and_then(|[_, _, width, height]| {
+                image_info.clip_rect([dst_pos[0], dst_pos[1], width, height])
+            });
+        let src_rect = match clipped {
+            Some([_, _, width, height]) => {
+                if [width, height] != [src_rect[2], src_rect[3]] {
+                    debug!("The scrolled region {:?} was clipped", src_rect);
+                }
+                [src_rect[0], src_rect[1], width, height]
+            }
+            None => {
+                debug!(
+                    "The scrolled region {:?} is out of bounds; presenting the whole image",
+                    src_rect
+                );
+                return self.present_image(i);
+            }
+        };
+
+        if self.full_damage.get() {
+            debug!("The surface was invalidated; presenting the whole image");
+            return self.present_image(i);
+        }
+
+        self.call_pre_present_hook(i);
+        self.inner.present_region(i, src_rect, dst_pos);
+        self.call_post_present_hook(i);
+        self.record_present(i);
+    }
+
+    /// Set the minimum interval between presentations.
+    ///
+    /// Until `min_interval` has elapsed since the last presentation,
+    /// [`Surface::poll_next_image`] (and the methods built on it, such as
+    /// `acquire_frame`) returns `None`, so the next frame isn't rendered or
+    /// committed before then. Nothing blocks the current thread:
+    /// [`Surface::control_flow_until_ready`] returns the time at which the
+    /// event loop should wake up, and [`Surface::handle_os_event`] calls the
+    /// ready callback at that point. This caps the presentation rate without
+    /// requiring the application to manage its own timer, e.g., to run a
+    /// low-rate animation on a high-refresh-rate display.
+    ///
+    /// Presentation methods called with an image index obtained otherwise
+    /// are not delayed. `acquire_with_timeout` ignores the limit.
+    ///
+    /// `Duration::from_secs(0)` (the default value) disables the limit.
+    pub fn set_present_rate_limit(&self, min_interval: Duration) {
+        self.present_rate_limit.set(min_interval);
+    }
+
+    /// Get the value previously set by `set_present_rate_limit`.
+    pub fn present_rate_limit(&self) -> Duration {
+        self.present_rate_limit.get()
+    }
+
+    /// Set the maximum number of swapchain images that can be in use by the
+    /// presentation engine at once.
+    ///
+    /// While `frames` images are in use, [`Surface::poll_next_image`] returns
+    /// `None` (and schedules a call to the ready callback as usual) even if
+    /// other images are available. This trades smoothness for input latency,
+    /// e.g., `1` keeps the application from rendering more than one frame
+    /// ahead of the display.
+    ///
+    /// The limit has no effect unless it's less than `Config::image_count`,
+    /// and only on backends where presented images are held by the
+    /// presentation engine (Wayland). `acquire_with_timeout` ignores it. By
+    /// default, there's no limit.
+    ///
+    /// Panics if `frames` is zero.
+    pub fn set_max_frame_latency(&self, frames: u32) {
+        assert_ne!(frames, 0, "the frame latency must be at least 1");
+        self.max_frame_latency.set(frames);
+    }
+
+    /// Get the value previously set by `set_max_frame_latency`. Returns
+    /// `u32::MAX` if it hasn't been set.
+    pub fn max_frame_latency(&self) -> u32 {
+        self.max_frame_latency.get()
+    }
+
+    /// Get the time until which `present_rate_limit` defers the next frame.
+    /// Returns `None` if it has already passed.
+    fn present_deadline(&self) -> Option<Instant> {
+        let deadline = self.last_present_time.get()? + self.present_rate_limit.get();
+        (Instant::now() < deadline).then_some(deadline)
+    }
+
+    fn call_pre_present_hook(&self, i: usize) {
+        if let Some(hooks) = &self.present_hooks {
+            (hooks.pre)(self.window_id, i);
+        }
+    }
+
+    fn call_post_present_hook(&self, i: usize) {
+        if let Some(hooks) = &self.present_hooks {
+            (hooks.post)(self.window_id, i);
+        }
+    }
+
+    fn record_present(&self, i: usize) {
+        self.full_damage.set(false);
+        self.last_present_time.set(Some(Instant::now()));
+
+        let present_count = self.present_count.get() + 1;
+        self.present_count.set(present_count);
+        self.last_presents.borrow_mut()[i] = present_count;
+    }
+
+    /// Read back the contents currently displayed in `window`'s client area.
+    ///
+    /// This requires the `screen-capture` feature.
+    ///
+    /// The returned image has the size `window.inner_size()` (in physical
+    /// pixels) and is in the `Argb8888` format with no padding between
+    /// scanlines. The alpha channel is always `0xff` except on the reference
+    /// backend, which returns the presented image as-is. Areas that couldn't
+    /// be captured are filled with zero.
+    ///
+    /// Returns `None` if the platform doesn't permit capture.
+    ///
+    /// # Backend support
+    ///
+    ///  - X11: Uses `XGetImage`. Returns `None` if the window isn't mapped.
+    ///  - Windows: Uses `BitBlt` from the window's device context.
+    ///  - macOS: Uses `CGWindowListCreateImage`, which requires the screen
+    ///    recording permission on macOS 10.15 and later. Without it, the
+    ///    result contains only the desktop background.
+    ///  - Wayland, iOS: Unsupported. Always returns `None`.
+    ///
+    #[cfg(feature = "screen-capture")]
+    pub fn screen_capture(&self, window: &Window) -> Option<Vec<u8>> {
+        let (width, height) = window.inner_size().into();
+        self.inner.screen_capture([width, height])
+    }
+
+    /// Get the age of the contents of the swapchain image at index `i`,
+    /// measured in frames.
+    ///
+    /// If this method returns `n >= 1`, the image holds the contents that
+    /// were presented `n` calls to `present_image` ago. For example, `1`
+    /// means the image holds the most recently presented contents. `0` means
+    /// the contents are unknown, i.e., the image has never been presented
+    /// since the last call to `update_surface`, and the application must
+    /// redraw the whole image.
+    ///
+    /// This is only meaningful if `does_preserve_image() == true`. Otherwise,
+    /// this method always returns `0`.
+    pub fn image_age(&self, i: usize) -> u32 {
+        self.assert_initialized();
+        if !self.does_preserve_image() {
+            return 0;
+        }
+
+        match self.last_presents.borrow()[i] {
+            0 => 0,
+            last_present => {
+                let age = self.present_count.get() - last_present + 1;
+                cmp::min(age, u32::MAX as u64) as u32
+            }
+        }
+    }
+
+    /// Check if `update_surface` was called after the swapchain image at
+    /// index `i` was last presented.
+    ///
+    /// Returns `true` if the image hasn't been presented since the last call
+    /// to `update_surface` or [`Surface::invalidate`] (or at all). In this case, per-image state kept by
+    /// the application (e.g., accumulated damage) is stale, and the whole
+    /// image must be redrawn before presenting it. Unlike `image_age`, this
+    /// doesn't depend on `does_preserve_image`.
+    ///
+    /// Returns `true` if `update_surface` hasn't been called yet. Panics if
+    /// `i` is out of range.
+    pub fn extent_changed_since_last_present(&self, i: usize) -> bool {
+        // `update_surface` resets `last_presents`
+        !self.initialized.get() || self.last_presents.borrow()[i] == 0
+    }
+
+    /// Get the number of swapchain images currently in use by the
+    /// presentation engine, i.e., presented but not released yet.
+    ///
+    /// An application rendering faster than the display refresh rate can use
+    /// this to skip rendering when enough frames are already pending. Backends
+    /// having only one swapchain image always return `0`.
+    /// [`Surface::num_images_presenting`] returns the same value.
+    ///
+    /// Returns `0` if `update_surface` hasn't been called yet.
+    pub fn pending_present_count(&self) -> usize {
+        if !self.initialized.get() {
+            return 0;
+        }
+        (0..self.num_images())
+            .filter(|&i| self.inner.is_presenting(i))
+            .count()
+    }
+
+    /// Lock a swapchain image at index `i`, pass its contents to `f`, and
+    /// enqueue its presentation.
+    ///
+    /// This is a shorthand for calling `lock_image`, `f`, and `present_image`
+    /// in a row. The same preconditions apply.
+    ///
+    /// If `f` panics, the image is unlocked while unwinding and is not
+    /// presented, so it remains available for the next call to
+    /// `poll_next_image`.
+    pub fn map_and_present(&self, i: usize, f: impl FnOnce(&mut [u8], ImageInfo)) {
+        let image_info = self.image_info();
+        f(&mut self.lock_image(i), image_info);
+        self.present_image(i);
+    }
+
+    /// Wait until a swapchain image becomes available, and then lock it, pass
+    /// its contents to `f`, and enqueue its presentation like
+    /// [`Surface::map_and_present`].
+    ///
+    /// This requires the `async` feature.
+    ///
+    /// The returned future is woken up by the ready callback (see
+    /// [`ContextBuilder::with_ready_cb`]), which is called while the event
+    /// loop dispatches events, so the future must be polled on the event
+    /// loop's thread. On backends where swapchain images are always
+    /// available, the future completes on the first poll.
+    ///
+    /// Panics when first polled if `update_surface` hasn't been called yet.
+    #[cfg(feature = "async")]
+    pub async fn acquire_and_render_async(&self, f: impl FnOnce(&mut [u8], ImageInfo)) {
+        // `poll_next_image` would keep returning `None` without scheduling a
+        // call to the ready callback, leaving the future pending forever
+        self.assert_initialized();
+
+        let i = std::future::poll_fn(|cx| match self.poll_next_image() {
+            Some(i) => std::task::Poll::Ready(i),
+            None => {
+                // `poll_next_image` has scheduled a call to the ready callback
+                let mut ready_wakers = self.ready_wakers.borrow_mut();
+                ready_wakers
+                    .retain(|(id, waker)| !(*id == self.window_id && waker.will_wake(cx.waker())));
+                ready_wakers.push((self.window_id, cx.waker().clone()));
+                std::task::Poll::Pending
+            }
+        })
+        .await;
+
+        self.map_and_present(i, f);
+    }
+
+    /// Lock a swapchain image at index `i` and call `f` for each scanline.
+    ///
+    /// `f` receives the row index `y` and the contents of the row. The row
+    /// is trimmed to `extent[0] * format.bytes_per_pixel()` bytes, so `f`
+    /// never sees the padding between rows.
+    ///
+    /// The same preconditions as `lock_image` apply.
+    pub fn map_scanlines_mut(&self, i: usize, f: impl FnMut(u32, &mut [u8])) {
+        let image_info = self.image_info();
+        for_each_scanline_mut(&image_info, &mut self.lock_image(i), f);
+    }
+
+    /// Copy an image from the memory pointed to by `src` to the swapchain
+    /// image at index `i` and enqueue its presentation.
+    ///
+    /// `src_info` describes the source image. Its stride may differ from the
+    /// swapchain image's, and the rows are copied one by one. This is useful
+    /// for copying the output of FFI renderers without constructing a slice.
+    ///
+    /// The same preconditions as `present_image` apply. Additionally, panics
+    /// if `src_info`'s format or extent doesn't match [`Surface::image_info`],
+    /// or its stride is smaller than a row.
+    ///
+    /// # Safety
+    ///
+    /// `src` must be valid for reads of
+    /// `src_info.stride * (extent[1] - 1) + extent[0] * bytes_per_pixel`
+    /// bytes (or zero bytes if the image is empty).
+    pub unsafe fn copy_from_raw(&self, i: usize, src: *const u8, src_info: ImageInfo) {
+        let image_info = self.image_info();
+        assert_eq!(
+            src_info.format, image_info.format,
+            "the formats don't match"
+        );
+        assert_eq!(
+            src_info.extent, image_info.extent,
+            "the extents don't match"
+        );
+        assert!(
+            src_info.stride >= src_info.extent[0] as usize * src_info.format.bytes_per_pixel(),
+            "the source stride is too small"
+        );
+
+        self.map_scanlines_mut(i, |y, row| {
+            let src_row = src.add(y as usize * src_info.stride);
+            std::ptr::copy_nonoverlapping(src_row, row.as_mut_ptr(), row.len());
+        });
+        self.present_image(i);
+    }
+
+    /// Copy the contents of the swapchain image at index `src_i` to the one at
+    /// index `dst_i`.
+    ///
+    /// This is useful for carrying the contents forward to another swapchain
+    /// image when rendering incrementally.
+    ///
+    /// Panics if `update_surface` hasn't been called yet, or either image is
+    /// locked or currently in use by the presentation engine.
+    pub fn copy_image(&self, src_i: usize, dst_i: usize) {
+        self.assert_initialized();
+        self.inner.copy_image(src_i, dst_i)
+    }
+
+    /// Copy the pixels inside `rect` (`[x, y, width, height]`) of the
+    /// swapchain image at index `i` to `out`.
+    ///
+    /// The pixels are copied in the current pixel format without padding
+    /// between rows, so `out.len()` must be `width * height * bpp`, where
+    /// `bpp` is [`Format::bytes_per_pixel`]. This is cheaper than locking
+    /// the whole image and copying it when only a small region is needed,
+    /// e.g., for thumbnails or to verify a single widget in tests.
+    ///
+    /// The same preconditions as `lock_image` apply. Additionally, panics if
+    /// `rect` extends beyond the image or `out` has a wrong length.
+    pub fn read_region(&self, i: usize, rect: [u32; 4], out: &mut [u8]) {
+        let image_info = self.image_info();
+        let data = self.lock_image(i);
+        read_region(&image_info, &data, rect, out);
+    }
+
+    /// Fill the swapchain image at index `i` with a solid color.
+    ///
+    /// The color is converted to the current pixel format. `a` is ignored if
+    /// the format doesn't have an alpha channel. Note that the alpha values of
+    /// a non-opaque surface are interpreted as pre-multiplied alpha (see
+    /// [`Config::opaque`]).
+    ///
+    /// The same preconditions as `lock_image` apply.
+    pub fn clear_with_color(&self, i: usize, r: u8, g: u8, b: u8, a: u8) {
+        let pixel = self.image_info().format.encode_rgba([r, g, b, a]);
+        self.map_scanlines_mut(i, |_, row| {
+            for p in row.chunks_exact_mut(pixel.len()) {
+                p.copy_from_slice(&pixel);
+            }
+        });
+    }
+
+    /// Enable or disable the blur effect applied to the content behind the
+    /// window.
+    ///
+    /// This is only meaningful for a non-opaque surface (see
+    /// [`Config::opaque`]).
+    ///
+    /// # Backend support
+    ///
+    ///  - macOS: Places `NSVisualEffectView` behind the content.
+    ///  - Other platforms: This method is a no-op.
+    ///
+    pub fn set_blur_behind(&self, enabled: bool) {
+        self.inner.set_blur_behind(enabled)
+    }
+
+    /// Get a flag indicating whether [`Config::content_protection`] is in
+    /// effect.
+    ///
+    /// Returns `false` if content protection wasn't requested or isn't
+    /// supported by the backend or the operating system.
+    pub fn is_content_protection_active(&self) -> bool {
+        self.inner.is_content_protection_active()
+    }
+
+    /// Set the region of the window that accepts pointer input.
+    ///
+    /// `region` is a list of rectangles `[x, y, width, height]` in the window
+    /// coordinate space. Pointer events outside the region pass through to
+    /// whatever is behind the window. Passing an empty slice makes the whole
+    /// window click-through. Passing `None` resets the input region to the
+    /// default (the whole window).
+    ///
+    /// # Backend support
+    ///
+    ///  - Wayland: Uses `wl_surface::set_input_region`. The new region takes
+    ///    effect when the next image is presented.
+    ///  - X11: Uses the input shape of the XFixes extension. This method is
+    ///    a no-op if `libXfixes` is not available.
+    ///  - Other platforms: This method is a no-op. On Windows, the extended
+    ///    window style `WS_EX_TRANSPARENT` makes the whole window
+    ///    click-through. On macOS, `-[NSWindow setIgnoresMouseEvents:]` does
+    ///    the same.
+    ///
+    pub fn set_input_region(&self, region: Option<&[[u32; 4]]>) {
+        self.inner.set_input_region(region)
+    }
+
+    /// Copy the contents of the swapchain image at index `i` to a
+    /// [`softbuffer`] buffer, converting them to `softbuffer`'s pixel format
+    /// (`0x00RRGGBB`).
+    ///
+    /// This is intended to help applications migrating between this crate and
+    /// `softbuffer`. Panics if the size of `sb_buffer` doesn't match
+    /// `image_info().extent`.
+    #[cfg(feature = "softbuffer-compat")]
+    pub fn export_image_to_softbuffer<D, W>(
+        &self,
+        i: usize,
+        sb_buffer: &mut softbuffer::Buffer<'_, D, W>,
+    ) where
+        D: raw_window_handle_06::HasDisplayHandle,
+        W: raw_window_handle_06::HasWindowHandle,
+    {
+        let image_info = self.image_info();
+        let [width, height] = image_info.extent;
+        assert_eq!(
+            [sb_buffer.width().get(), sb_buffer.height().get()],
+            [width, height],
+            "the size of the `softbuffer` buffer doesn't match the image"
+        );
+
+        let image = self.lock_image(i);
+        for (src_row, dst_row) in image
+            .chunks(image_info.stride)
+            .zip(sb_buffer.chunks_exact_mut(width as usize))
+        {
+            for (src, dst) in src_row.chunks_exact(4).zip(dst_row.iter_mut()) {
+                let pixel = u32::from_le_bytes([src[0], src[1], src[2], src[3]]);
+                *dst = match image_info.format {
+                    // `0xAARRGGBB` or `0xXXRRGGBB`
+                    Format::Argb8888 | Format::Xrgb8888 => pixel & 0xffffff,
+                    // `0xXXBBGGRR`
+                    Format::Xbgr8888 => pixel.swap_bytes() >> 8,
+                };
+            }
+        }
+    }
+
+    /// Set how damaged regions are coalesced when presenting an image.
+    ///
+    /// Defaults to `MergeStrategy::MaxRects(16)`.
+    ///
+    /// # Backend support
+    ///
+    ///  - Wayland: Applied to the rectangles passed to
+    ///    `wl_surface::damage_buffer`.
+    ///  - X11: Applied to the rectangles transferred by `XPutImage`.
+    ///  - Other platforms: Ignored because the whole image is always updated.
+    ///
+    pub fn set_damage_merge_strategy(&self, strategy: MergeStrategy) {
+        self.inner.set_damage_merge_strategy(strategy)
+    }
+
+    /// Attach a human-readable label to the surface for debugging.
+    ///
+    ///  - macOS: Labels the OpenGL texture (requires `GL_EXT_debug_label`),
+    ///    which is displayed by graphics debuggers.
+    ///  - iOS: Sets the name of the `CALayer`.
+    ///  - Wayland: Included in log messages.
+    ///  - All backends: Included in the messages of assertion failures.
+    ///
+    /// This is a no-op unless debug assertions are enabled.
+    pub fn set_debug_label(&self, label: impl Into<String>) {
+        #[cfg(debug_assertions)]
+        {
+            let label = label.into();
+            self.inner.set_debug_label(&label);
+            *self.debug_label.borrow_mut() = Some(label);
+        }
+        #[cfg(not(debug_assertions))]
+        let _ = label;
+    }
+
+    /// Panic if a swapchain image is accessed before `update_surface` is
+    /// called.
+    fn assert_initialized(&self) {
+        #[cfg(debug_assertions)]
+        let label = self.debug_label.borrow();
+        #[cfg(debug_assertions)]
+        let label = label.as_deref();
+        #[cfg(not(debug_assertions))]
+        let label = None;
+
+        assert_initialized(self.initialized.get(), label);
+    }
+}
+
+/// A render target that provides CPU-accessible images, implemented by
+/// [`Surface`] and [`SwWindow`].
+///
+/// This allows an application to write its presentation code once for
+/// different kinds of render targets. For example, an application that
+/// falls back to software rendering when OpenGL isn't available can
+/// implement `Drawable` for its GPU-backed surface (e.g., by mapping a pixel
+/// buffer object) and combine the two:
+///
+/// ```
+/// use std::ops::DerefMut;
+/// use swsurface::{Drawable, ImageInfo, Surface};
+///
+/// enum CompatSurface<G> {
+///     Gpu(G),
+///     Software(Surface),
+/// }
+///
+/// impl<G: Drawable> Drawable for CompatSurface<G> {
+///     fn image_info(&self) -> ImageInfo {
+///         match self {
+///             CompatSurface::Gpu(s) => s.image_info(),
+///             CompatSurface::Software(s) => s.image_info(),
+///         }
+///     }
+///
+///     fn acquire(&self) -> Option<usize> {
+///         match self {
+///             CompatSurface::Gpu(s) => s.acquire(),
+///             CompatSurface::Software(s) => Drawable::acquire(s),
+///         }
+///     }
+///
+///     fn lock(&self, i: usize) -> Box<dyn DerefMut<Target = [u8]> + '_> {
+///         match self {
+///             CompatSurface::Gpu(s) => s.lock(i),
+///             CompatSurface::Software(s) => s.lock(i),
+///         }
+///     }
+///
+///     fn present(&self, i: usize) {
+///         match self {
+///             CompatSurface::Gpu(s) => s.present(i),
+///             CompatSurface::Software(s) => s.present(i),
+///         }
+///     }
+/// }
+///
+/// fn draw(surface: &impl Drawable) {
+///     if let Some(i) = surface.acquire() {
+///         surface.lock(i).fill(0xff);
+///         surface.present(i);
+///     }
+/// }
+/// ```
+pub trait Drawable {
+    /// Get the `ImageInfo` describing the images.
+    fn image_info(&self) -> ImageInfo;
+
+    /// Get the index of the next available image. Returns `None` if no image
+    /// is available.
+    fn acquire(&self) -> Option<usize>;
+
+    /// Lock the image at index `i` to access its contents.
+    fn lock(&self, i: usize) -> Box<dyn DerefMut<Target = [u8]> + '_>;
+
+    /// Enqueue the presentation of the image at index `i`.
+    fn present(&self, i: usize);
+}
+
+/// Forwards to [`Surface::image_info`], [`Surface::poll_next_image`],
+/// [`Surface::lock_image`], and [`Surface::present_image`].
+impl Drawable for Surface {
+    fn image_info(&self) -> ImageInfo {
+        self.image_info()
+    }
+
+    fn acquire(&self) -> Option<usize> {
+        self.poll_next_image()
+    }
+
+    fn lock(&self, i: usize) -> Box<dyn DerefMut<Target = [u8]> + '_> {
+        Box::new(self.lock_image(i))
+    }
+
+    fn present(&self, i: usize) {
+        self.present_image(i)
+    }
+}
+
+impl Drawable for SwWindow {
+    fn image_info(&self) -> ImageInfo {
+        (**self).image_info()
+    }
+
+    fn acquire(&self) -> Option<usize> {
+        (**self).poll_next_image()
+    }
+
+    fn lock(&self, i: usize) -> Box<dyn DerefMut<Target = [u8]> + '_> {
+        Box::new((**self).lock_image(i))
+    }
+
+    fn present(&self, i: usize) {
+        (**self).present_image(i)
+    }
+}
+
+impl<T: Drawable + ?Sized> Drawable for &T {
+    fn image_info(&self) -> ImageInfo {
+        (**self).image_info()
+    }
+
+    fn acquire(&self) -> Option<usize> {
+        (**self).acquire()
+    }
+
+    fn lock(&self, i: usize) -> Box<dyn DerefMut<Target = [u8]> + '_> {
+        (**self).lock(i)
+    }
+
+    fn present(&self, i: usize) {
+        (**self).present(i)
+    }
+}
+
+impl<T: Drawable + ?Sized> Drawable for Box<T> {
+    fn image_info(&self) -> ImageInfo {
+        (**self).image_info()
+    }
+
+    fn acquire(&self) -> Option<usize> {
+        (**self).acquire()
+    }
+
+    fn lock(&self, i: usize) -> Box<dyn DerefMut<Target = [u8]> + '_> {
+        (**self).lock(i)
+    }
+
+    fn present(&self, i: usize) {
+        (**self).present(i)
+    }
+}
+
+/// Reinterprets the contents of a lock guard returned by
+/// `SurfaceImpl::lock_image` as `[T]`.
+#[cfg(feature = "bytemuck")]
+struct CastGuard<G, T> {
+    guard: G,
+    _phantom: std::marker::PhantomData<T>,
+}
+
+#[cfg(feature = "bytemuck")]
+impl<G: DerefMut<Target = [u8]>, T: bytemuck::Pod> std::ops::Deref for CastGuard<G, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        bytemuck::cast_slice(&self.guard)
+    }
+}
+
+#[cfg(feature = "bytemuck")]
+impl<G: DerefMut<Target = [u8]>, T: bytemuck::Pod> DerefMut for CastGuard<G, T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        bytemuck::cast_slice_mut(&mut self.guard)
+    }
+}
+
+/// A lock guard returned by [`Surface::lock_image_writer`].
+///
+/// Writing through `std::io::Write` copies the data to the image at the
+/// current position and advances it. Once the end of the image is reached,
+/// `write` returns `Ok(0)`.
+pub struct LockedImage<'a> {
+    guard: Box<dyn DerefMut<Target = [u8]> + 'a>,
+    /// The position where the next `write` starts
+    cursor: usize,
+}
+
+impl fmt::Debug for LockedImage<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LockedImage")
+            .field("len", &self.guard.len())
+            .field("cursor", &self.cursor)
+            .finish()
+    }
+}
+
+impl LockedImage<'_> {
+    /// Get the position where the next `write` starts.
+    pub fn position(&self) -> usize {
+        self.cursor
+    }
+
+    /// Move the write position to `pos` bytes from the beginning of the
+    /// image.
+    ///
+    /// Panics if `pos` is greater than the image's length.
+    pub fn seek_to(&mut self, pos: usize) {
+        assert!(pos <= self.guard.len(), "position out of range");
+        self.cursor = pos;
+    }
+}
+
+impl std::ops::Deref for LockedImage<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.guard
+    }
+}
+
+impl DerefMut for LockedImage<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.guard
+    }
+}
+
+impl std::io::Write for LockedImage<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let dst = &mut self.guard[self.cursor..];
+        let len = cmp::min(buf.len(), dst.len());
+        dst[..len].copy_from_slice(&buf[..len]);
+        self.cursor += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A lock guard returned by [`Surface::lock_image_region`]. Presents the image
+/// on drop.
+pub struct RegionGuard<'a> {
+    surface: &'a Surface,
+    i: usize,
+    /// The guard returned by `lock_image`. `None` after dropped.
+    guard: Option<Box<dyn DerefMut<Target = [u8]> + 'a>>,
+    /// The clipped region, in the image's coordinate space
+    rect: [u32; 4],
+    /// Describes the locked rows
+    image_info: ImageInfo,
+}
+
+impl fmt::Debug for RegionGuard<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegionGuard")
+            .field("i", &self.i)
+            .field("rect", &self.rect)
+            .field("image_info", &self.image_info)
+            .finish()
+    }
+}
+
+impl RegionGuard<'_> {
+    /// Get the region to update (`[x, y, width, height]`), in the coordinate
+    /// space of the whole image.
+    pub fn rect(&self) -> [u32; 4] {
+        self.rect
+    }
+
+    /// Get the `ImageInfo` describing the locked rows. `extent[1]` is the
+    /// height of the region, and row `0` corresponds to row `rect()[1]` of
+    /// the image.
+    pub fn image_info(&self) -> ImageInfo {
+        self.image_info
+    }
+
+    fn byte_range(&self) -> std::ops::Range<usize> {
+        let start = self.rect[1] as usize * self.image_info.stride;
+        start..start + self.rect[3] as usize * self.image_info.stride
+    }
+}
+
+impl std::ops::Deref for RegionGuard<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.guard.as_ref().unwrap()[self.byte_range()]
+    }
+}
+
+impl DerefMut for RegionGuard<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        let range = self.byte_range();
+        &mut self.guard.as_mut().unwrap()[range]
+    }
+}
+
+impl Drop for RegionGuard<'_> {
+    fn drop(&mut self) {
+        // Unlock the image first
+        drop(self.guard.take());
+        self.surface.present_image_damaged(self.i, &[self.rect]);
+    }
+}
+
+/// A lock guard returned by [`Surface::acquire_frame`]. Presents the image on
+/// drop unless presented by [`Surface::present_frame`].
+pub struct ImageFrame<'a> {
+    surface: &'a Surface,
+    i: usize,
+    /// The guard returned by `lock_image`. `None` after presented.
+    guard: Option<Box<dyn DerefMut<Target = [u8]> + 'a>>,
+    image_info: ImageInfo,
+}
+
+impl fmt::Debug for ImageFrame<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ImageFrame")
+            .field("i", &self.i)
+            .field("image_info", &self.image_info)
+            .finish()
+    }
+}
+
+impl ImageFrame<'_> {
+    /// Get the index of the swapchain image.
+    pub fn index(&self) -> usize {
+        self.i
+    }
+
+    /// Get the `ImageInfo` describing the image.
+    pub fn image_info(&self) -> ImageInfo {
+        self.image_info
+    }
+}
+
+impl std::ops::Deref for ImageFrame<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for ImageFrame<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl Drop for ImageFrame<'_> {
+    fn drop(&mut self) {
+        if let Some(guard) = self.guard.take() {
+            // Unlock the image first
+            drop(guard);
+            self.surface.present_image(self.i);
+        }
+    }
+}
+
+/// A lock guard returned by [`Surface::borrow_as_image_buffer`]. Presents the
+/// image on drop.
+#[cfg(feature = "image")]
+pub struct ImageBufferGuard<'a> {
+    surface: &'a Surface,
+    i: usize,
+    /// The guard returned by `lock_image`. `None` after dropped.
+    guard: Option<Box<dyn DerefMut<Target = [u8]> + 'a>>,
+    image_info: ImageInfo,
+}
+
+#[cfg(feature = "image")]
+impl fmt::Debug for ImageBufferGuard<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ImageBufferGuard")
+            .field("i", &self.i)
+            .field("image_info", &self.image_info)
+            .finish()
+    }
+}
+
+#[cfg(feature = "image")]
+impl ImageBufferGuard<'_> {
+    /// Get the `ImageInfo` describing the locked image.
+    pub fn image_info(&self) -> ImageInfo {
+        self.image_info
+    }
+
+    /// View the locked image as an `image::ImageBuffer`.
+    ///
+    /// Note that the red and blue channels are swapped. See
+    /// [`Surface::borrow_as_image_buffer`] for details.
+    pub fn image_buffer(&mut self) -> image::ImageBuffer<image::Rgba<u8>, &mut [u8]> {
+        let [width, height] = self.image_info.extent;
+        let size = self.image_info.stride * height as usize;
+        let data = &mut self.guard.as_mut().unwrap()[..size];
+        image::ImageBuffer::from_raw(width, height, data).unwrap()
+    }
+}
+
+#[cfg(feature = "image")]
+impl Drop for ImageBufferGuard<'_> {
+    fn drop(&mut self) {
+        // Unlock the image first
+        drop(self.guard.take());
+        self.surface.present_image(self.i);
+    }
+}
+
+/// Presents swapchain images of multiple [`Surface`]s together.
+///
+/// On Wayland, committing a `wl_surface` is not synchronized with other
+/// surfaces. `PresentGroup` attaches the buffers to all surfaces first and
+/// then commits them in rapid succession, so the compositor is likely to
+/// pick up all of them in the same frame. On other backends, the images are
+/// simply presented in sequence.
+#[derive(Debug, Default)]
+pub struct PresentGroup<'a> {
+    images: Vec<(&'a Surface, usize)>,
+}
+
+impl<'a> PresentGroup<'a> {
+    /// Construct an empty `PresentGroup`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the swapchain image at index `i` of `surface` to the group.
+    pub fn with(mut self, surface: &'a Surface, i: usize) -> Self {
+        self.images.push((surface, i));
+        self
+    }
+
+    /// Present all images in the group. See [`PresentGroup::present_all`].
+    pub fn present(self) {
+        Self::present_all(&self.images);
+    }
+
+    /// Present the swapchain images `images` (`(surface, image_index)`)
+    /// together.
+    ///
+    /// The same preconditions as [`Surface::present_image`] apply to each
+    /// image. A surface must not appear more than once. The preconditions
+    /// are checked for all images before any of them is presented, so a
+    /// panic doesn't leave some of the surfaces with uncommitted images.
+    pub fn present_all(images: &[(&Surface, usize)]) {
+        for (k, &(surface, i)) in images.iter().enumerate() {
+            surface.assert_initialized();
+            assert!(!surface.is_locked(i), "the image is currently locked");
+            assert!(
+                !surface.is_presenting(i),
+                "the image is currently in use by the presentation engine"
+            );
+            assert!(
+                !images[..k]
+                    .iter()
+                    .any(|&(other, _)| std::ptr::eq(other, surface)),
+                "a surface appears more than once"
+            );
+        }
+
+        for &(surface, i) in images {
+            surface.call_pre_present_hook(i);
+            surface.inner.present_image_uncommitted(i);
+            surface.record_present(i);
+        }
+
+        for &(surface, i) in images {
+            surface.inner.commit_present();
+            surface.call_post_present_hook(i);
+        }
+    }
+}
+
+/// A child surface covering a rectangular region of a [`Surface`], created by
+/// [`Surface::create_subsurface`].
+///
+/// A subsurface has its own swapchain images, which can be updated and
+/// presented without presenting the parent surface.
+#[derive(Debug)]
+pub struct SubSurface<'a> {
+    parent: &'a Surface,
+    rect: [u32; 4],
+    inner: SubSurfaceInner,
+}
+
+#[derive(Debug)]
+enum SubSurfaceInner {
+    /// Backed by a native subsurface
+    Native(Box<SubSurfaceImpl>),
+    /// Emulated by copying the contents to the parent's swapchain image
+    Emulated {
+        image: RefCell<Vec<u8>>,
+        image_info: ImageInfo,
+    },
+}
+
+impl Surface {
+    /// Create a [`SubSurface`] covering the region `rect` (`[x, y, width,
+    /// height]`) of the surface.
+    ///
+    /// The subsurface's swapchain images have the size `rect[2..4]` and the
+    /// surface's current format.
+    ///
+    /// Panics if `update_surface` hasn't been called yet, or `rect[2]` or
+    /// `rect[3]` is zero.
+    ///
+    /// # Backend support
+    ///
+    ///  - Wayland: The subsurface is backed by a desynchronized
+    ///    `wl_subsurface`. Its position is applied when the parent surface is
+    ///    presented next time. Falls back to emulation if the compositor
+    ///    doesn't support `wl_subcompositor`.
+    ///  - Other platforms: Emulated. Presenting the subsurface copies its
+    ///    contents to the parent's next swapchain image and presents it as a
+    ///    whole. This only preserves the rest of the parent's contents if
+    ///    the parent has one swapchain image.
+    ///
+    pub fn create_subsurface(&self, rect: [u32; 4]) -> SubSurface<'_> {
+        self.assert_initialized();
+        assert_ne!(rect[2], 0);
+        assert_ne!(rect[3], 0);
+
+        let inner = match self.inner.create_subsurface(rect, &self.config.get()) {
+            Some(imp) => SubSurfaceInner::Native(Box::new(imp)),
+            None => {
+                let format = self.image_info().format;
+                let stride = rect[2] as usize * format.bytes_per_pixel();
+                let image_info = ImageInfo {
+                    extent: [rect[2], rect[3]],
+                    stride,
+                    ..self.image_info()
+                };
+                SubSurfaceInner::Emulated {
+                    image: RefCell::new(vec![0; stride * rect[3] as usize]),
+                    image_info,
+                }
+            }
+        };
+
+        SubSurface {
+            parent: self,
+            rect,
+            inner,
+        }
+    }
+}
+
+impl SubSurface<'_> {
+    /// Get the region of the parent surface covered by the subsurface.
+    pub fn rect(&self) -> [u32; 4] {
+        self.rect
+    }
+
+    /// Get the `ImageInfo` describing the subsurface's swapchain images.
+    pub fn image_info(&self) -> ImageInfo {
+        match &self.inner {
+            SubSurfaceInner::Native(imp) => imp.surface().image_info(),
+            SubSurfaceInner::Emulated { image_info, .. } => *image_info,
+        }
+    }
+
+    /// Get the number of swapchain images.
+    pub fn num_images(&self) -> usize {
+        match &self.inner {
+            SubSurfaceInner::Native(imp) => imp.surface().num_images(),
+            SubSurfaceInner::Emulated { .. } => 1,
+        }
+    }
+
+    /// Get the index of the next available swapchain image. See
+    /// [`Surface::poll_next_image`].
+    pub fn poll_next_image(&self) -> Option<usize> {
+        match &self.inner {
+            SubSurfaceInner::Native(imp) => imp.surface().poll_next_image(),
+            SubSurfaceInner::Emulated { .. } => Some(0),
+        }
+    }
+
+    /// Lock a swapchain image at index `i` to access its contents. See
+    /// [`Surface::lock_image`].
+    pub fn lock_image(&self, i: usize) -> Box<dyn DerefMut<Target = [u8]> + '_> {
+        match &self.inner {
+            SubSurfaceInner::Native(imp) => Box::new(imp.surface().lock_image(i)),
+            SubSurfaceInner::Emulated { image, .. } => {
+                assert_eq!(i, 0);
+                Box::new(std::cell::RefMut::map(
+                    image.try_borrow_mut().expect("the image is locked"),
+                    |image| &mut image[..],
+                ))
+            }
+        }
+    }
+
+    /// Enqueue the presentation of a swapchain image at index `i`. See
+    /// [`Surface::present_image`].
+    ///
+    /// If the subsurface is emulated, this also panics if none of the
+    /// parent's swapchain images is available.
+    pub fn present_image(&self, i: usize) {
+        match &self.inner {
+            SubSurfaceInner::Native(imp) => imp.surface().present_image(i),
+            SubSurfaceInner::Emulated { image, image_info } => {
+                assert_eq!(i, 0);
+                self.present_emulated(&image.borrow(), image_info);
+            }
+        }
+    }
+
+    fn present_emulated(&self, image: &[u8], image_info: &ImageInfo) {
+        let parent = self.parent;
+        let parent_i = match parent.acquire_with_timeout(Duration::from_secs(0)) {
+            AcquireResult::Ready(i) => i,
+            _ => panic!("no swapchain image of the parent surface is available"),
+        };
+        let parent_info = parent.image_info();
+
+        // Clip the region by the parent's extent
+        let [x, y, _, _] = self.rect;
+        let [width, height] = image_info.extent;
+        let [width, height] = match parent_info.clip_rect([x, y, width, height]) {
+            Some([_, _, width, height]) => [width as usize, height as usize],
+            None => [0, 0],
+        };
+
+        let (src_format, dst_format) = (image_info.format, parent_info.format);
+        let bpp = src_format.bytes_per_pixel();
+
+        {
+            let mut parent_image = parent.lock_image(parent_i);
+            for row in 0..height {
+                let src = &image[row * image_info.stride..][..width * bpp];
+                let dst = &mut parent_image
+                    [(y as usize + row) * parent_info.stride + x as usize * bpp..][..width * bpp];
+                if src_format == dst_format {
+                    dst.copy_from_slice(src);
+                } else {
+                    for (s, d) in src.chunks_exact(4).zip(dst.chunks_exact_mut(4)) {
+                        let rgba = src_format.decode_rgba([s[0], s[1], s[2], s[3]]);
+                        d.copy_from_slice(&dst_format.encode_rgba(rgba));
+                    }
+                }
+            }
+        }
+
+        parent.present_image(parent_i);
+    }
+}
+
+/// Panic if a swapchain image is accessed before `update_surface` is called.
+/// Without this check, the backends would panic in various obscure ways.
+pub(crate) fn assert_initialized(initialized: bool, label: Option<&str>) {
+    if let Some(label) = label {
+        assert!(
+            initialized,
+            "the surface {:?} is not initialized; call `update_surface` first",
+            label
+        );
+    } else {
+        assert!(
+            initialized,
+            "the surface is not initialized; call `update_surface` first"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests using a `Surface` backed by the reference backend
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    ))]
+    mod reference {
+        use super::*;
+        use crate::Format;
+
+        fn new_surface(image_count: usize) -> Surface {
+            new_surface_with_ready_cb(image_count, Box::new(|_| {}))
+        }
+
+        fn new_surface_with_ready_cb(image_count: usize, ready_cb: ReadyCb) -> Surface {
+            let ready_cb = Rc::new(SharedReadyCb(ready_cb));
+            let context = Context {
+                inner: ContextImpl::new_reference(Box::new({
+                    let ready_cb = Rc::clone(&ready_cb);
+                    move |id| (ready_cb.0)(id)
+                })),
+                num_surfaces: Arc::new(AtomicUsize::new(0)),
+                present_hooks: None,
+                ready_cb,
+                #[cfg(feature = "async")]
+                ready_wakers: Rc::default(),
+            };
+            let config = Config {
+                image_count,
+                ..Config::default()
+            };
+            let wnd_id = unsafe { WindowId::dummy() };
+            let inner = SurfaceImpl::new_reference(wnd_id, &context.inner, &config);
+            Surface::from_impl(inner, wnd_id, &context, &config).unwrap()
+        }
+
+        #[test]
+        fn scaling_content_after_acquire() {
+            let surface = new_surface(2);
+            surface.update_surface([2, 2], Format::Argb8888);
+
+            let i = surface.poll_next_image().unwrap();
+            surface.lock_image(i)[..4].copy_from_slice(&[1, 2, 3, 4]);
+
+            // The contents of the image acquired before resizing are scaled
+            surface.update_surface_scaling_content([4, 4], Format::Argb8888);
+
+            let i = surface.poll_next_image().unwrap();
+            assert_eq!(surface.lock_image(i)[..8], [1, 2, 3, 4, 1, 2, 3, 4]);
+        }
+
+        #[test]
+        fn aligned_stride_survives_reconfigure() {
+            let surface = new_surface(2);
+            surface.update_surface_aligned([3, 2], Format::Argb8888, 64);
+            assert_eq!(surface.image_info().stride, 64);
+
+            surface.set_vsync(!surface.vsync_enabled());
+            surface.update_surface([3, 2], Format::Argb8888);
+            assert_eq!(surface.image_info().stride, 64);
+
+            // A different alignment is applied
+            surface
+                .reconfigure(&Config {
+                    image_count: 2,
+                    scanline_align: 4,
+                    ..Config::default()
+                })
+                .unwrap();
+            surface.update_surface([3, 2], Format::Argb8888);
+            assert_eq!(surface.image_info().stride, 12);
+        }
+
+        #[test]
+        fn reconfigure_image_count() {
+            let surface = new_surface(2);
+            surface.update_surface([2, 2], Format::Argb8888);
+
+            let config = Config {
+                image_count: 3,
+                ..Config::default()
+            };
+            surface.reconfigure(&config).unwrap();
+            assert_eq!(surface.num_images(), 3);
+
+            // The image to be removed is in use
+            surface.present_image(2);
+            let config = Config {
+                image_count: 2,
+                ..config
+            };
+            assert_eq!(
+                surface.reconfigure(&config),
+                Err(SurfaceError::RequiresRecreation(vec!["image_count"]))
+            );
+            assert_eq!(surface.num_images(), 3);
+        }
+
+        #[test]
+        fn lock_image_timeout() {
+            let surface = new_surface(2);
+            surface.update_surface([2, 2], Format::Argb8888);
+
+            let timeout = Duration::from_millis(10);
+            let image = surface.lock_image_timeout(0, timeout).unwrap();
+            assert!(surface.lock_image_timeout(0, timeout).is_none());
+            drop(image);
+            assert!(surface.lock_image_timeout(0, timeout).is_some());
+        }
+
+        #[test]
+        fn lock_after_update_without_acquire() {
+            let surface = new_surface(2);
+            surface.update_surface([2, 2], Format::Argb8888);
+            let i = surface.poll_next_image().unwrap();
+
+            // Acquiring the image again after resizing is not required
+            surface.update_surface([4, 4], Format::Argb8888);
+            let image_info = surface.image_info();
+            assert_eq!(
+                surface.lock_image(i).len(),
+                image_info.extent[1] as usize * image_info.stride
+            );
+        }
+
+        #[test]
+        fn present_rate_limit() {
+            let num_calls = Rc::new(Cell::new(0));
+            let surface = new_surface_with_ready_cb(2, {
+                let num_calls = Rc::clone(&num_calls);
+                Box::new(move |_| num_calls.set(num_calls.get() + 1))
+            });
+            surface.update_surface([2, 2], Format::Argb8888);
+            surface.set_present_rate_limit(Duration::from_secs(3600));
+
+            let i = surface.poll_next_image().unwrap();
+            surface.present_image(i);
+
+            // The next frame is deferred without blocking
+            assert_eq!(surface.poll_next_image(), None);
+            assert!(matches!(
+                surface.control_flow_until_ready(),
+                ControlFlow::WaitUntil(_)
+            ));
+            surface.handle_os_event(&Event::<()>::MainEventsCleared);
+            assert_eq!(num_calls.get(), 0);
+
+            // The ready callback is called once the limit expires
+            surface.set_present_rate_limit(Duration::from_secs(0));
+            surface.handle_os_event(&Event::<()>::MainEventsCleared);
+            assert_eq!(num_calls.get(), 1);
+            assert!(surface.poll_next_image().is_some());
+        }
+
+        #[test]
+        fn present_all_checks_before_presenting() {
+            let surface1 = new_surface(2);
+            let surface2 = new_surface(2);
+            surface1.update_surface([2, 2], Format::Argb8888);
+            surface2.update_surface([2, 2], Format::Argb8888);
+
+            // The second entry is invalid, so the first one must not be
+            // presented either
+            let _image = surface2.lock_image(0);
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                PresentGroup::present_all(&[(&surface1, 0), (&surface2, 0)]);
+            }));
+            assert!(result.is_err());
+            assert!(!surface1.is_presenting(0));
+
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                PresentGroup::present_all(&[(&surface1, 0), (&surface1, 1)]);
+            }));
+            assert!(result.is_err());
+            assert!(!surface1.is_presenting(0));
+        }
+
+        #[test]
+        #[should_panic(expected = "call `update_surface` first")]
+        fn uninitialized_access() {
+            let surface = new_surface(2);
+            assert_eq!(surface.poll_next_image(), None);
+            surface.lock_image(0);
+        }
+
+        #[test]
+        #[cfg(debug_assertions)]
+        #[should_panic(expected = "surface \"main\" is not initialized")]
+        fn uninitialized_access_labeled() {
+            let surface = new_surface(2);
+            surface.set_debug_label("main");
+            surface.present_image(0);
+        }
+    }
+
+    #[test]
+    fn locked_image_write() {
+        use std::io::Write;
+
+        let mut data = [0u8; 6];
+        let mut image = LockedImage {
+            guard: Box::new(&mut data[..]),
+            cursor: 0,
+        };
+        image.write_all(&[1, 2, 3, 4]).unwrap();
+        assert_eq!(image.position(), 4);
+        assert_eq!(image.write(&[5, 6, 7]).unwrap(), 2);
+        assert_eq!(image.write(&[8]).unwrap(), 0);
+
+        image.seek_to(1);
+        image.write_all(&[9]).unwrap();
+        drop(image);
+
+        assert_eq!(data, [1, 9, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn buffer_write() {
+        use std::io::Write;
+
+        let mut buffer = crate::Buffer::from_size_align(6, 4).unwrap();
+        buffer.write_all(&[1, 2, 3, 4]).unwrap();
+        assert_eq!(buffer.position(), 4);
+        assert_eq!(buffer.write(&[5, 6, 7]).unwrap(), 2);
+        assert_eq!(buffer.write(&[8]).unwrap(), 0);
+
+        buffer.seek_to(1);
+        buffer.write_all(&[9]).unwrap();
+        assert_eq!(&buffer[..], [1, 9, 3, 4, 5, 6]);
+
+        // Shrinking the buffer clamps the position
+        buffer.seek_to(6);
+        buffer.resize(3);
+        assert_eq!(buffer.position(), 3);
+    }
+}
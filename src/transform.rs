@@ -0,0 +1,129 @@
+use super::{ImageInfo, Transform};
+
+/// Apply `transform` to the image `src` and return the result.
+///
+/// If `transform` is `Transform::Identity`, this function returns `src` as-is.
+/// Otherwise, the transformed image is stored in `scratch`, which is resized as
+/// needed.
+pub fn apply<'a>(
+    transform: Transform,
+    src: &'a [u8],
+    src_info: &ImageInfo,
+    scratch: &'a mut Vec<u8>,
+) -> (&'a [u8], ImageInfo) {
+    if transform == Transform::Identity {
+        return (src, *src_info);
+    }
+
+    let extent = transform.transform_extent(src_info.extent);
+    let dst_info = ImageInfo {
+        extent,
+        stride: extent[0] as usize * src_info.format.bytes_per_pixel(),
+        ..*src_info
+    };
+
+    scratch.resize(dst_info.stride * extent[1] as usize, 0);
+    transform_image(transform, src, src_info, scratch, &dst_info);
+
+    (scratch, dst_info)
+}
+
+/// Apply `transform` to the image `src` and store the result to `dst`.
+/// `dst_info.extent` must be equal to `transform.transform_extent(src_info.extent)`.
+fn transform_image(
+    transform: Transform,
+    src: &[u8],
+    src_info: &ImageInfo,
+    dst: &mut [u8],
+    dst_info: &ImageInfo,
+) {
+    let bpp = src_info.format.bytes_per_pixel();
+    let [src_w, src_h] = [src_info.extent[0] as usize, src_info.extent[1] as usize];
+    let [dst_w, dst_h] = [dst_info.extent[0] as usize, dst_info.extent[1] as usize];
+    debug_assert_eq!(transform.transform_extent(src_info.extent), dst_info.extent);
+
+    // Find the source pixel for each destination pixel. Destination rows are
+    // written sequentially.
+    let src_pos = |x: usize, y: usize| -> (usize, usize) {
+        match transform {
+            Transform::Identity => (x, y),
+            Transform::Rotate90 => (y, src_h - 1 - x),
+            Transform::Rotate180 => (src_w - 1 - x, src_h - 1 - y),
+            Transform::Rotate270 => (src_w - 1 - y, x),
+            Transform::FlipHorizontal => (src_w - 1 - x, y),
+            Transform::FlipVertical => (x, src_h - 1 - y),
+        }
+    };
+
+    for y in 0..dst_h {
+        let dst_row = &mut dst[y * dst_info.stride..][..dst_w * bpp];
+        for (x, pixel) in dst_row.chunks_exact_mut(bpp).enumerate() {
+            let (sx, sy) = src_pos(x, y);
+            pixel.copy_from_slice(&src[sy * src_info.stride + sx * bpp..][..bpp]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Format;
+
+    fn run(transform: Transform) -> (Vec<u8>, ImageInfo) {
+        let src_info = ImageInfo {
+            extent: [3, 2],
+            stride: 16,
+            format: Format::Argb8888,
+            ..ImageInfo::default()
+        };
+        // Each pixel is filled with its index. The padding is filled with `99`.
+        #[rustfmt::skip]
+        let src: Vec<u8> = [
+            0, 1, 2, 99,
+            3, 4, 5, 99,
+        ]
+        .iter()
+        .flat_map(|&x| vec![x; 4])
+        .collect();
+
+        let mut scratch = Vec::new();
+        let (dst, dst_info) = apply(transform, &src, &src_info, &mut scratch);
+        let pixels = dst
+            .chunks(dst_info.stride)
+            .flat_map(|row| row[..dst_info.extent[0] as usize * 4].chunks(4))
+            .map(|p| p[0])
+            .collect();
+        (pixels, dst_info)
+    }
+
+    #[test]
+    fn identity() {
+        let (pixels, info) = run(Transform::Identity);
+        assert_eq!(info.extent, [3, 2]);
+        assert_eq!(pixels, vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn rotate() {
+        let (pixels, info) = run(Transform::Rotate90);
+        assert_eq!(info.extent, [2, 3]);
+        assert_eq!(pixels, vec![3, 0, 4, 1, 5, 2]);
+
+        let (pixels, info) = run(Transform::Rotate180);
+        assert_eq!(info.extent, [3, 2]);
+        assert_eq!(pixels, vec![5, 4, 3, 2, 1, 0]);
+
+        let (pixels, info) = run(Transform::Rotate270);
+        assert_eq!(info.extent, [2, 3]);
+        assert_eq!(pixels, vec![2, 5, 1, 4, 0, 3]);
+    }
+
+    #[test]
+    fn flip() {
+        let (pixels, _) = run(Transform::FlipHorizontal);
+        assert_eq!(pixels, vec![2, 1, 0, 5, 4, 3]);
+
+        let (pixels, _) = run(Transform::FlipVertical);
+        assert_eq!(pixels, vec![3, 4, 5, 0, 1, 2]);
+    }
+}
@@ -1,42 +1,147 @@
-//! Wayland/X11 backend
+//! Wayland/X11 backend (and the reference backend)
 use either::Either;
-use std::ops::{Deref, DerefMut};
-use winit::{platform::unix::*, window::Window};
+use raw_window_handle::{RawDisplayHandle, RawWindowHandle};
+use std::{
+    ops::DerefMut,
+    os::unix::io::{AsRawFd, OwnedFd},
+    time::Duration,
+};
+use wayland_client::protocol::wl_seat;
+use winit::{
+    event::Event,
+    platform::unix::*,
+    window::{Window, WindowId},
+};
 
-use super::{align::Align, Config, ContextBuilder, Format, ImageInfo};
+use super::{
+    align::Align, surface::ConfigField, AcquireResult, Backend, Config, ContextBuilder, Format,
+    ImageInfo, MergeStrategy, SurfaceError, Transform,
+};
 
+mod reference;
 mod wayland;
 mod x11;
 
-#[derive(Debug)]
+pub type WaylandSeatCb =
+    Box<dyn FnOnce(wayland_client::NewProxy<wl_seat::WlSeat>) -> wl_seat::WlSeat>;
+
+#[derive(Debug, Clone)]
 pub enum ContextImpl {
     Wayland(wayland::ContextImpl),
     X11,
+    Reference(reference::ContextImpl),
 }
 
 impl ContextImpl {
     pub const TAKES_READY_CB: bool = true;
 
     pub fn new<T: 'static>(builder: ContextBuilder<'_, T>) -> Self {
+        if reference::is_selected() {
+            return ContextImpl::Reference(reference::ContextImpl::new(builder));
+        }
+
+        if builder.wayland_from_env {
+            return ContextImpl::Wayland(unsafe { wayland::ContextImpl::new_from_env(builder) });
+        }
+
+        let wl_dpy = match (builder.event_loop, builder.display_handle) {
+            (Some(event_loop), _) => event_loop.wayland_display(),
+            (None, Some(RawDisplayHandle::Wayland(handle))) => Some(handle.display),
+            (None, Some(RawDisplayHandle::Xlib(_))) | (None, Some(RawDisplayHandle::Xcb(_))) => {
+                None
+            }
+            (None, handle) => panic!("unsupported display handle: {:?}", handle),
+        };
+
         unsafe {
-            match builder.event_loop.wayland_display() {
+            match wl_dpy {
                 Some(wl_dpy) => ContextImpl::Wayland(wayland::ContextImpl::new(wl_dpy, builder)),
                 None => ContextImpl::X11,
             }
         }
     }
+
+    pub fn backend(&self) -> Backend {
+        match self {
+            ContextImpl::Wayland(_) => Backend::Wayland,
+            ContextImpl::X11 => Backend::X11,
+            ContextImpl::Reference(_) => Backend::Other("reference"),
+        }
+    }
+
+    pub fn flush(&self) {
+        if let ContextImpl::Wayland(imp) = self {
+            imp.flush();
+        }
+    }
+
+    pub fn dispatch_pending(&self) -> usize {
+        match self {
+            ContextImpl::Wayland(imp) => imp.dispatch_pending(),
+            ContextImpl::X11 | ContextImpl::Reference(_) => 0,
+        }
+    }
+}
+
+/// A native subsurface created by `SurfaceImpl::create_subsurface`.
+#[derive(Debug)]
+pub struct SubSurfaceImpl {
+    // Fields are dropped in the declaration order, so `surface` is dropped
+    // before the `wl_surface` it's attached to
+    surface: SurfaceImpl,
+    _proxies: wayland::SubSurfaceProxies,
+}
+
+impl SubSurfaceImpl {
+    pub fn surface(&self) -> &SurfaceImpl {
+        &self.surface
+    }
 }
 
 #[derive(Debug)]
 pub enum SurfaceImpl {
     Wayland(wayland::SurfaceImpl),
     X11(x11::SurfaceImpl),
+    Reference(reference::SurfaceImpl),
+}
+
+/// Constructs the reference backend directly so that tests can exercise
+/// `Surface` without a window.
+#[cfg(test)]
+impl ContextImpl {
+    pub fn new_reference(ready_cb: super::surface::ReadyCb) -> Self {
+        ContextImpl::Reference(reference::ContextImpl::with_ready_cb(ready_cb))
+    }
+}
+
+#[cfg(test)]
+impl SurfaceImpl {
+    pub fn new_reference(wnd_id: WindowId, context: &ContextImpl, config: &Config) -> Self {
+        match context {
+            ContextImpl::Reference(context) => SurfaceImpl::Reference(reference::SurfaceImpl::new(
+                wnd_id,
+                context,
+                config,
+                Align::new(config.scanline_align).unwrap(),
+            )),
+            _ => panic!("backend mismatch"),
+        }
+    }
 }
 
 impl SurfaceImpl {
     pub(crate) unsafe fn new(window: &Window, context: &ContextImpl, config: &Config) -> Self {
         let scanline_align = Align::new(config.scanline_align).unwrap();
 
+        if let ContextImpl::Reference(context) = context {
+            return SurfaceImpl::Reference(reference::SurfaceImpl::new(
+                window.id(),
+                context,
+                config,
+                scanline_align,
+            ));
+        }
+
         match (
             window.wayland_display(),
             window.wayland_surface(),
@@ -52,10 +157,9 @@ impl SurfaceImpl {
                     config,
                     scanline_align,
                 )),
-                ContextImpl::X11 => panic!("backend mismatch"),
+                _ => panic!("backend mismatch"),
             },
             (None, None, Some(x_dpy), Some(x_wnd)) => match context {
-                ContextImpl::Wayland(_) => panic!("backend mismatch"),
                 ContextImpl::X11 => SurfaceImpl::X11(x11::SurfaceImpl::new(
                     x_dpy,
                     x_wnd,
@@ -63,22 +167,116 @@ impl SurfaceImpl {
                     config,
                     scanline_align,
                 )),
+                _ => panic!("backend mismatch"),
             },
             _ => unreachable!(),
         }
     }
 
+    pub(crate) unsafe fn from_raw_handles(
+        window: RawWindowHandle,
+        display: RawDisplayHandle,
+        context: &ContextImpl,
+        config: &Config,
+    ) -> Result<Self, SurfaceError> {
+        let scanline_align = Align::new(config.scanline_align).unwrap();
+
+        // There's no `winit` window to take an ID from
+        let wnd_id = WindowId::dummy();
+
+        match (context, window, display) {
+            (ContextImpl::Reference(context), _, _) => Ok(SurfaceImpl::Reference(
+                reference::SurfaceImpl::new(wnd_id, context, config, scanline_align),
+            )),
+            (
+                ContextImpl::Wayland(context),
+                RawWindowHandle::Wayland(window),
+                RawDisplayHandle::Wayland(display),
+            ) if !window.surface.is_null() => Ok(SurfaceImpl::Wayland(wayland::SurfaceImpl::new(
+                display.display,
+                window.surface,
+                wnd_id,
+                context,
+                config,
+                scanline_align,
+            ))),
+            (ContextImpl::X11, RawWindowHandle::Xlib(window), RawDisplayHandle::Xlib(display))
+                if !display.display.is_null() && window.window != 0 =>
+            {
+                Ok(SurfaceImpl::X11(x11::SurfaceImpl::new(
+                    display.display,
+                    window.window,
+                    wnd_id,
+                    config,
+                    scanline_align,
+                )))
+            }
+            _ => Err(SurfaceError::UnsupportedHandle),
+        }
+    }
+
     pub fn update_surface(&self, extent: [u32; 2], format: Format) {
         match self {
             SurfaceImpl::Wayland(imp) => imp.update_surface(extent, format),
             SurfaceImpl::X11(imp) => imp.update_surface(extent, format),
+            SurfaceImpl::Reference(imp) => imp.update_surface(extent, format),
+        }
+    }
+
+    pub fn reconfigure(&self, old: &Config, new: &Config) -> Vec<ConfigField> {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.reconfigure(old, new),
+            SurfaceImpl::X11(imp) => imp.reconfigure(old, new),
+            SurfaceImpl::Reference(imp) => imp.reconfigure(old, new),
+        }
+    }
+
+    pub fn resize_swapchain(&self, count: usize) -> Result<(), SurfaceError> {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.resize_swapchain(count),
+            SurfaceImpl::X11(imp) => imp.resize_swapchain(count),
+            SurfaceImpl::Reference(imp) => imp.resize_swapchain(count),
+        }
+    }
+
+    pub fn set_scanline_align(&self, scanline_align: Align) {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.set_scanline_align(scanline_align),
+            SurfaceImpl::X11(imp) => imp.set_scanline_align(scanline_align),
+            SurfaceImpl::Reference(imp) => imp.set_scanline_align(scanline_align),
+        }
+    }
+
+    pub fn refresh_rate(&self, window: &Window) -> Option<u32> {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.refresh_rate(window),
+            // Not implemented; XRandR could provide this
+            SurfaceImpl::X11(_) => None,
+            SurfaceImpl::Reference(_) => None,
+        }
+    }
+
+    pub fn default_scanline_align(&self) -> Align {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.default_scanline_align(),
+            SurfaceImpl::X11(imp) => imp.default_scanline_align(),
+            SurfaceImpl::Reference(imp) => imp.default_scanline_align(),
+        }
+    }
+
+    pub fn supports_transparency(&self) -> bool {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.supports_transparency(),
+            SurfaceImpl::X11(imp) => imp.supports_transparency(),
+            SurfaceImpl::Reference(imp) => imp.supports_transparency(),
         }
     }
 
     pub fn supported_formats(&self) -> impl Iterator<Item = Format> + '_ {
         match self {
-            SurfaceImpl::Wayland(imp) => Either::Left(imp.supported_formats()),
-            SurfaceImpl::X11(imp) => Either::Right(imp.supported_formats()),
+            SurfaceImpl::Wayland(imp) => Either::Left(Either::Left(imp.supported_formats())),
+            SurfaceImpl::X11(imp) => Either::Left(Either::Right(imp.supported_formats())),
+            SurfaceImpl::Reference(imp) => Either::Right(imp.supported_formats()),
         }
     }
 
@@ -86,6 +284,7 @@ impl SurfaceImpl {
         match self {
             SurfaceImpl::Wayland(imp) => imp.image_info(),
             SurfaceImpl::X11(imp) => imp.image_info(),
+            SurfaceImpl::Reference(imp) => imp.image_info(),
         }
     }
 
@@ -93,6 +292,7 @@ impl SurfaceImpl {
         match self {
             SurfaceImpl::Wayland(imp) => imp.num_images(),
             SurfaceImpl::X11(imp) => imp.num_images(),
+            SurfaceImpl::Reference(imp) => imp.num_images(),
         }
     }
 
@@ -100,6 +300,23 @@ impl SurfaceImpl {
         match self {
             SurfaceImpl::Wayland(imp) => imp.does_preserve_image(),
             SurfaceImpl::X11(imp) => imp.does_preserve_image(),
+            SurfaceImpl::Reference(imp) => imp.does_preserve_image(),
+        }
+    }
+
+    pub fn is_presenting(&self, i: usize) -> bool {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.is_presenting(i),
+            SurfaceImpl::X11(imp) => imp.is_presenting(i),
+            SurfaceImpl::Reference(imp) => imp.is_presenting(i),
+        }
+    }
+
+    pub fn is_locked(&self, i: usize) -> bool {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.is_locked(i),
+            SurfaceImpl::X11(imp) => imp.is_locked(i),
+            SurfaceImpl::Reference(imp) => imp.is_locked(i),
         }
     }
 
@@ -107,13 +324,63 @@ impl SurfaceImpl {
         match self {
             SurfaceImpl::Wayland(imp) => imp.poll_next_image(),
             SurfaceImpl::X11(imp) => imp.poll_next_image(),
+            SurfaceImpl::Reference(imp) => imp.poll_next_image(),
+        }
+    }
+
+    pub fn enable_ready_cb(&self) {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.enable_ready_cb(),
+            SurfaceImpl::X11(imp) => imp.enable_ready_cb(),
+            SurfaceImpl::Reference(imp) => imp.enable_ready_cb(),
         }
     }
 
-    pub fn lock_image(&self, i: usize) -> impl Deref<Target = [u8]> + DerefMut + '_ {
+    pub fn is_ready_cb_scheduled(&self) -> bool {
         match self {
-            SurfaceImpl::Wayland(imp) => Either::Left(imp.lock_image(i)),
-            SurfaceImpl::X11(imp) => Either::Right(imp.lock_image(i)),
+            SurfaceImpl::Wayland(imp) => imp.is_ready_cb_scheduled(),
+            SurfaceImpl::X11(imp) => imp.is_ready_cb_scheduled(),
+            SurfaceImpl::Reference(imp) => imp.is_ready_cb_scheduled(),
+        }
+    }
+
+    pub fn acquire_with_timeout(&self, timeout: Duration) -> AcquireResult {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.acquire_with_timeout(timeout),
+            SurfaceImpl::X11(imp) => imp.acquire_with_timeout(timeout),
+            SurfaceImpl::Reference(imp) => imp.acquire_with_timeout(timeout),
+        }
+    }
+
+    pub fn handle_os_event<T>(&self, event: &Event<T>) {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.handle_os_event(event),
+            SurfaceImpl::X11(imp) => imp.handle_os_event(event),
+            SurfaceImpl::Reference(imp) => imp.handle_os_event(event),
+        }
+    }
+
+    pub fn detach(&self) {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.detach(),
+            SurfaceImpl::X11(imp) => imp.detach(),
+            SurfaceImpl::Reference(imp) => imp.detach(),
+        }
+    }
+
+    pub fn wait_all_released(&self, timeout: Duration) -> bool {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.wait_all_released(timeout),
+            SurfaceImpl::X11(imp) => imp.wait_all_released(timeout),
+            SurfaceImpl::Reference(imp) => imp.wait_all_released(timeout),
+        }
+    }
+
+    pub fn lock_image(&self, i: usize) -> impl DerefMut<Target = [u8]> + '_ {
+        match self {
+            SurfaceImpl::Wayland(imp) => Either::Left(Either::Left(imp.lock_image(i))),
+            SurfaceImpl::X11(imp) => Either::Left(Either::Right(imp.lock_image(i))),
+            SurfaceImpl::Reference(imp) => Either::Right(imp.lock_image(i)),
         }
     }
 
@@ -121,6 +388,163 @@ impl SurfaceImpl {
         match self {
             SurfaceImpl::Wayland(imp) => imp.present_image(i),
             SurfaceImpl::X11(imp) => imp.present_image(i),
+            SurfaceImpl::Reference(imp) => imp.present_image(i),
+        }
+    }
+
+    pub fn present_image_damaged(&self, i: usize, damage: &[[u32; 4]]) {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.present_image_damaged(i, damage),
+            SurfaceImpl::X11(imp) => imp.present_image_damaged(i, damage),
+            SurfaceImpl::Reference(imp) => imp.present_image_damaged(i, damage),
+        }
+    }
+
+    pub fn present_image_immediately(&self, i: usize) {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.present_image_immediately(i),
+            SurfaceImpl::X11(imp) => imp.present_image_immediately(i),
+            SurfaceImpl::Reference(imp) => imp.present_image(i),
+        }
+    }
+
+    pub fn present_image_at(&self, i: usize, offset: [i32; 2]) {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.present_image_at(i, offset),
+            SurfaceImpl::X11(imp) => imp.present_image_at(i, offset),
+            SurfaceImpl::Reference(imp) => imp.present_image(i),
+        }
+    }
+
+    pub fn present_image_uncommitted(&self, i: usize) {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.present_image_uncommitted(i),
+            SurfaceImpl::X11(imp) => imp.present_image(i),
+            SurfaceImpl::Reference(imp) => imp.present_image(i),
+        }
+    }
+
+    pub fn commit_present(&self) {
+        if let SurfaceImpl::Wayland(imp) = self {
+            imp.commit();
+        }
+    }
+
+    pub fn try_present_image(&self, i: usize) -> Result<(), SurfaceError> {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.try_present_image(i),
+            SurfaceImpl::X11(imp) => imp.try_present_image(i),
+            SurfaceImpl::Reference(imp) => imp.try_present_image(i),
+        }
+    }
+
+    pub fn present_region(&self, i: usize, src_rect: [u32; 4], dst_pos: [u32; 2]) {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.present_region(i, src_rect, dst_pos),
+            SurfaceImpl::X11(imp) => imp.present_region(i, src_rect, dst_pos),
+            SurfaceImpl::Reference(imp) => imp.present_region(i, src_rect, dst_pos),
+        }
+    }
+
+    pub fn copy_image(&self, src_i: usize, dst_i: usize) {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.copy_image(src_i, dst_i),
+            SurfaceImpl::X11(imp) => imp.copy_image(src_i, dst_i),
+            SurfaceImpl::Reference(imp) => imp.copy_image(src_i, dst_i),
+        }
+    }
+
+    pub fn set_transform(&self, transform: Transform) {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.set_transform(transform),
+            SurfaceImpl::X11(imp) => imp.set_transform(transform),
+            SurfaceImpl::Reference(imp) => imp.set_transform(transform),
+        }
+    }
+
+    pub fn transform(&self) -> Transform {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.transform(),
+            SurfaceImpl::X11(imp) => imp.transform(),
+            SurfaceImpl::Reference(imp) => imp.transform(),
+        }
+    }
+
+    pub fn set_blur_behind(&self, _enabled: bool) {
+        // TODO: Use `org_kde_kwin_blur` on Wayland
+    }
+
+    pub fn is_content_protection_active(&self) -> bool {
+        // Neither X11 nor Wayland provides a way to do this
+        false
+    }
+
+    #[cfg(feature = "screen-capture")]
+    pub fn screen_capture(&self, extent: [u32; 2]) -> Option<Vec<u8>> {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.screen_capture(extent),
+            SurfaceImpl::X11(imp) => imp.screen_capture(extent),
+            SurfaceImpl::Reference(imp) => imp.screen_capture(extent),
+        }
+    }
+
+    pub fn set_damage_merge_strategy(&self, strategy: MergeStrategy) {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.set_damage_merge_strategy(strategy),
+            SurfaceImpl::X11(imp) => imp.set_damage_merge_strategy(strategy),
+            SurfaceImpl::Reference(_) => {}
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    pub fn set_debug_label(&self, label: &str) {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.set_debug_label(label),
+            SurfaceImpl::X11(imp) => imp.set_debug_label(label),
+            SurfaceImpl::Reference(imp) => imp.set_debug_label(label),
+        }
+    }
+
+    pub fn create_subsurface(&self, rect: [u32; 4], config: &Config) -> Option<SubSurfaceImpl> {
+        match self {
+            SurfaceImpl::Wayland(imp) => {
+                let (surface, proxies) = imp.create_subsurface(rect, config)?;
+                Some(SubSurfaceImpl {
+                    surface: SurfaceImpl::Wayland(surface),
+                    _proxies: proxies,
+                })
+            }
+            SurfaceImpl::X11(_) | SurfaceImpl::Reference(_) => None,
+        }
+    }
+
+    pub fn set_input_region(&self, region: Option<&[[u32; 4]]>) {
+        match self {
+            SurfaceImpl::Wayland(imp) => imp.set_input_region(region),
+            SurfaceImpl::X11(imp) => imp.set_input_region(region),
+            SurfaceImpl::Reference(_) => {}
+        }
+    }
+}
+
+/// Block the current thread until the sync file `fd` is signaled, and then
+/// close `fd`.
+pub fn wait_and_close_fence(fd: OwnedFd) {
+    let mut pollfd = libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    loop {
+        // A sync file becomes readable when the fence is signaled
+        if unsafe { libc::poll(&mut pollfd, 1, -1) } >= 0 {
+            break;
+        }
+        let e = std::io::Error::last_os_error();
+        if e.kind() != std::io::ErrorKind::Interrupted {
+            log::warn!("Could not wait for the fence: {}", e);
+            break;
         }
     }
+    drop(fd);
 }
@@ -0,0 +1,664 @@
+//! Reference backend
+//!
+//! This backend doesn't display anything. It emulates the multi-buffered
+//! behavior of the Wayland backend using in-memory buffers and checks the
+//! usage of the API as strictly as possible. This is useful for reproducing
+//! backend-agnostic bugs and for running the state machine under Miri.
+//!
+//! This backend is selected by setting the environment variable
+//! `SWSURFACE_BACKEND` to `reference`.
+use log::trace;
+use owning_ref::{OwningHandle, OwningRefMut};
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+    ops::DerefMut,
+    rc::Rc,
+    time::Duration,
+};
+use winit::{event::Event, window::WindowId};
+
+use super::super::{
+    align::Align,
+    buffer::Buffer,
+    surface::{assert_initialized, ConfigField, ReadyCb},
+    AcquireResult, Config, ContextBuilder, Format, ImageInfo, SurfaceError, Transform,
+};
+
+/// Check if the reference backend is requested by the environment variable.
+pub fn is_selected() -> bool {
+    std::env::var_os("SWSURFACE_BACKEND").is_some_and(|x| x == "reference")
+}
+
+#[derive(Clone)]
+pub struct ContextImpl {
+    ready_cb: Rc<ReadyCb>,
+}
+
+impl fmt::Debug for ContextImpl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ContextImpl").finish()
+    }
+}
+
+impl ContextImpl {
+    pub fn new<T: 'static>(builder: ContextBuilder<'_, T>) -> Self {
+        Self::with_ready_cb(builder.ready_cb)
+    }
+
+    pub fn with_ready_cb(ready_cb: ReadyCb) -> Self {
+        Self {
+            ready_cb: Rc::new(ready_cb),
+        }
+    }
+}
+
+pub struct SurfaceImpl {
+    ctx: ContextImpl,
+    wnd_id: WindowId,
+
+    /// Each image is reference-counted so that `lock_image` guards can keep
+    /// it alive without borrowing the list, which `resize_swapchain` modifies.
+    images: RefCell<Vec<Rc<RefCell<Buffer>>>>,
+    /// [`Config::align`]
+    align: usize,
+    /// [`Config::no_clear_on_resize`]
+    no_clear_on_resize: Cell<bool>,
+
+    /// The index of the image being used by the imaginary compositor. Like
+    /// the Wayland backend, presenting an image causes the previously
+    /// presented image to be released.
+    presenting: Cell<Option<usize>>,
+
+    /// If `true`, `ready_cb` will be called when an image is released for the
+    /// next time.
+    enable_ready_cb: Cell<bool>,
+
+    /// `true` if `update_surface` has been called at least once.
+    initialized: Cell<bool>,
+
+    image_info: Cell<ImageInfo>,
+    scanline_align: Cell<Align>,
+    transform: Cell<Transform>,
+}
+
+impl fmt::Debug for SurfaceImpl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SurfaceImpl")
+            .field("wnd_id", &self.wnd_id)
+            .field("presenting", &self.presenting)
+            .field("enable_ready_cb", &self.enable_ready_cb)
+            .field("image_info", &self.image_info)
+            .finish()
+    }
+}
+
+impl SurfaceImpl {
+    pub fn new(
+        wnd_id: WindowId,
+        context: &ContextImpl,
+        config: &Config,
+        scanline_align: Align,
+    ) -> Self {
+        assert_ne!(config.image_count, 0);
+
+        let images: Vec<_> = (0..config.image_count)
+            .map(|_| {
+                Rc::new(RefCell::new(
+                    Buffer::from_size_align(1, config.align).unwrap(),
+                ))
+            })
+            .collect();
+
+        Self {
+            ctx: context.clone(),
+            wnd_id,
+            images: RefCell::new(images),
+            align: config.align,
+            no_clear_on_resize: Cell::new(config.no_clear_on_resize),
+            presenting: Cell::new(None),
+            enable_ready_cb: Cell::new(false),
+            initialized: Cell::new(false),
+            image_info: Cell::new(ImageInfo {
+                color_space: config.color_space,
+                ..ImageInfo::default()
+            }),
+            scanline_align: Cell::new(scanline_align),
+            transform: Cell::new(Transform::Identity),
+        }
+    }
+
+    pub fn update_surface(&self, extent: [u32; 2], format: Format) {
+        assert_ne!(extent[0], 0);
+        assert_ne!(extent[1], 0);
+        assert!(
+            self.supported_formats().any(|f| f == format),
+            "unsupported format"
+        );
+
+        // Fail-fast if some images are locked by the appliction
+        let images = self.images.borrow();
+        let mut images: Vec<_> = images
+            .iter()
+            .map(|image| image.try_borrow_mut().expect("some images are locked"))
+            .collect();
+
+        // Use the same limits as the Wayland backend
+        assert!(extent[0] <= <i32>::MAX as u32);
+        assert!(extent[1] <= <i32>::MAX as u32);
+
+        let stride = (extent[0] as usize)
+            .checked_mul(4)
+            .and_then(|x| self.scanline_align.get().align_up(x))
+            .filter(|&x| x <= <i32>::MAX as usize)
+            .expect("overflow");
+
+        let size = stride.checked_mul(extent[1] as usize).expect("overflow");
+
+        let image_info = ImageInfo {
+            extent,
+            stride,
+            format,
+            ..self.image_info.get()
+        };
+
+        trace!("{:?}: New image info = {:?}", self.wnd_id, image_info);
+
+        for (i, image) in images.iter_mut().enumerate() {
+            // Like the Wayland backend, defer the resize of the image in use
+            // by the compositor until it's accessed again
+            if self.presenting.get() != Some(i) {
+                self.resize_image(image, size);
+            }
+        }
+
+        self.image_info.set(image_info);
+        self.initialized.set(true);
+    }
+
+    pub fn reconfigure(&self, old: &Config, new: &Config) -> Vec<ConfigField> {
+        let mut unsupported = Vec::new();
+
+        // `image_count` is applied by `resize_swapchain`
+        if new.align != old.align {
+            unsupported.push(ConfigField::Align);
+        }
+
+        self.no_clear_on_resize.set(new.no_clear_on_resize);
+
+        // `color_space` is merely a tag
+        self.image_info.set(ImageInfo {
+            color_space: new.color_space,
+            ..self.image_info.get()
+        });
+
+        unsupported
+    }
+
+    pub fn resize_swapchain(&self, count: usize) -> Result<(), SurfaceError> {
+        assert_ne!(count, 0);
+
+        let mut images = self.images.borrow_mut();
+
+        // The removed images must be idle
+        if let Some(i) = (count..images.len())
+            .find(|&i| self.presenting.get() == Some(i) || images[i].try_borrow_mut().is_err())
+        {
+            return Err(SurfaceError::ImageInUse(i));
+        }
+
+        trace!(
+            "{:?}: Resizing the swapchain from {} to {} images",
+            self.wnd_id,
+            images.len(),
+            count
+        );
+
+        images.truncate(count);
+
+        // The new images are resized when they are accessed for the first time
+        // (see `ensure_image_size`)
+        while images.len() < count {
+            let image = Buffer::from_size_align(1, self.align).unwrap();
+            images.push(Rc::new(RefCell::new(image)));
+        }
+
+        Ok(())
+    }
+
+    pub fn set_scanline_align(&self, scanline_align: Align) {
+        self.scanline_align.set(scanline_align);
+    }
+
+    pub fn supports_transparency(&self) -> bool {
+        true
+    }
+
+    pub fn default_scanline_align(&self) -> Align {
+        // Any stride works
+        Align::new(4).unwrap()
+    }
+
+    pub fn supported_formats(&self) -> impl Iterator<Item = Format> + '_ {
+        [Format::Argb8888].iter().cloned()
+    }
+
+    pub fn image_info(&self) -> ImageInfo {
+        self.image_info.get()
+    }
+
+    pub fn num_images(&self) -> usize {
+        self.images.borrow().len()
+    }
+
+    pub fn does_preserve_image(&self) -> bool {
+        true
+    }
+
+    pub fn is_presenting(&self, i: usize) -> bool {
+        assert!(i < self.num_images(), "image index out of range");
+        self.presenting.get() == Some(i)
+    }
+
+    pub fn is_locked(&self, i: usize) -> bool {
+        assert!(i < self.num_images(), "image index out of range");
+        self.images.borrow()[i].try_borrow_mut().is_err()
+    }
+
+    pub fn poll_next_image(&self) -> Option<usize> {
+        if !self.initialized.get() {
+            return None;
+        }
+
+        let presenting = self.presenting.get();
+        let result = (0..self.num_images()).find(|&i| Some(i) != presenting);
+
+        if let Some(i) = result {
+            trace!("{:?}: Swapchain image {} is available", self.wnd_id, i);
+        } else {
+            trace!("{:?}: No swapchain image is available", self.wnd_id);
+
+            // Enable the ready callback. The compositor releases the image
+            // immediately, so call it right away.
+            self.enable_ready_cb.set(true);
+            self.release();
+        }
+
+        result
+    }
+
+    pub fn enable_ready_cb(&self) {
+        // The compositor releases the image immediately
+        self.enable_ready_cb.set(true);
+        self.release();
+    }
+
+    pub fn is_ready_cb_scheduled(&self) -> bool {
+        self.enable_ready_cb.get()
+    }
+
+    pub fn acquire_with_timeout(&self, _timeout: Duration) -> AcquireResult {
+        // The second call always succeeds because images are released
+        // immediately
+        match self.poll_next_image().or_else(|| self.poll_next_image()) {
+            Some(i) => AcquireResult::Ready(i),
+            None => unreachable!(),
+        }
+    }
+
+    pub fn handle_os_event<T>(&self, _event: &Event<T>) {}
+
+    pub fn detach(&self) {}
+
+    pub fn wait_all_released(&self, _timeout: Duration) -> bool {
+        // The compositor releases images immediately
+        self.release();
+        true
+    }
+
+    /// Emulate the `release` event of the image being used by the compositor.
+    fn release(&self) {
+        if let Some(i) = self.presenting.take() {
+            trace!("{:?}: Swapchain image {} was released", self.wnd_id, i);
+
+            if self.enable_ready_cb.replace(false) {
+                trace!("Calling `ready_cb`");
+                (self.ctx.ready_cb)(self.wnd_id);
+            }
+        }
+    }
+
+    fn check_image_access(&self, i: usize) {
+        assert_initialized(self.initialized.get(), None);
+        assert!(
+            !self.is_presenting(i),
+            "the image is currently in use by the compositor"
+        );
+    }
+
+    pub fn lock_image(&self, i: usize) -> impl DerefMut<Target = [u8]> + '_ {
+        self.check_image_access(i);
+
+        let image = Rc::clone(&self.images.borrow()[i]);
+
+        // Safety: `image` outlives the `RefMut` because both are owned by the
+        // `OwningHandle`
+        let image = OwningHandle::new_with_fn(image, |image| unsafe {
+            (*image)
+                .try_borrow_mut()
+                .expect("the image is already locked")
+        });
+
+        OwningRefMut::new(image).map_mut(|image| {
+            self.ensure_image_size(image);
+            &mut **image
+        })
+    }
+
+    /// Apply the resize deferred by `update_surface`.
+    fn ensure_image_size(&self, image: &mut Buffer) {
+        let image_info = self.image_info.get();
+        let size = image_info.stride * image_info.extent[1] as usize;
+        if image.len() != size {
+            self.resize_image(image, size);
+        }
+    }
+
+    fn resize_image(&self, image: &mut Buffer, size: usize) {
+        if self.no_clear_on_resize.get() {
+            image.resize_no_clear(size);
+        } else {
+            image.resize(size);
+        }
+    }
+
+    pub fn copy_image(&self, src_i: usize, dst_i: usize) {
+        self.check_image_access(src_i);
+        self.check_image_access(dst_i);
+
+        let images = self.images.borrow();
+        let mut src = images[src_i].try_borrow_mut().expect("the image is locked");
+        self.ensure_image_size(&mut src);
+
+        if src_i == dst_i {
+            return;
+        }
+
+        let mut dst = images[dst_i].try_borrow_mut().expect("the image is locked");
+        self.ensure_image_size(&mut dst);
+
+        trace!(
+            "{:?}: Copying swapchain image {} to {}",
+            self.wnd_id,
+            src_i,
+            dst_i
+        );
+
+        dst.copy_from_slice(&src);
+    }
+
+    pub fn present_image(&self, i: usize) {
+        self.check_image_access(i);
+
+        {
+            let images = self.images.borrow();
+            let mut image = images[i].try_borrow_mut().expect("the image is locked");
+            self.ensure_image_size(&mut image);
+        }
+
+        trace!("{:?}: Presenting swapchain image {}", self.wnd_id, i);
+
+        // Attaching a new buffer releases the old one
+        self.release();
+        self.presenting.set(Some(i));
+    }
+
+    pub fn try_present_image(&self, i: usize) -> Result<(), SurfaceError> {
+        self.present_image(i);
+        Ok(())
+    }
+
+    pub fn present_image_damaged(&self, i: usize, _damage: &[[u32; 4]]) {
+        self.present_image(i);
+    }
+
+    pub fn present_region(&self, i: usize, _src_rect: [u32; 4], _dst_pos: [u32; 2]) {
+        self.present_image(i);
+    }
+
+    /// Return the contents of the image being used by the imaginary
+    /// compositor.
+    #[cfg(feature = "screen-capture")]
+    pub fn screen_capture(&self, extent: [u32; 2]) -> Option<Vec<u8>> {
+        let [width, height] = extent;
+        let mut out = vec![0u8; width as usize * height as usize * 4];
+
+        if let Some(i) = self.presenting.get() {
+            let image_info = self.image_info.get();
+            let images = self.images.borrow();
+            let image = images[i].borrow();
+            let cap_width = std::cmp::min(width, image_info.extent[0]) as usize;
+            // The image may be smaller if it was being presented when
+            // `update_surface` was called
+            let cap_height = std::cmp::min(height, image_info.extent[1]) as usize;
+            let cap_height = std::cmp::min(cap_height, image.len() / image_info.stride);
+            for y in 0..cap_height {
+                out[y * width as usize * 4..][..cap_width * 4]
+                    .copy_from_slice(&image[y * image_info.stride..][..cap_width * 4]);
+            }
+        }
+
+        Some(out)
+    }
+
+    pub fn set_transform(&self, transform: Transform) {
+        self.transform.set(transform);
+    }
+
+    pub fn transform(&self) -> Transform {
+        self.transform.get()
+    }
+
+    #[cfg(debug_assertions)]
+    pub fn set_debug_label(&self, _label: &str) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ColorSpace;
+
+    fn new_surface(image_count: usize, ready_cb: ReadyCb) -> SurfaceImpl {
+        let context = ContextImpl::with_ready_cb(ready_cb);
+        let config = Config {
+            image_count,
+            ..Config::default()
+        };
+        let wnd_id = unsafe { WindowId::dummy() };
+        let surface = SurfaceImpl::new(wnd_id, &context, &config, Align::new(4).unwrap());
+        surface.update_surface([3, 2], Format::Argb8888);
+        surface
+    }
+
+    #[test]
+    fn double_buffered() {
+        let surface = new_surface(2, Box::new(|_| {}));
+        assert_eq!(surface.image_info().stride, 12);
+
+        assert_eq!(surface.poll_next_image(), Some(0));
+        surface.lock_image(0)[0] = 42;
+        surface.present_image(0);
+        assert!(surface.is_presenting(0));
+
+        assert_eq!(surface.poll_next_image(), Some(1));
+        surface.copy_image(1, 1);
+        surface.present_image(1);
+        assert!(!surface.is_presenting(0));
+
+        // The contents are preserved
+        assert_eq!(surface.poll_next_image(), Some(0));
+        assert_eq!(surface.lock_image(0)[0], 42);
+    }
+
+    #[test]
+    fn single_buffered_ready_cb() {
+        let num_calls = Rc::new(Cell::new(0));
+        let surface = new_surface(1, {
+            let num_calls = Rc::clone(&num_calls);
+            Box::new(move |_| num_calls.set(num_calls.get() + 1))
+        });
+
+        surface.present_image(0);
+        assert_eq!(surface.poll_next_image(), None);
+        assert_eq!(num_calls.get(), 1);
+        // The callback was called, so it isn't scheduled anymore
+        assert!(!surface.is_ready_cb_scheduled());
+        assert_eq!(
+            surface.acquire_with_timeout(Duration::from_secs(0)),
+            AcquireResult::Ready(0)
+        );
+    }
+
+    #[test]
+    fn resize_while_presenting() {
+        let surface = new_surface(2, Box::new(|_| {}));
+        surface.lock_image(1)[0] = 42;
+        surface.present_image(0);
+        surface.present_image(1);
+        assert!(surface.is_presenting(1));
+
+        surface.update_surface([8, 4], Format::Argb8888);
+        let size = surface.image_info().stride * 4;
+
+        // The image in use by the compositor is left untouched
+        assert_eq!(surface.images.borrow()[1].borrow()[0], 42);
+
+        assert_eq!(surface.poll_next_image(), Some(0));
+        assert_eq!(surface.lock_image(0).len(), size);
+        surface.present_image(0);
+
+        assert_eq!(surface.poll_next_image(), Some(1));
+        assert_eq!(surface.lock_image(1).len(), size);
+        surface.present_image(1);
+    }
+
+    #[test]
+    fn reconfigure() {
+        let surface = new_surface(2, Box::new(|_| {}));
+        let old = Config {
+            image_count: 2,
+            ..Config::default()
+        };
+        let new = Config {
+            image_count: 3,
+            align: old.align * 2,
+            vsync: !old.vsync,
+            color_space: ColorSpace::DisplayP3,
+            ..old
+        };
+
+        assert_eq!(surface.reconfigure(&old, &new), vec![ConfigField::Align]);
+        assert_eq!(surface.image_info().color_space, ColorSpace::DisplayP3);
+    }
+
+    #[test]
+    fn resize_swapchain() {
+        let surface = new_surface(2, Box::new(|_| {}));
+        surface.lock_image(1)[0] = 42;
+
+        surface.resize_swapchain(3).unwrap();
+        assert_eq!(surface.num_images(), 3);
+        assert_eq!(surface.lock_image(1)[0], 42);
+        assert_eq!(surface.lock_image(2).len(), surface.image_info().stride * 2);
+
+        // The remaining images may be locked
+        let mut image = surface.lock_image(0);
+        surface.resize_swapchain(1).unwrap();
+        image[0] = 1;
+        drop(image);
+        assert_eq!(surface.num_images(), 1);
+        assert_eq!(surface.poll_next_image(), Some(0));
+    }
+
+    #[test]
+    fn shrink_swapchain_in_use() {
+        let surface = new_surface(3, Box::new(|_| {}));
+
+        surface.present_image(2);
+        let _image = surface.lock_image(1);
+
+        // Neither the locked nor the presented image can be removed
+        assert_eq!(
+            surface.resize_swapchain(1),
+            Err(SurfaceError::ImageInUse(1))
+        );
+        assert_eq!(
+            surface.resize_swapchain(2),
+            Err(SurfaceError::ImageInUse(2))
+        );
+        assert_eq!(surface.num_images(), 3);
+
+        surface.resize_swapchain(3).unwrap();
+    }
+
+    #[test]
+    fn image_state() {
+        let surface = new_surface(2, Box::new(|_| {}));
+
+        let image = surface.lock_image(0);
+        assert!(surface.is_locked(0));
+        assert!(!surface.is_locked(1));
+        drop(image);
+        assert!(!surface.is_locked(0));
+
+        surface.present_image(0);
+        assert!(surface.is_presenting(0));
+        assert!(!surface.is_locked(0));
+
+        assert!(surface.wait_all_released(Duration::from_secs(0)));
+        assert!(!surface.is_presenting(0));
+    }
+
+    #[test]
+    #[cfg(feature = "screen-capture")]
+    fn screen_capture() {
+        let surface = new_surface(2, Box::new(|_| {}));
+        assert_eq!(surface.screen_capture([2, 1]), Some(vec![0; 8]));
+
+        surface.lock_image(0)[..8].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        surface.present_image(0);
+        assert_eq!(
+            surface.screen_capture([2, 1]),
+            Some(vec![1, 2, 3, 4, 5, 6, 7, 8])
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "in use by the compositor")]
+    fn lock_presenting_image() {
+        let surface = new_surface(2, Box::new(|_| {}));
+        surface.present_image(0);
+        surface.lock_image(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "some images are locked")]
+    fn update_while_locked() {
+        let surface = new_surface(2, Box::new(|_| {}));
+        let _image = surface.lock_image(1);
+        surface.update_surface([4, 4], Format::Argb8888);
+    }
+
+    #[test]
+    #[should_panic(expected = "call `update_surface` first")]
+    fn lock_uninitialized() {
+        let context = ContextImpl::with_ready_cb(Box::new(|_| panic!()));
+        let wnd_id = unsafe { WindowId::dummy() };
+        let surface =
+            SurfaceImpl::new(wnd_id, &context, &Config::default(), Align::new(4).unwrap());
+
+        // No image is available, and the ready callback isn't scheduled
+        assert_eq!(surface.poll_next_image(), None);
+        surface.lock_image(0);
+    }
+}
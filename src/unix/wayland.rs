@@ -1,22 +1,39 @@
 use fragile::Fragile;
-use log::trace;
-use owning_ref::OwningRefMut;
+use log::{debug, trace, warn};
+use owning_ref::{OwningHandle, OwningRefMut};
 use smithay_client_toolkit::utils::MemPool;
 use std::{
     cell::{Cell, RefCell},
-    fmt,
-    ops::{Deref, DerefMut},
+    cmp, fmt,
+    ops::DerefMut,
     os::raw::c_void,
     rc::Rc,
+    time::{Duration, Instant},
 };
 use wayland_client::{
     self as wl,
-    protocol::{wl_buffer, wl_display, wl_shm, wl_surface},
+    protocol::{
+        wl_buffer, wl_compositor, wl_display, wl_output, wl_region, wl_registry, wl_seat, wl_shm,
+        wl_subcompositor, wl_subsurface, wl_surface,
+    },
 };
+use wayland_protocols::unstable::linux_dmabuf::v1::client::zwp_linux_dmabuf_v1;
 use wayland_sys::{client::WAYLAND_CLIENT_HANDLE, ffi_dispatch};
-use winit::window::WindowId;
+use winit::{
+    event::Event,
+    window::{Window, WindowId},
+};
+
+use super::super::{
+    align::Align,
+    merge_rects,
+    surface::{ConfigField, ErrorHandler, ReadyCb},
+    AcquireResult, Config, ContextBuilder, Format, ImageInfo, MergeStrategy, SurfaceError,
+    Transform,
+};
 
-use super::super::{align::Align, Config, ContextBuilder, Format, ImageInfo, ReadyCb};
+/// `DRM_FORMAT_MOD_LINEAR` from `<drm_fourcc.h>`
+const DRM_FORMAT_MOD_LINEAR: u32 = 0;
 
 #[derive(Clone)]
 pub struct ContextImpl {
@@ -24,10 +41,104 @@ pub struct ContextImpl {
     // `winit::event_loop::EventLoop`'s underlying `wl::Display` object. They
     // are valid as long as the `winit::event_loop_EventLoop` or
     // at least one instance of `winit::window::Window` created from it are
-    // alive.
+    // alive. (If the context was created by `with_display_handle`, the
+    // application is responsible for keeping the display alive. If it was
+    // created by `new_from_env`, `_ownership` keeps it alive.)
     wl_dpy: wl_display::WlDisplay,
     wl_shm: wl_shm::WlShm,
+    /// The formats advertised by `wl_shm`
+    shm_formats: Rc<RefCell<Vec<wl_shm::Format>>>,
+    wl_compositor: wl_compositor::WlCompositor,
+    wl_subcompositor: Option<wl_subcompositor::WlSubcompositor>,
+    /// The `wl_output` globals, updated as outputs are added or removed
+    outputs: Rc<RefCell<Vec<OutputInfo>>>,
     ready_cb: Rc<ReadyCb>,
+    error_handler: Rc<ErrorHandler>,
+    /// The target of log messages, specified by
+    /// `ContextBuilder::with_log_target`
+    log_target: &'static str,
+    // This must be dropped after the proxies above
+    _ownership: Rc<DisplayOwnership>,
+}
+
+/// A `wl_output` global tracked by `ContextImpl`.
+#[derive(Debug, Clone, Copy)]
+struct OutputInfo {
+    /// The global name
+    name: u32,
+    /// The refresh rate of the current mode, measured in mHz. `0` if unknown.
+    refresh: i32,
+}
+
+/// Start or stop tracking a `wl_output` global in response to a
+/// `GlobalManager` event.
+fn track_output(
+    outputs: &Rc<RefCell<Vec<OutputInfo>>>,
+    event: wl::GlobalEvent,
+    registry: wl_registry::WlRegistry,
+) {
+    match event {
+        wl::GlobalEvent::New {
+            id,
+            interface,
+            version,
+        } if interface == "wl_output" => {
+            outputs.borrow_mut().push(OutputInfo {
+                name: id,
+                refresh: 0,
+            });
+
+            let outputs = Rc::clone(outputs);
+            let _ = registry.bind(
+                cmp::min(version, 2),
+                id,
+                |wl_output: wl::NewProxy<wl_output::WlOutput>| {
+                    wl_output.implement_closure(
+                        move |evt, _| {
+                            // `wl_output` sends the current mode via `mode`
+                            // events when bound and whenever it changes
+                            if let wl_output::Event::Mode { flags, refresh, .. } = evt {
+                                if flags.contains(wl_output::Mode::Current) {
+                                    let mut outputs = outputs.borrow_mut();
+                                    for output in outputs.iter_mut().filter(|o| o.name == id) {
+                                        output.refresh = refresh;
+                                    }
+                                }
+                            }
+                        },
+                        (),
+                    )
+                },
+            );
+        }
+        wl::GlobalEvent::Removed { id, interface } if interface == "wl_output" => {
+            outputs.borrow_mut().retain(|o| o.name != id);
+        }
+        _ => {}
+    }
+}
+
+/// Indicates whether `ContextImpl` owns the display connection.
+enum DisplayOwnership {
+    /// The connection is owned by `winit` or the application.
+    Borrowed,
+    /// The connection was opened by `ContextImpl::new_from_env` and is
+    /// closed when dropped.
+    Owned(*mut c_void),
+}
+
+impl Drop for DisplayOwnership {
+    fn drop(&mut self) {
+        if let DisplayOwnership::Owned(wl_dpy_ptr) = *self {
+            unsafe {
+                ffi_dispatch!(
+                    WAYLAND_CLIENT_HANDLE,
+                    wl_display_disconnect,
+                    wl_dpy_ptr as _
+                );
+            }
+        }
+    }
 }
 
 impl fmt::Debug for ContextImpl {
@@ -39,8 +150,13 @@ impl fmt::Debug for ContextImpl {
 impl ContextImpl {
     pub unsafe fn new<T: 'static>(wl_dpy_ptr: *mut c_void, builder: ContextBuilder<'_, T>) -> Self {
         let wl_dpy: wl_display::WlDisplay = wl::Proxy::from_c_ptr(wl_dpy_ptr as _).into();
+        let log_target = builder.log_target.unwrap_or(module_path!());
 
-        let manager = wl::GlobalManager::new(&wl_dpy);
+        let outputs = Rc::new(RefCell::new(Vec::new()));
+        let manager = wl::GlobalManager::new_with_cb(&wl_dpy, {
+            let outputs = Rc::clone(&outputs);
+            move |event, registry| track_output(&outputs, event, registry)
+        });
 
         // Retrieve the globals metadata (without this, we will fail to get
         // the global `wl_shm`)
@@ -48,14 +164,15 @@ impl ContextImpl {
             ffi_dispatch!(WAYLAND_CLIENT_HANDLE, wl_display_roundtrip, wl_dpy_ptr as _);
         }
 
+        let shm_formats = Rc::new(RefCell::new(Vec::new()));
         let wl_shm: wl_shm::WlShm = manager
             .instantiate_range(1, 1, |wl_shm| {
+                let shm_formats = Rc::clone(&shm_formats);
                 wl_shm.implement_closure(
                     move |evt, _| {
                         // `wl_shm` sends suppored formats via events
                         if let wl_shm::Event::Format { format } = evt {
-                            let _ = format;
-                            // TODO: examine supported formats
+                            shm_formats.borrow_mut().push(format);
                         }
                     },
                     (),
@@ -63,11 +180,145 @@ impl ContextImpl {
             })
             .expect("server does not advertise `wl_shm`");
 
+        // `zwp_linux_dmabuf_v1` is optional. We never create DMA-BUF buffers,
+        // so the formats it advertises (with the linear modifier, or with any
+        // modifier for version 1 and 2, which don't report modifiers) are only
+        // logged for diagnostics. They say nothing about what `wl_shm`
+        // accepts.
+        let dmabuf_formats = Rc::new(RefCell::new(Vec::new()));
+        let _: Option<zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1> = manager
+            .instantiate_range(1, 3, |zwp_linux_dmabuf| {
+                let dmabuf_formats = Rc::clone(&dmabuf_formats);
+                zwp_linux_dmabuf.implement_closure(
+                    move |evt, zwp_linux_dmabuf: zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1| {
+                        let format = match evt {
+                            // The `format` event is deprecated in version 3,
+                            // which sends `modifier` events instead
+                            zwp_linux_dmabuf_v1::Event::Format { format }
+                                if zwp_linux_dmabuf.as_ref().version() < 3 =>
+                            {
+                                format
+                            }
+                            zwp_linux_dmabuf_v1::Event::Modifier {
+                                format,
+                                modifier_hi: 0,
+                                modifier_lo: DRM_FORMAT_MOD_LINEAR,
+                            } => format,
+                            _ => return,
+                        };
+                        let mut dmabuf_formats = dmabuf_formats.borrow_mut();
+                        if !dmabuf_formats.contains(&format) {
+                            dmabuf_formats.push(format);
+                        }
+                    },
+                    (),
+                )
+            })
+            .map_err(
+                |e| debug!(target: log_target, "Could not bind `zwp_linux_dmabuf_v1`: {:?}", e),
+            )
+            .ok();
+
+        // Receive the `format` and `modifier` events
+        ffi_dispatch!(WAYLAND_CLIENT_HANDLE, wl_display_roundtrip, wl_dpy_ptr as _);
+        debug!(target: log_target, "Formats advertised by `wl_shm`: {:?}", shm_formats.borrow());
+        debug!(
+            target: log_target,
+            "Formats advertised by `zwp_linux_dmabuf_v1`: {:x?}",
+            dmabuf_formats.borrow()
+        );
+
+        let wl_compositor: wl_compositor::WlCompositor = manager
+            .instantiate_range(1, 4, |wl_compositor| wl_compositor.implement_dummy())
+            .expect("server does not advertise `wl_compositor`");
+
+        // This one is optional; `create_subsurface` falls back to emulation
+        let wl_subcompositor = manager
+            .instantiate_range(1, 1, |wl_subcompositor| wl_subcompositor.implement_dummy())
+            .map_err(|e| debug!(target: log_target, "Could not bind `wl_subcompositor`: {:?}", e))
+            .ok();
+
+        // The application implements the seat so that it receives the
+        // `capabilities` and `name` events
+        if let Some(seat_cb) = builder.wayland_seat_cb {
+            let _: Option<wl_seat::WlSeat> = manager
+                .instantiate_range(1, 6, seat_cb)
+                .map_err(|e| debug!(target: log_target, "Could not bind `wl_seat`: {:?}", e))
+                .ok();
+        }
+
         Self {
             wl_dpy,
             wl_shm,
+            shm_formats,
+            wl_compositor,
+            wl_subcompositor,
+            outputs,
 
             ready_cb: Rc::new(builder.ready_cb),
+            error_handler: Rc::new(builder.error_handler),
+            log_target,
+            _ownership: Rc::new(DisplayOwnership::Borrowed),
+        }
+    }
+
+    /// Get the refresh rate of the output `window` is on, measured in mHz.
+    fn refresh_rate(&self) -> Option<u32> {
+        let outputs = self.outputs.borrow();
+
+        // The refresh rate is only known if all outputs share the same one.
+        // Otherwise we'd need to know which output the window is on, but
+        // `Window::current_monitor` panics if the window hasn't entered any
+        // output yet.
+        let refresh = outputs.first()?.refresh;
+        if outputs.iter().any(|o| o.refresh != refresh) {
+            return None;
+        }
+
+        if refresh > 0 {
+            Some(refresh as u32)
+        } else {
+            None
+        }
+    }
+
+    /// Open a new connection to the Wayland display specified by the
+    /// environment and construct a `ContextImpl` owning it.
+    pub unsafe fn new_from_env<T: 'static>(builder: ContextBuilder<'_, T>) -> Self {
+        let wl_dpy_ptr: *mut c_void =
+            ffi_dispatch!(WAYLAND_CLIENT_HANDLE, wl_display_connect, std::ptr::null()) as _;
+        assert!(
+            !wl_dpy_ptr.is_null(),
+            "could not connect to the Wayland display"
+        );
+
+        Self {
+            _ownership: Rc::new(DisplayOwnership::Owned(wl_dpy_ptr)),
+            ..Self::new(wl_dpy_ptr, builder)
+        }
+    }
+
+    pub fn flush(&self) {
+        let wl_dpy_ptr = self.wl_dpy.as_ref().c_ptr() as *mut _;
+        unsafe {
+            ffi_dispatch!(WAYLAND_CLIENT_HANDLE, wl_display_flush, wl_dpy_ptr);
+        }
+    }
+
+    pub fn dispatch_pending(&self) -> usize {
+        let wl_dpy_ptr = self.wl_dpy.as_ref().c_ptr() as *mut _;
+        let num_events = unsafe {
+            ffi_dispatch!(
+                WAYLAND_CLIENT_HANDLE,
+                wl_display_dispatch_pending,
+                wl_dpy_ptr
+            )
+        };
+        if num_events < 0 {
+            warn!(target: self.log_target, "`wl_display_dispatch_pending` failed");
+            0
+        } else {
+            num_events as usize
         }
     }
 }
@@ -85,14 +336,41 @@ struct State {
     wnd_id: WindowId,
     wl_srf: wl_surface::WlSurface,
 
-    images: Box<[Image]>,
+    /// Each image is reference-counted so that `lock_image` guards can keep
+    /// it alive without borrowing the list, which `resize_swapchain` modifies.
+    images: RefCell<Vec<Rc<Image>>>,
 
     /// If `true`, the `release` event handler will call `ready_cb` when
     /// called for the next time.
     enable_ready_cb: Cell<bool>,
 
     image_info: Cell<ImageInfo>,
-    scanline_align: Align,
+    scanline_align: Cell<Align>,
+    transform: Cell<Transform>,
+    damage_merge: Cell<MergeStrategy>,
+
+    /// Set by `set_debug_label` and included in log messages.
+    #[cfg(debug_assertions)]
+    debug_label: RefCell<Option<String>>,
+}
+
+impl State {
+    /// Get the size of each swapchain image in bytes.
+    fn image_size(&self) -> usize {
+        let image_info = self.image_info.get();
+        image_info.stride * image_info.extent[1] as usize
+    }
+
+    /// Get a string identifying the surface in log messages.
+    fn log_name(&self) -> String {
+        #[cfg(debug_assertions)]
+        {
+            if let Some(label) = &*self.debug_label.borrow() {
+                return format!("{:?} ({:?})", self.wnd_id, label);
+            }
+        }
+        format!("{:?}", self.wnd_id)
+    }
 }
 
 impl fmt::Debug for State {
@@ -127,6 +405,37 @@ struct Image {
     /// it via `wl_surface::attach` but haven't received the `release` event.
     /// FIXME: Could be merged into `MemPool::is_used()`
     presenting: Cell<bool>,
+
+    /// A copy of `ContextImpl::log_target`
+    log_target: &'static str,
+}
+
+/// The `wl_surface` and `wl_subsurface` backing a subsurface created by
+/// `SurfaceImpl::create_subsurface`. They are destroyed when this is dropped,
+/// so this must outlive the `SurfaceImpl` attached to the `wl_surface`.
+pub struct SubSurfaceProxies {
+    wl_srf: wl_surface::WlSurface,
+    wl_subsrf: wl_subsurface::WlSubsurface,
+}
+
+impl Drop for SubSurfaceProxies {
+    fn drop(&mut self) {
+        self.wl_subsrf.destroy();
+        self.wl_srf.destroy();
+    }
+}
+
+impl fmt::Debug for SubSurfaceProxies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SubSurfaceProxies").finish()
+    }
+}
+
+/// The result of `SurfaceImpl::dispatch_until`.
+enum DispatchResult {
+    Done,
+    Timeout,
+    Lost,
 }
 
 impl Drop for Image {
@@ -134,10 +443,11 @@ impl Drop for Image {
         let mem = self.mem.get_mut();
         if let Some(mem) = mem {
             if let Some(wl_buf) = mem.1.take() {
-                trace!("Destroying `wl_buffer` {:?}", wl_buf.as_ref().c_ptr());
+                trace!(target: self.log_target, "Destroying `wl_buffer` {:?}", wl_buf.as_ref().c_ptr());
 
-                // `wl_buf` could be still in use by the presenter, but there
-                // isn't much we can do. The Wayland connection might not even
+                // `wl_buf` could be still in use by the presenter unless the
+                // application called `wait_all_released`, but there isn't
+                // much we can do here. The Wayland connection might not even
                 // exist after this call to `drop`... (Remember that the
                 // connection is managed by `winit`)
                 wl_buf.destroy();
@@ -164,9 +474,12 @@ impl SurfaceImpl {
         assert_eq!(wl_dpy, context.wl_dpy.as_ref().c_ptr() as _);
 
         let images: Vec<_> = (0..config.image_count)
-            .map(|_| Image {
-                mem: RefCell::new(None),
-                presenting: Cell::new(false),
+            .map(|_| {
+                Rc::new(Image {
+                    mem: RefCell::new(None),
+                    presenting: Cell::new(false),
+                    log_target: context.log_target,
+                })
             })
             .collect();
 
@@ -177,10 +490,17 @@ impl SurfaceImpl {
                 ctx: context.clone(),
                 wnd_id,
                 wl_srf,
-                images: images.into_boxed_slice(),
+                images: RefCell::new(images),
                 enable_ready_cb: Cell::new(false),
-                image_info: Cell::new(ImageInfo::default()),
-                scanline_align,
+                image_info: Cell::new(ImageInfo {
+                    color_space: config.color_space,
+                    ..ImageInfo::default()
+                }),
+                scanline_align: Cell::new(scanline_align),
+                transform: Cell::new(Transform::Identity),
+                damage_merge: Cell::new(MergeStrategy::default()),
+                #[cfg(debug_assertions)]
+                debug_label: RefCell::new(None),
             }),
         }
     }
@@ -190,16 +510,15 @@ impl SurfaceImpl {
         assert_ne!(extent[1], 0);
 
         // Fail-fast if some images are locked by the appliction
-        let mut mems: Vec<_> = self
-            .state
-            .images
+        let images = self.state.images.borrow();
+        let mut mems: Vec<_> = images
             .iter()
             .map(|image| image.mem.try_borrow_mut().expect("some images are locked"))
             .collect();
 
         // Check the value range
-        assert!(extent[0] <= <i32>::max_value() as u32);
-        assert!(extent[1] <= <i32>::max_value() as u32);
+        assert!(extent[0] <= <i32>::MAX as u32);
+        assert!(extent[1] <= <i32>::MAX as u32);
 
         use std::convert::TryInto;
         let extent_usize: [usize; 2] = [
@@ -209,7 +528,7 @@ impl SurfaceImpl {
 
         let stride = extent_usize[0]
             .checked_mul(4)
-            .and_then(|x| self.state.scanline_align.align_up(x))
+            .and_then(|x| self.state.scanline_align.get().align_up(x))
             .expect("overflow");
 
         // `stride` must fit in `i32`
@@ -220,9 +539,14 @@ impl SurfaceImpl {
             extent,
             stride,
             format,
+            ..self.state.image_info.get()
         };
 
-        trace!("{:?}: New image info = {:?}", self.state.wnd_id, image_info);
+        trace!(target: self.state.ctx.log_target,
+            "{}: New image info = {:?}",
+            self.state.log_name(),
+            image_info
+        );
 
         let size = stride
             .checked_mul(image_info.extent[1] as usize)
@@ -230,52 +554,147 @@ impl SurfaceImpl {
 
         // Resize mempools
         for (i, mem) in mems.iter_mut().enumerate() {
-            let (mem_pool, _) = mem.get_or_insert_with(|| {
-                // `MemPool` isn't created yet, so make one now
-                let state = Rc::clone(&self.state);
-
-                // `MemPool` doesn't call the event handler from another thread
-                // (AFAIK). It requires it to be `Send` only to allow you to
-                // create a `MemPool` for a `WlShm` originaing from another
-                // thread.  So assert that `state` will be used by the current
-                // thread.
-                let state = Fragile::new(state);
-
-                let on_release = move || {
-                    // Assert that we are using it from the correct thread
-                    let state = state.get();
-
-                    trace!("{:?}: Swapchain image {} was released", state.wnd_id, i);
-
-                    state.images[i].presenting.set(false);
-
-                    // Does the application want to receive a notification?
-                    // If so, reset this flag and call the ready callback.
-                    if state.enable_ready_cb.replace(false) {
-                        trace!("Calling `ready_cb`");
-                        (state.ctx.ready_cb)(state.wnd_id);
-                    }
-                };
+            let (mem_pool, _) = mem.get_or_insert_with(|| (self.create_mem_pool(i), None));
+
+            if images[i].presenting.get() {
+                // The compositor may still be reading the attached
+                // `wl_buffer`. Leave the pool alone until the image is
+                // released and accessed again (see `ensure_pool_size`).
+                trace!(target: self.state.ctx.log_target,
+                    "{}: Deferring the resize of swapchain image {}",
+                    self.state.log_name(),
+                    i
+                );
+                continue;
+            }
+
+            ensure_pool_size(self.state.ctx.log_target, mem_pool, size);
+        }
 
-                trace!("Creating `MemPool`");
+        self.state.image_info.set(image_info);
+    }
 
-                let mem_pool = MemPool::new(&self.state.ctx.wl_shm, on_release)
-                    .expect("could not create `wl_shm_pool`");
+    /// Create a `MemPool` for the swapchain image at index `i`.
+    fn create_mem_pool(&self, i: usize) -> MemPool {
+        let state = Rc::clone(&self.state);
 
-                (mem_pool, None)
-            });
+        // `MemPool` doesn't call the event handler from another thread
+        // (AFAIK). It requires it to be `Send` only to allow you to
+        // create a `MemPool` for a `WlShm` originaing from another
+        // thread.  So assert that `state` will be used by the current
+        // thread.
+        let state = Fragile::new(state);
+
+        let on_release = move || {
+            // Assert that we are using it from the correct thread
+            let state = state.get();
+
+            trace!(target: state.ctx.log_target, "{}: Swapchain image {} was released", state.log_name(), i);
+
+            if !state.images.borrow()[i].presenting.replace(false) {
+                (state.ctx.error_handler)(SurfaceError::UnexpectedRelease(i));
+            }
+
+            // Does the application want to receive a notification?
+            // If so, reset this flag and call the ready callback.
+            if state.enable_ready_cb.replace(false) {
+                trace!(target: state.ctx.log_target, "Calling `ready_cb`");
+                (state.ctx.ready_cb)(state.wnd_id);
+            }
+        };
+
+        trace!(target: self.state.ctx.log_target, "Creating `MemPool`");
+
+        MemPool::new(&self.state.ctx.wl_shm, on_release).expect("could not create `wl_shm_pool`")
+    }
+
+    pub fn resize_swapchain(&self, count: usize) -> Result<(), SurfaceError> {
+        assert_ne!(count, 0);
 
-            trace!("Resizing `MemPool` to {}", size);
-            mem_pool
-                .resize(size)
-                .expect("could not resize the memory-mapped file");
+        let mut images = self.state.images.borrow_mut();
+
+        // The removed images must be idle. Their `MemPool`s (and `wl_buffer`s)
+        // are destroyed right away.
+        if let Some(i) = (count..images.len())
+            .find(|&i| images[i].presenting.get() || images[i].mem.try_borrow_mut().is_err())
+        {
+            return Err(SurfaceError::ImageInUse(i));
         }
 
-        self.state.image_info.set(image_info);
+        trace!(target: self.state.ctx.log_target,
+            "{}: Resizing the swapchain from {} to {} images",
+            self.state.log_name(),
+            images.len(),
+            count
+        );
+
+        images.truncate(count);
+
+        // Allocate the new images now if `update_surface` has been called
+        let initialized = self.state.image_info.get().extent[0] != 0;
+        while images.len() < count {
+            let mem = if initialized {
+                let mut mem_pool = self.create_mem_pool(images.len());
+                ensure_pool_size(
+                    self.state.ctx.log_target,
+                    &mut mem_pool,
+                    self.state.image_size(),
+                );
+                Some((mem_pool, None))
+            } else {
+                None
+            };
+
+            images.push(Rc::new(Image {
+                mem: RefCell::new(mem),
+                presenting: Cell::new(false),
+                log_target: self.state.ctx.log_target,
+            }));
+        }
+
+        Ok(())
+    }
+
+    pub fn reconfigure(&self, _old: &Config, new: &Config) -> Vec<ConfigField> {
+        // `image_count` is applied by `resize_swapchain`
+
+        // `color_space` is merely a tag
+        self.state.image_info.set(ImageInfo {
+            color_space: new.color_space,
+            ..self.state.image_info.get()
+        });
+
+        Vec::new()
+    }
+
+    pub fn set_scanline_align(&self, scanline_align: Align) {
+        self.state.scanline_align.set(scanline_align);
+    }
+
+    pub fn supports_transparency(&self) -> bool {
+        true
+    }
+
+    pub fn refresh_rate(&self, _window: &Window) -> Option<u32> {
+        self.state.ctx.refresh_rate()
+    }
+
+    pub fn default_scanline_align(&self) -> Align {
+        // `wl_shm` accepts any stride
+        Align::new(4).unwrap()
     }
 
     pub fn supported_formats(&self) -> impl Iterator<Item = Format> + '_ {
-        [Format::Argb8888].iter().cloned()
+        // `argb8888` is mandatory. `xbgr8888` is only available if advertised
+        // by `wl_shm`, which allocates the images.
+        let xbgr8888 = self
+            .state
+            .ctx
+            .shm_formats
+            .borrow()
+            .contains(&wl_shm::Format::Xbgr8888)
+            .then_some(Format::Xbgr8888);
+        std::iter::once(Format::Argb8888).chain(xbgr8888)
     }
 
     pub fn image_info(&self) -> ImageInfo {
@@ -283,36 +702,45 @@ impl SurfaceImpl {
     }
 
     pub fn num_images(&self) -> usize {
-        self.state.images.len()
+        self.state.images.borrow().len()
     }
 
     pub fn does_preserve_image(&self) -> bool {
         true
     }
 
+    pub fn is_presenting(&self, i: usize) -> bool {
+        self.state.images.borrow()[i].presenting.get()
+    }
+
+    pub fn is_locked(&self, i: usize) -> bool {
+        self.state.images.borrow()[i].mem.try_borrow_mut().is_err()
+    }
+
     pub fn poll_next_image(&self) -> Option<usize> {
         let result = self
             .state
             .images
+            .borrow()
             .iter()
-            .position(|image| image.presenting.get() == false);
+            .position(|image| !image.presenting.get());
 
         if let Some(i) = result {
-            trace!(
-                "{:?}: Swapchain image {} is available, returning it",
-                self.state.wnd_id,
+            trace!(target: self.state.ctx.log_target,
+                "{}: Swapchain image {} is available, returning it",
+                self.state.log_name(),
                 i
             );
         } else {
             if self.state.enable_ready_cb.get() {
-                trace!(
-                    "{:?}: No swapchain image is available. `ready_cb` is already enabled.",
-                    self.state.wnd_id
+                trace!(target: self.state.ctx.log_target,
+                    "{}: No swapchain image is available. `ready_cb` is already enabled.",
+                    self.state.log_name()
                 );
             } else {
-                trace!(
-                    "{:?}: No swapchain image is available. Enabling `ready_cb`.",
-                    self.state.wnd_id
+                trace!(target: self.state.ctx.log_target,
+                    "{}: No swapchain image is available. Enabling `ready_cb`.",
+                    self.state.log_name()
                 );
             }
 
@@ -323,44 +751,367 @@ impl SurfaceImpl {
         result
     }
 
-    pub fn lock_image(&self, i: usize) -> impl Deref<Target = [u8]> + DerefMut + '_ {
-        let image = &self.state.images[i];
+    pub fn enable_ready_cb(&self) {
+        self.state.enable_ready_cb.set(true);
+    }
+
+    pub fn is_ready_cb_scheduled(&self) -> bool {
+        self.state.enable_ready_cb.get()
+    }
+
+    pub fn acquire_with_timeout(&self, timeout: Duration) -> AcquireResult {
+        let mut result = None;
+        match self.dispatch_until(timeout, || {
+            result = self.poll_next_image();
+            result.is_some()
+        }) {
+            DispatchResult::Done => AcquireResult::Ready(result.unwrap()),
+            DispatchResult::Timeout => AcquireResult::Timeout,
+            DispatchResult::Lost => AcquireResult::Lost,
+        }
+    }
+
+    pub fn handle_os_event<T>(&self, _event: &Event<T>) {}
 
-        assert_eq!(
-            image.presenting.get(),
-            false,
+    /// Detach the buffer from the surface so that the compositor releases
+    /// it. The compositor keeps the last attached buffer until another one is
+    /// attached.
+    pub fn detach(&self) {
+        self.state.wl_srf.attach(None, 0, 0);
+        self.state.wl_srf.commit();
+    }
+
+    pub fn wait_all_released(&self, timeout: Duration) -> bool {
+        let images = &self.state.images;
+        match self.dispatch_until(timeout, || {
+            images.borrow().iter().all(|i| !i.presenting.get())
+        }) {
+            DispatchResult::Done => true,
+            DispatchResult::Timeout | DispatchResult::Lost => false,
+        }
+    }
+
+    /// Read and dispatch events until `cond` returns `true`, the timeout
+    /// elapses, or the connection is lost.
+    fn dispatch_until(&self, timeout: Duration, mut cond: impl FnMut() -> bool) -> DispatchResult {
+        let wl_dpy_ptr = self.state.ctx.wl_dpy.as_ref().c_ptr() as *mut _;
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            if cond() {
+                return DispatchResult::Done;
+            }
+
+            unsafe {
+                if ffi_dispatch!(WAYLAND_CLIENT_HANDLE, wl_display_get_error, wl_dpy_ptr) != 0 {
+                    warn!(target: self.state.ctx.log_target, "{}: The display connection is lost", self.state.log_name());
+                    return DispatchResult::Lost;
+                }
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                trace!(target: self.state.ctx.log_target, "{}: Timed out", self.state.log_name());
+                return DispatchResult::Timeout;
+            }
+            let remaining = deadline - now;
+
+            // Wait for the `release` event. Events are read and dispatched
+            // in the default event queue, so this may call the event handlers
+            // of other objects as well.
+            unsafe {
+                if ffi_dispatch!(WAYLAND_CLIENT_HANDLE, wl_display_prepare_read, wl_dpy_ptr) != 0 {
+                    // The queue already has pending events
+                    ffi_dispatch!(
+                        WAYLAND_CLIENT_HANDLE,
+                        wl_display_dispatch_pending,
+                        wl_dpy_ptr
+                    );
+                    continue;
+                }
+
+                ffi_dispatch!(WAYLAND_CLIENT_HANDLE, wl_display_flush, wl_dpy_ptr);
+
+                let mut pollfd = libc::pollfd {
+                    fd: ffi_dispatch!(WAYLAND_CLIENT_HANDLE, wl_display_get_fd, wl_dpy_ptr),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                // Round up so that we don't spin when less than 1ms is left
+                let timeout_ms = remaining.as_micros().div_ceil(1000);
+                let timeout_ms = cmp::min(timeout_ms, libc::c_int::MAX as u128) as libc::c_int;
+
+                if libc::poll(&mut pollfd, 1, timeout_ms) > 0 {
+                    ffi_dispatch!(WAYLAND_CLIENT_HANDLE, wl_display_read_events, wl_dpy_ptr);
+                } else {
+                    ffi_dispatch!(WAYLAND_CLIENT_HANDLE, wl_display_cancel_read, wl_dpy_ptr);
+                }
+
+                ffi_dispatch!(
+                    WAYLAND_CLIENT_HANDLE,
+                    wl_display_dispatch_pending,
+                    wl_dpy_ptr
+                );
+            }
+        }
+    }
+
+    pub fn lock_image(&self, i: usize) -> impl DerefMut<Target = [u8]> + '_ {
+        let image = Rc::clone(&self.state.images.borrow()[i]);
+
+        assert!(
+            !image.presenting.get(),
             "the image is currently in use by the compositor"
         );
 
-        OwningRefMut::new(image.mem.borrow_mut()).map_mut(|x| {
+        let size = self.state.image_size();
+
+        // Safety: `image` outlives the `RefMut` because both are owned by the
+        // `OwningHandle`
+        let mem = OwningHandle::new_with_fn(image, |image| unsafe { (*image).mem.borrow_mut() });
+
+        OwningRefMut::new(mem).map_mut(move |x| {
             // `update_surface` should have been called at least one.
             // Otherwise, panic
-            x.as_mut()
-                .expect("surface is not initialized")
-                .0
-                // Get the underlying data of the memory-mapped file
-                .mmap()
-                .as_mut()
+            let mem_pool = &mut x.as_mut().expect("surface is not initialized").0;
+
+            ensure_pool_size(self.state.ctx.log_target, mem_pool, size);
+
+            // Get the underlying data of the memory-mapped file
+            mem_pool.mmap().as_mut()
         })
     }
 
+    pub fn set_transform(&self, transform: Transform) {
+        // `set_buffer_transform` is available since version 2
+        if self.state.wl_srf.as_ref().version() < 2 {
+            warn!(target: self.state.ctx.log_target, "`wl_surface::set_buffer_transform` is not supported");
+            return;
+        }
+
+        // The compositor applies the inverse of the buffer transform.
+        // (`wl_output::Transform` rotates counter-clockwise)
+        let wl_transform = match transform {
+            Transform::Identity => wl_output::Transform::Normal,
+            Transform::Rotate90 => wl_output::Transform::_90,
+            Transform::Rotate180 => wl_output::Transform::_180,
+            Transform::Rotate270 => wl_output::Transform::_270,
+            Transform::FlipHorizontal => wl_output::Transform::Flipped,
+            Transform::FlipVertical => wl_output::Transform::Flipped180,
+        };
+
+        // This state is double-buffered and applied by the next commit
+        self.state.wl_srf.set_buffer_transform(wl_transform);
+        self.state.transform.set(transform);
+    }
+
+    pub fn transform(&self) -> Transform {
+        self.state.transform.get()
+    }
+
+    #[cfg(feature = "screen-capture")]
+    pub fn screen_capture(&self, _extent: [u32; 2]) -> Option<Vec<u8>> {
+        // TODO: Use `wlr-screencopy` where available
+        None
+    }
+
+    pub fn set_damage_merge_strategy(&self, strategy: MergeStrategy) {
+        self.state.damage_merge.set(strategy);
+    }
+
+    #[cfg(debug_assertions)]
+    pub fn set_debug_label(&self, label: &str) {
+        *self.state.debug_label.borrow_mut() = Some(label.to_owned());
+    }
+
+    /// Create a `wl_subsurface` positioned at `rect[0..2]` relative to this
+    /// surface and a swapchain of size `rect[2..4]` attached to it. Returns
+    /// `None` if the server doesn't support `wl_subcompositor`.
+    pub fn create_subsurface(
+        &self,
+        rect: [u32; 4],
+        config: &Config,
+    ) -> Option<(SurfaceImpl, SubSurfaceProxies)> {
+        let ctx = &self.state.ctx;
+        let wl_subcompositor = ctx.wl_subcompositor.as_ref()?;
+
+        let wl_srf: wl_surface::WlSurface = ctx
+            .wl_compositor
+            .create_surface(|wl_srf| wl_srf.implement_dummy())
+            .expect("could not create `wl_surface`");
+        let wl_subsrf: wl_subsurface::WlSubsurface = wl_subcompositor
+            .get_subsurface(&wl_srf, &self.state.wl_srf, |wl_subsrf| {
+                wl_subsrf.implement_dummy()
+            })
+            .expect("could not create `wl_subsurface`");
+
+        // The position is applied by the parent's next commit
+        let clamp = |x: u32| cmp::min(x, <i32>::MAX as u32) as i32;
+        wl_subsrf.set_position(clamp(rect[0]), clamp(rect[1]));
+
+        // Let the subsurface's commits take effect without the parent's
+        wl_subsrf.set_desync();
+
+        trace!(target: self.state.ctx.log_target,
+            "{}: Created a subsurface at {:?}",
+            self.state.log_name(),
+            rect
+        );
+
+        let surface = unsafe {
+            SurfaceImpl::new(
+                ctx.wl_dpy.as_ref().c_ptr() as _,
+                wl_srf.as_ref().c_ptr() as _,
+                self.state.wnd_id,
+                ctx,
+                config,
+                self.state.scanline_align.get(),
+            )
+        };
+        surface.update_surface([rect[2], rect[3]], self.state.image_info.get().format);
+
+        Some((surface, SubSurfaceProxies { wl_srf, wl_subsrf }))
+    }
+
+    pub fn set_input_region(&self, region: Option<&[[u32; 4]]>) {
+        let region = region.map(|rects| {
+            let wl_region: wl_region::WlRegion = self
+                .state
+                .ctx
+                .wl_compositor
+                .create_region(|wl_region| wl_region.implement_dummy())
+                .expect("could not create `wl_region`");
+
+            for &[x, y, width, height] in rects {
+                let clamp = |x: u32| cmp::min(x, <i32>::MAX as u32) as i32;
+                wl_region.add(clamp(x), clamp(y), clamp(width), clamp(height));
+            }
+
+            wl_region
+        });
+
+        // This state is double-buffered and applied by the next commit
+        self.state.wl_srf.set_input_region(region.as_ref());
+
+        // The compositor copies the region, so we can destroy it right away
+        if let Some(wl_region) = region {
+            wl_region.destroy();
+        }
+    }
+
+    pub fn copy_image(&self, src_i: usize, dst_i: usize) {
+        let images = self.state.images.borrow();
+
+        for &i in &[src_i, dst_i] {
+            assert!(
+                !images[i].presenting.get(),
+                "the image is currently in use by the compositor"
+            );
+        }
+
+        let mut src_mem = images[src_i]
+            .mem
+            .try_borrow_mut()
+            .expect("the image is locked");
+
+        if src_i == dst_i {
+            return;
+        }
+
+        let mut dst_mem = images[dst_i]
+            .mem
+            .try_borrow_mut()
+            .expect("the image is locked");
+
+        let size = self.state.image_size();
+
+        trace!(target: self.state.ctx.log_target,
+            "{}: Copying swapchain image {} to {}",
+            self.state.log_name(),
+            src_i,
+            dst_i
+        );
+
+        let src_pool = &mut src_mem.as_mut().expect("surface is not initialized").0;
+        let dst_pool = &mut dst_mem.as_mut().expect("surface is not initialized").0;
+        ensure_pool_size(self.state.ctx.log_target, src_pool, size);
+        ensure_pool_size(self.state.ctx.log_target, dst_pool, size);
+
+        dst_pool.mmap()[..size].copy_from_slice(&src_pool.mmap()[..size]);
+    }
+
     pub fn present_image(&self, i: usize) {
-        let image = &self.state.images[i];
+        let [size_w, size_h] = self.state.image_info.get().extent;
+        self.present(i, &[[0, 0, size_w, size_h]], [0, 0]);
+    }
+
+    pub fn present_image_at(&self, i: usize, offset: [i32; 2]) {
+        let [size_w, size_h] = self.state.image_info.get().extent;
+        self.present(i, &[[0, 0, size_w, size_h]], offset);
+    }
 
-        assert_eq!(
-            image.presenting.get(),
-            false,
+    pub fn present_image_immediately(&self, i: usize) {
+        // We don't wait for frame callbacks in the first place
+        self.present_image(i);
+    }
+
+    /// Attach the image at index `i` without committing the surface. The
+    /// caller must call `commit` afterwards.
+    pub fn present_image_uncommitted(&self, i: usize) {
+        let [size_w, size_h] = self.state.image_info.get().extent;
+        self.attach(i, &[[0, 0, size_w, size_h]], [0, 0]);
+    }
+
+    pub fn commit(&self) {
+        self.state.wl_srf.commit();
+    }
+
+    pub fn try_present_image(&self, i: usize) -> Result<(), SurfaceError> {
+        self.present_image(i);
+        Ok(())
+    }
+
+    pub fn present_image_damaged(&self, i: usize, damage: &[[u32; 4]]) {
+        self.present(i, damage, [0, 0]);
+    }
+
+    pub fn present_region(&self, i: usize, src_rect: [u32; 4], dst_pos: [u32; 2]) {
+        // Only the moved region and the uncovered area have changed, both
+        // of which are contained by these rectangles
+        let [_, _, width, height] = src_rect;
+        self.present(
+            i,
+            &[src_rect, [dst_pos[0], dst_pos[1], width, height]],
+            [0, 0],
+        );
+    }
+
+    /// Present the image at index `i`. `damage` specifies the updated regions
+    /// in the buffer coordinate space. `offset` is passed to
+    /// `wl_surface::attach`.
+    fn present(&self, i: usize, damage: &[[u32; 4]], offset: [i32; 2]) {
+        self.attach(i, damage, offset);
+        self.state.wl_srf.commit();
+    }
+
+    /// Attach and damage the image at index `i` without committing the
+    /// surface. See `present` for the parameters.
+    fn attach(&self, i: usize, damage: &[[u32; 4]], offset: [i32; 2]) {
+        let images = self.state.images.borrow();
+        let image = &images[i];
+
+        assert!(
+            !image.presenting.get(),
             "the image is currently in use by the compositor"
         );
 
         let mut mem = image.mem.try_borrow_mut().expect("the image is locked");
         let (mem_pool, buffer_cell) = mem.as_mut().expect("surface is not initialized");
 
+        ensure_pool_size(self.state.ctx.log_target, mem_pool, self.state.image_size());
+
         let image_info = self.state.image_info.get();
-        let format = match image_info.format {
-            Format::Argb8888 => wl_shm::Format::Argb8888,
-            Format::Xrgb8888 => wl_shm::Format::Xrgb8888,
-        };
+        let format = image_info.format.to_wl_shm();
 
         // Create `wl_buffer`.
         let buffer = mem_pool.buffer(
@@ -371,9 +1122,9 @@ impl SurfaceImpl {
             format,
         );
 
-        trace!(
-            "{:?}: Presenting swapchain image {} using `wl_buffer` {:?}",
-            self.state.wnd_id,
+        trace!(target: self.state.ctx.log_target,
+            "{}: Presenting swapchain image {} using `wl_buffer` {:?}",
+            self.state.log_name(),
             i,
             buffer.as_ref().c_ptr()
         );
@@ -384,11 +1135,23 @@ impl SurfaceImpl {
         debug_assert!(mem_pool.is_used());
 
         // Attach the `wl_buffer` to the `wl_surface`.
-        self.state.wl_srf.attach(Some(&buffer), 0, 0);
+        // (A non-zero offset is a protocol error since `wl_surface` version 5,
+        // but we only bind up to version 4.)
         self.state
             .wl_srf
-            .damage_buffer(0, 0, image_info.extent[0] as _, image_info.extent[1] as _);
-        self.state.wl_srf.commit();
+            .attach(Some(&buffer), offset[0], offset[1]);
+
+        // Drop the parts outside the buffer
+        let mut damage: Vec<_> = damage
+            .iter()
+            .filter_map(|&rect| image_info.clip_rect(rect))
+            .collect();
+        merge_rects(&mut damage, self.state.damage_merge.get());
+        for [x, y, width, height] in damage {
+            self.state
+                .wl_srf
+                .damage_buffer(x as _, y as _, width as _, height as _);
+        }
 
         if let Some(old_buffer) = buffer_cell.take() {
             old_buffer.destroy();
@@ -399,3 +1162,18 @@ impl SurfaceImpl {
         image.presenting.set(true);
     }
 }
+
+/// Make sure `mem_pool` can hold `size` bytes. `update_surface` defers
+/// resizing the pools of the images in use by the compositor, so this must be
+/// called before accessing a pool.
+///
+/// This is a no-op if the pool is already large enough. (`MemPool` never
+/// shrinks.)
+fn ensure_pool_size(log_target: &str, mem_pool: &mut MemPool, size: usize) {
+    if size > mem_pool.mmap().len() {
+        trace!(target: log_target, "Resizing `MemPool` to {}", size);
+    }
+    mem_pool
+        .resize(size)
+        .expect("could not resize the memory-mapped file");
+}
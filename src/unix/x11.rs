@@ -1,30 +1,67 @@
-use log::debug;
+use log::{debug, warn};
 use owning_ref::OwningRefMut;
 use std::{
     cell::{Cell, RefCell},
-    fmt,
-    ops::{Deref, DerefMut},
-    os::raw::{c_ulong, c_void},
+    cmp, fmt,
+    ops::DerefMut,
+    os::raw::{c_int, c_ulong, c_void},
+    slice::{from_raw_parts, from_raw_parts_mut},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
 };
-use winit::window::WindowId;
-use x11_dl::xlib;
+use winit::{event::Event, window::WindowId};
+use x11_dl::{xfixes, xlib, xshm};
 
-use super::super::{align::Align, buffer::Buffer, Config, Format, ImageInfo};
+use super::super::{
+    align::Align, buffer::Buffer, merge_rects, rect_difference, surface::ConfigField, transform,
+    AcquireResult, Config, Format, ImageInfo, MergeStrategy, SurfaceError, Transform,
+    TransparentFallback,
+};
 
-// TODO: Non-opaque window
+// TODO: Force alpha = 0xff for opaque surfaces on ARGB visuals
 
 lazy_static::lazy_static! {
     static ref XLIB: xlib::Xlib = xlib::Xlib::open().unwrap();
+    /// `libXfixes` (`x11_dl` calls it `Xlib` for some reason). This is
+    /// optional and only used by `set_input_region`.
+    static ref XFIXES: Option<xfixes::Xlib> = xfixes::Xlib::open().ok();
+    /// `libXext`. This is optional and only used for MIT-SHM
+    /// ([`Config::prefer_shared_memory`]).
+    static ref XEXT: Option<xshm::Xext> = xshm::Xext::open().ok();
 }
 
+/// `ShapeBounding` from `<X11/extensions/shape.h>`
+const SHAPE_BOUNDING: c_int = 0;
+/// `ShapeInput` from `<X11/extensions/shape.h>`
+const SHAPE_INPUT: c_int = 2;
+
 pub struct SurfaceImpl {
     xlib: &'static xlib::Xlib,
     x_dpy: *mut xlib::Display,
     x_wnd: c_ulong,
     x_scrn: *mut xlib::Screen,
+    /// The depth of the window's visual. `32` means an ARGB visual.
+    depth: c_int,
+    /// A GC created for the window. The screen's default GC can't be used
+    /// because it has the root window's depth, which may differ from
+    /// `depth`.
+    x_gc: xlib::GC,
+    /// `true` if the window shape is derived from the alpha channel
+    /// (`TransparentFallback::ShapeMask`).
+    shape_mask: Cell<bool>,
     image_info: Cell<ImageInfo>,
-    image: RefCell<Buffer>,
-    scanline_align: Align,
+    image: RefCell<ImageMemory>,
+    /// [`Config::no_clear_on_resize`]
+    no_clear_on_resize: Cell<bool>,
+    /// `true` if the image should be placed in a shared memory segment.
+    /// Set by [`Config::prefer_shared_memory`] and cleared if MIT-SHM turns
+    /// out to be unavailable.
+    use_shm: Cell<bool>,
+    scanline_align: Cell<Align>,
+    transform: Cell<Transform>,
+    damage_merge: Cell<MergeStrategy>,
+    /// Stores the transformed image if `transform != Identity`
+    transformed_image: RefCell<Vec<u8>>,
 }
 
 impl fmt::Debug for SurfaceImpl {
@@ -33,6 +70,14 @@ impl fmt::Debug for SurfaceImpl {
     }
 }
 
+impl Drop for SurfaceImpl {
+    fn drop(&mut self) {
+        unsafe {
+            (self.xlib.XFreeGC)(self.x_dpy, self.x_gc);
+        }
+    }
+}
+
 impl SurfaceImpl {
     pub unsafe fn new(
         x_dpy: *mut c_void,
@@ -50,22 +95,41 @@ impl SurfaceImpl {
         let x_scrn = x_wnd_attrs.screen;
         assert!(!x_scrn.is_null());
 
+        let depth = x_wnd_attrs.depth;
+        debug!("Window depth = {}", depth);
+
+        let x_gc = (xlib.XCreateGC)(x_dpy, x_wnd, 0, std::ptr::null_mut());
+        assert!(!x_gc.is_null());
+
         Self {
             xlib,
             x_dpy,
             x_wnd,
             x_scrn,
-            image_info: Cell::new(ImageInfo::default()),
-            image: RefCell::new(Buffer::from_size_align(1, config.align).unwrap()),
-            scanline_align,
+            depth,
+            x_gc,
+            shape_mask: Cell::new(use_shape_mask(config, depth)),
+            image_info: Cell::new(ImageInfo {
+                color_space: config.color_space,
+                ..ImageInfo::default()
+            }),
+            image: RefCell::new(ImageMemory::Heap(
+                Buffer::from_size_align(1, config.align).unwrap(),
+            )),
+            no_clear_on_resize: Cell::new(config.no_clear_on_resize),
+            use_shm: Cell::new(config.prefer_shared_memory && config.align <= SHM_ALIGN),
+            scanline_align: Cell::new(scanline_align),
+            transform: Cell::new(Transform::Identity),
+            damage_merge: Cell::new(MergeStrategy::default()),
+            transformed_image: RefCell::new(Vec::new()),
         }
     }
 
     pub fn update_surface(&self, extent: [u32; 2], format: Format) {
         assert_ne!(extent[0], 0);
         assert_ne!(extent[1], 0);
-        assert!(extent[0] <= <i32>::max_value() as u32);
-        assert!(extent[1] <= <i32>::max_value() as u32);
+        assert!(extent[0] <= <i32>::MAX as u32);
+        assert!(extent[1] <= <i32>::MAX as u32);
 
         use std::convert::TryInto;
         let extent_usize: [usize; 2] = [
@@ -75,7 +139,7 @@ impl SurfaceImpl {
 
         let stride = extent_usize[0]
             .checked_mul(4)
-            .and_then(|x| self.scanline_align.align_up(x))
+            .and_then(|x| self.scanline_align.get().align_up(x))
             .expect("overflow");
 
         // `stride` must fit in `XImage::bytes_per_line`
@@ -91,15 +155,163 @@ impl SurfaceImpl {
         let _ = depth;
 
         let mut image = self.image.borrow_mut();
-        image.resize(size);
+        if self.use_shm.get() && !unsafe { image.resize_shm(self.xlib, self.x_dpy, size) } {
+            // Don't try again
+            self.use_shm.set(false);
+        }
+        if !self.use_shm.get() {
+            image.resize(size, !self.no_clear_on_resize.get());
+        }
 
         self.image_info.set(ImageInfo {
             extent,
             stride: extent[0] as usize * 4,
             format,
+            ..self.image_info.get()
         });
     }
 
+    pub fn reconfigure(&self, old: &Config, new: &Config) -> Vec<ConfigField> {
+        let mut unsupported = Vec::new();
+
+        // `Buffer`'s alignment is fixed
+        if new.align != old.align {
+            unsupported.push(ConfigField::Align);
+        }
+
+        // The image is moved by the next call to `update_surface`
+        self.use_shm
+            .set(new.prefer_shared_memory && new.align <= SHM_ALIGN);
+
+        self.no_clear_on_resize.set(new.no_clear_on_resize);
+
+        // `color_space` is merely a tag
+        self.image_info.set(ImageInfo {
+            color_space: new.color_space,
+            ..self.image_info.get()
+        });
+
+        let shape_mask = use_shape_mask(new, self.depth);
+        if self.shape_mask.replace(shape_mask) && !shape_mask {
+            self.set_shape_region(SHAPE_BOUNDING, None);
+        }
+
+        unsupported
+    }
+
+    pub fn resize_swapchain(&self, _count: usize) -> Result<(), SurfaceError> {
+        // There's always exactly one image
+        Ok(())
+    }
+
+    pub fn set_scanline_align(&self, scanline_align: Align) {
+        self.scanline_align.set(scanline_align);
+    }
+
+    pub fn set_damage_merge_strategy(&self, strategy: MergeStrategy) {
+        self.damage_merge.set(strategy);
+    }
+
+    #[cfg(debug_assertions)]
+    pub fn set_debug_label(&self, _label: &str) {}
+
+    pub fn set_input_region(&self, region: Option<&[[u32; 4]]>) {
+        self.set_shape_region(SHAPE_INPUT, region);
+    }
+
+    /// Set the window shape of the kind `shape_kind` to the union of
+    /// rectangles `[x, y, width, height]`. `None` resets the shape.
+    fn set_shape_region(&self, shape_kind: c_int, region: Option<&[[u32; 4]]>) {
+        let xfixes = if let Some(xfixes) = &*XFIXES {
+            xfixes
+        } else {
+            warn!("`libXfixes` is not available; ignoring the window shape");
+            return;
+        };
+
+        unsafe {
+            let x_region = if let Some(rects) = region {
+                let mut x_rects: Vec<xlib::XRectangle> = rects
+                    .iter()
+                    .map(|&[x, y, width, height]| xlib::XRectangle {
+                        x: cmp::min(x, <i16>::MAX as u32) as _,
+                        y: cmp::min(y, <i16>::MAX as u32) as _,
+                        width: cmp::min(width, <u16>::MAX as u32) as _,
+                        height: cmp::min(height, <u16>::MAX as u32) as _,
+                    })
+                    .collect();
+
+                (xfixes.XFixesCreateRegion)(self.x_dpy, x_rects.as_mut_ptr(), x_rects.len() as _)
+            } else {
+                // `None` resets the shape
+                0
+            };
+
+            (xfixes.XFixesSetWindowShapeRegion)(self.x_dpy, self.x_wnd, shape_kind, 0, 0, x_region);
+
+            if x_region != 0 {
+                (xfixes.XFixesDestroyRegion)(self.x_dpy, x_region);
+            }
+        }
+    }
+
+    #[cfg(feature = "screen-capture")]
+    pub fn screen_capture(&self, extent: [u32; 2]) -> Option<Vec<u8>> {
+        let [width, height] = extent;
+        let mut out = vec![0u8; width as usize * height as usize * 4];
+
+        unsafe {
+            // `XGetImage` fails if the rectangle extends beyond the window
+            let mut x_wnd_attrs: xlib::XWindowAttributes = std::mem::zeroed();
+            (self.xlib.XGetWindowAttributes)(self.x_dpy, self.x_wnd, &mut x_wnd_attrs);
+            if x_wnd_attrs.map_state != xlib::IsViewable {
+                return None;
+            }
+            let cap_width = cmp::min(width, x_wnd_attrs.width as u32);
+            let cap_height = cmp::min(height, x_wnd_attrs.height as u32);
+            if cap_width == 0 || cap_height == 0 {
+                return Some(out);
+            }
+
+            let x_image = (self.xlib.XGetImage)(
+                self.x_dpy,
+                self.x_wnd,
+                0,
+                0,
+                cap_width,
+                cap_height,
+                !0, // `AllPlanes`
+                xlib::ZPixmap,
+            );
+            if x_image.is_null() {
+                warn!("XGetImage failed");
+                return None;
+            }
+
+            // Assumes the same visual as `present`
+            for y in 0..cap_height {
+                let row = &mut out[y as usize * width as usize * 4..];
+                for x in 0..cap_width {
+                    let value = (self.xlib.XGetPixel)(x_image, x as _, y as _) as u32;
+                    row[x as usize * 4..][..4].copy_from_slice(&(value | 0xff000000).to_le_bytes());
+                }
+            }
+
+            (self.xlib.XDestroyImage)(x_image);
+        }
+
+        Some(out)
+    }
+
+    pub fn supports_transparency(&self) -> bool {
+        self.depth == 32
+    }
+
+    pub fn default_scanline_align(&self) -> Align {
+        // Matches `bitmap_pad` of `XImage`
+        Align::new(4).unwrap()
+    }
+
     pub fn supported_formats(&self) -> impl Iterator<Item = Format> + '_ {
         [Format::Argb8888, Format::Xrgb8888].iter().cloned()
     }
@@ -116,16 +328,95 @@ impl SurfaceImpl {
         true
     }
 
+    pub fn is_presenting(&self, i: usize) -> bool {
+        assert_eq!(i, 0);
+        false
+    }
+
+    pub fn is_locked(&self, i: usize) -> bool {
+        assert_eq!(i, 0);
+        self.image.try_borrow_mut().is_err()
+    }
+
     pub fn poll_next_image(&self) -> Option<usize> {
         Some(0)
     }
 
-    pub fn lock_image(&self, i: usize) -> impl Deref<Target = [u8]> + DerefMut + '_ {
+    pub fn enable_ready_cb(&self) {
+        // Images are never in use by the presentation engine
+    }
+
+    pub fn is_ready_cb_scheduled(&self) -> bool {
+        false
+    }
+
+    pub fn acquire_with_timeout(&self, _timeout: Duration) -> AcquireResult {
+        AcquireResult::Ready(0)
+    }
+
+    pub fn handle_os_event<T>(&self, _event: &Event<T>) {}
+
+    pub fn detach(&self) {}
+
+    pub fn wait_all_released(&self, _timeout: Duration) -> bool {
+        true
+    }
+
+    pub fn lock_image(&self, i: usize) -> impl DerefMut<Target = [u8]> + '_ {
         assert_eq!(i, 0);
         OwningRefMut::new(self.image.borrow_mut()).map_mut(|p| &mut **p)
     }
 
+    pub fn copy_image(&self, src_i: usize, dst_i: usize) {
+        assert_eq!(src_i, 0);
+        assert_eq!(dst_i, 0);
+
+        // There's only one image, so there's nothing to copy
+        let _ = self
+            .image
+            .try_borrow_mut()
+            .expect("the image is currently locked");
+    }
+
     pub fn present_image(&self, i: usize) {
+        self.present(i, Update::Full, [0, 0]);
+    }
+
+    pub fn present_image_damaged(&self, i: usize, damage: &[[u32; 4]]) {
+        if self.transform.get() == Transform::Identity {
+            self.present(i, Update::Damage(damage), [0, 0]);
+        } else {
+            // The region is specified in the untransformed space
+            self.present(i, Update::Full, [0, 0]);
+        }
+    }
+
+    pub fn present_image_at(&self, i: usize, offset: [i32; 2]) {
+        self.present(i, Update::Full, offset);
+    }
+
+    pub fn present_image_immediately(&self, i: usize) {
+        // `XPutImage` doesn't wait for the vertical blank
+        self.present_image(i);
+    }
+
+    pub fn try_present_image(&self, i: usize) -> Result<(), SurfaceError> {
+        self.present_image(i);
+        Ok(())
+    }
+
+    pub fn present_region(&self, i: usize, src_rect: [u32; 4], dst_pos: [u32; 2]) {
+        if self.transform.get() == Transform::Identity {
+            self.present(i, Update::Scroll(src_rect, dst_pos), [0, 0]);
+        } else {
+            // The region is specified in the untransformed space
+            self.present(i, Update::Full, [0, 0]);
+        }
+    }
+
+    /// Present the image, transferring the area specified by `update`.
+    /// The transferred area is displaced by `offset`.
+    fn present(&self, i: usize, update: Update<'_>, offset: [i32; 2]) {
         assert_eq!(i, 0);
 
         let image_info = self.image_info.get();
@@ -134,8 +425,20 @@ impl SurfaceImpl {
             .try_borrow()
             .expect("the image is currently locked");
 
-        // TODO: Use XShape to set the window shape based on alpha channel
-        //       <https://www.x.org/releases/X11R7.7/doc/xextproto/shape.html>
+        // The server can read the image directly if it's in a shared memory
+        // segment and doesn't need transformation
+        let shm_seg = match &*image {
+            ImageMemory::Shm(seg) if self.transform.get() == Transform::Identity => Some(seg),
+            _ => None,
+        };
+
+        let mut transformed_image = self.transformed_image.borrow_mut();
+        let (image, image_info) = transform::apply(
+            self.transform.get(),
+            &image,
+            &image_info,
+            &mut transformed_image,
+        );
 
         // TODO: See if this works on uncommon visuals
 
@@ -154,31 +457,330 @@ impl SurfaceImpl {
                 bitmap_unit: 32,
                 bitmap_bit_order: xlib::LSBFirst,
                 bitmap_pad: 32,
-                depth: 24,
+                depth: self.depth as _,
                 bytes_per_line: image_info.stride as _,
                 bits_per_pixel: 32,
                 red_mask: 0xff0000,
                 green_mask: 0xff00,
                 blue_mask: 0xff,
+                // `XShmPutImage` gets the segment from here
+                obdata: shm_seg.map_or(std::ptr::null_mut(), |seg| &seg.info as *const _ as *mut _),
                 ..std::mem::zeroed()
             };
 
             (self.xlib.XInitImage)(&mut x_image);
 
-            let x_gc = (self.xlib.XDefaultGCOfScreen)(self.x_scrn);
+            let x_gc = self.x_gc;
 
-            (self.xlib.XPutImage)(
-                self.x_dpy,
-                self.x_wnd,
-                x_gc,
-                &mut x_image,
-                0,
-                0,
-                0,
-                0,
-                image_info.extent[0] as _,
-                image_info.extent[1] as _,
-            );
+            let mut regions = match update {
+                Update::Full => vec![[0, 0, image_info.extent[0], image_info.extent[1]]],
+                Update::Damage(damage) => damage.to_vec(),
+                Update::Scroll(src_rect, [dst_x, dst_y]) => {
+                    let [src_x, src_y, width, height] = src_rect;
+                    (self.xlib.XCopyArea)(
+                        self.x_dpy,
+                        self.x_wnd,
+                        self.x_wnd,
+                        x_gc,
+                        src_x as _,
+                        src_y as _,
+                        width as _,
+                        height as _,
+                        dst_x as _,
+                        dst_y as _,
+                    );
+
+                    rect_difference(src_rect, [dst_x, dst_y, width, height])
+                }
+            };
+
+            merge_rects(&mut regions, self.damage_merge.get());
+
+            for [x, y, width, height] in regions {
+                if let Some(seg) = shm_seg {
+                    (seg.xext.XShmPutImage)(
+                        self.x_dpy,
+                        self.x_wnd,
+                        x_gc,
+                        &mut x_image,
+                        x as _,
+                        y as _,
+                        x as i32 + offset[0],
+                        y as i32 + offset[1],
+                        width as _,
+                        height as _,
+                        xlib::False,
+                    );
+                } else {
+                    (self.xlib.XPutImage)(
+                        self.x_dpy,
+                        self.x_wnd,
+                        x_gc,
+                        &mut x_image,
+                        x as _,
+                        y as _,
+                        x as i32 + offset[0],
+                        y as i32 + offset[1],
+                        width as _,
+                        height as _,
+                    );
+                }
+            }
+
+            if shm_seg.is_some() {
+                // The server reads the segment asynchronously. Wait until
+                // it's done so that the application can modify the image
+                (self.xlib.XSync)(self.x_dpy, xlib::False);
+            }
+        }
+
+        if self.shape_mask.get() {
+            self.update_shape_mask(image, &image_info);
+        }
+    }
+
+    /// Set the window shape based on the alpha channel of `image`.
+    fn update_shape_mask(&self, image: &[u8], image_info: &ImageInfo) {
+        if image_info.format != Format::Argb8888 {
+            self.set_shape_region(SHAPE_BOUNDING, None);
+            return;
+        }
+
+        let [width, height] = image_info.extent;
+        let mut rects = Vec::new();
+        for y in 0..height {
+            let row = &image[y as usize * image_info.stride..][..width as usize * 4];
+            let mut run_start = None;
+            for (x, pixel) in row.chunks_exact(4).chain(Some(&[0; 4][..])).enumerate() {
+                match (run_start, pixel[3] != 0) {
+                    (None, true) => run_start = Some(x as u32),
+                    (Some(x0), false) => {
+                        rects.push([x0, y, x as u32 - x0, 1]);
+                        run_start = None;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        self.set_shape_region(SHAPE_BOUNDING, Some(&rects));
+    }
+
+    pub fn set_transform(&self, transform: Transform) {
+        self.transform.set(transform);
+    }
+
+    pub fn transform(&self) -> Transform {
+        self.transform.get()
+    }
+}
+
+/// Determine whether the window shape should be derived from the alpha
+/// channel.
+fn use_shape_mask(config: &Config, depth: c_int) -> bool {
+    !config.opaque && depth != 32 && config.transparent_fallback == TransparentFallback::ShapeMask
+}
+
+/// The area of the image transferred by `SurfaceImpl::present`.
+#[derive(Clone, Copy)]
+enum Update<'a> {
+    /// The whole image.
+    Full,
+    /// The given rectangles (`[x, y, width, height]`).
+    Damage(&'a [[u32; 4]]),
+    /// Move `src_rect` to `dst_pos` on the server side and only transfer the
+    /// uncovered area.
+    Scroll([u32; 4], [u32; 2]),
+}
+
+/// The alignment guaranteed for shared memory segments (the page size).
+const SHM_ALIGN: usize = 4096;
+
+/// The storage of the swapchain image.
+enum ImageMemory {
+    Heap(Buffer),
+    Shm(ShmSegment),
+}
+
+impl ImageMemory {
+    /// Change the size of the image, preserving the contents, and place it on
+    /// the heap. A shared memory segment is replaced with a heap allocation.
+    /// `clear` specifies whether a heap allocation zero-fills the added bytes
+    /// (see [`Config::no_clear_on_resize`]).
+    fn resize(&mut self, new_size: usize, clear: bool) {
+        match self {
+            ImageMemory::Heap(buffer) if clear => buffer.resize(new_size),
+            ImageMemory::Heap(buffer) => buffer.resize_no_clear(new_size),
+            ImageMemory::Shm(seg) => {
+                // `SHM_ALIGN` satisfies the alignment
+                let mut buffer = Buffer::from_size_align(new_size, SHM_ALIGN).unwrap();
+                let len = cmp::min(seg.len, new_size);
+                buffer[..len].copy_from_slice(&seg[..len]);
+                *self = ImageMemory::Heap(buffer);
+            }
+        }
+    }
+
+    /// Change the size of the image, preserving the contents, and place it in
+    /// a shared memory segment. Returns `false` without modifying the image if
+    /// a shared memory segment couldn't be created.
+    unsafe fn resize_shm(
+        &mut self,
+        xlib: &'static xlib::Xlib,
+        x_dpy: *mut xlib::Display,
+        new_size: usize,
+    ) -> bool {
+        if let ImageMemory::Shm(seg) = self {
+            if new_size <= seg.capacity {
+                seg.set_len(new_size);
+                return true;
+            }
+        }
+
+        if let Some(mut seg) = ShmSegment::new(xlib, x_dpy, new_size) {
+            let len = cmp::min(self.len(), new_size);
+            seg[..len].copy_from_slice(&self[..len]);
+            *self = ImageMemory::Shm(seg);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl std::ops::Deref for ImageMemory {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            ImageMemory::Heap(buffer) => buffer,
+            ImageMemory::Shm(seg) => seg,
+        }
+    }
+}
+
+impl DerefMut for ImageMemory {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            ImageMemory::Heap(buffer) => buffer,
+            ImageMemory::Shm(seg) => seg,
+        }
+    }
+}
+
+/// A System V shared memory segment attached to the X server by MIT-SHM.
+struct ShmSegment {
+    xlib: &'static xlib::Xlib,
+    xext: &'static xshm::Xext,
+    x_dpy: *mut xlib::Display,
+    info: xshm::XShmSegmentInfo,
+    capacity: usize,
+    len: usize,
+}
+
+/// Set by `trap_error_handler`.
+static X_ERROR_TRAPPED: AtomicBool = AtomicBool::new(false);
+
+unsafe extern "C" fn trap_error_handler(_: *mut xlib::Display, _: *mut xlib::XErrorEvent) -> c_int {
+    X_ERROR_TRAPPED.store(true, Ordering::Relaxed);
+    0
+}
+
+impl ShmSegment {
+    /// Create a segment of `len` bytes and attach it to the X server. Returns
+    /// `None` if MIT-SHM is unavailable, e.g., on a remote display.
+    unsafe fn new(
+        xlib: &'static xlib::Xlib,
+        x_dpy: *mut xlib::Display,
+        len: usize,
+    ) -> Option<Self> {
+        let xext = XEXT.as_ref()?;
+        if (xext.XShmQueryExtension)(x_dpy) == xlib::False {
+            debug!("MIT-SHM is not available");
+            return None;
+        }
+
+        let shmid = libc::shmget(libc::IPC_PRIVATE, len, libc::IPC_CREAT | 0o600);
+        if shmid < 0 {
+            warn!("shmget failed");
+            return None;
+        }
+
+        let shmaddr = libc::shmat(shmid, std::ptr::null(), 0);
+
+        let mut info = xshm::XShmSegmentInfo {
+            shmseg: 0,
+            shmid,
+            shmaddr: shmaddr as *mut _,
+            readOnly: xlib::True,
+        };
+
+        // `XShmAttach` raises an X error if the server can't access the
+        // segment. Don't let the default error handler terminate the process.
+        let attached = shmaddr as isize != -1 && {
+            X_ERROR_TRAPPED.store(false, Ordering::Relaxed);
+            let old_handler = (xlib.XSetErrorHandler)(Some(trap_error_handler));
+            (xext.XShmAttach)(x_dpy, &mut info);
+            (xlib.XSync)(x_dpy, xlib::False);
+            (xlib.XSetErrorHandler)(old_handler);
+            !X_ERROR_TRAPPED.load(Ordering::Relaxed)
+        };
+
+        // The segment will be destroyed when both of us detach it
+        libc::shmctl(shmid, libc::IPC_RMID, std::ptr::null_mut());
+
+        if !attached {
+            warn!("Could not attach a shared memory segment to the X server");
+            if shmaddr as isize != -1 {
+                libc::shmdt(shmaddr);
+            }
+            return None;
+        }
+
+        Some(Self {
+            xlib,
+            xext,
+            x_dpy,
+            info,
+            capacity: len,
+            len,
+        })
+    }
+}
+
+impl ShmSegment {
+    /// Change the length, zero-filling the added part. `len` must not exceed
+    /// `capacity`.
+    fn set_len(&mut self, len: usize) {
+        debug_assert!(len <= self.capacity);
+        let old_len = self.len;
+        self.len = len;
+        if len > old_len {
+            self[old_len..].fill(0);
         }
     }
 }
+
+impl Drop for ShmSegment {
+    fn drop(&mut self) {
+        unsafe {
+            (self.xext.XShmDetach)(self.x_dpy, &mut self.info);
+            (self.xlib.XSync)(self.x_dpy, xlib::False);
+            libc::shmdt(self.info.shmaddr as *const _);
+        }
+    }
+}
+
+impl std::ops::Deref for ShmSegment {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { from_raw_parts(self.info.shmaddr as *const u8, self.len) }
+    }
+}
+
+impl DerefMut for ShmSegment {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { from_raw_parts_mut(self.info.shmaddr as *mut u8, self.len) }
+    }
+}
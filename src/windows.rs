@@ -1,44 +1,128 @@
 //! Windows backend
+use log::warn;
 use owning_ref::OwningRefMut;
+use raw_window_handle::{RawDisplayHandle, RawWindowHandle};
 use std::{
     cell::{Cell, RefCell},
     mem::size_of,
-    ops::{Deref, DerefMut},
+    ops::DerefMut,
+    time::Duration,
 };
 use winapi::{
-    shared::windef::{HDC, HWND},
+    shared::{
+        minwindef::{DWORD, FALSE, FARPROC, UINT},
+        windef::{HDC, HWND},
+        winerror::SUCCEEDED,
+    },
     um::{
-        wingdi::{StretchDIBits, BITMAPINFO, BITMAPINFOHEADER, BI_RGB, DIB_RGB_COLORS, SRCCOPY},
-        winuser::{GetDC, ReleaseDC},
+        dwmapi::DwmIsCompositionEnabled,
+        libloaderapi::{GetModuleHandleA, GetProcAddress},
+        wingdi::{
+            GetDeviceCaps, SetBrushOrgEx, SetDIBitsToDevice, SetStretchBltMode, StretchDIBits,
+            BITMAPINFO, BITMAPINFOHEADER, BI_RGB, DIB_RGB_COLORS, GDI_ERROR, HALFTONE, LOGPIXELSX,
+            SRCCOPY,
+        },
+        winuser::{GetDC, ReleaseDC, SetWindowDisplayAffinity, WDA_MONITOR, WDA_NONE},
     },
 };
-use winit::{platform::windows::WindowExtWindows, window::Window};
+use winit::{event::Event, platform::windows::WindowExtWindows, window::Window};
 
-use super::{align::Align, buffer::Buffer, Config, Format, ImageInfo, NullContextImpl};
+use super::{
+    align::Align,
+    buffer::Buffer,
+    surface::{ConfigField, NullContextImpl, NullSubSurfaceImpl},
+    transform, AcquireResult, Config, Format, ImageInfo, MergeStrategy, SurfaceError, Transform,
+};
+
+lazy_static::lazy_static! {
+    /// `GetDpiForWindow`, which is only available on Windows 10 version 1607
+    /// and later.
+    static ref GET_DPI_FOR_WINDOW: Option<unsafe extern "system" fn(HWND) -> UINT> = unsafe {
+        let user32 = GetModuleHandleA(b"user32.dll\0".as_ptr() as _);
+        let proc_addr = if user32.is_null() {
+            std::ptr::null_mut()
+        } else {
+            GetProcAddress(user32, b"GetDpiForWindow\0".as_ptr() as _)
+        };
+        if proc_addr.is_null() {
+            None
+        } else {
+            Some(std::mem::transmute::<
+                FARPROC,
+                unsafe extern "system" fn(HWND) -> UINT,
+            >(proc_addr))
+        }
+    };
+}
+
+/// `WDA_EXCLUDEFROMCAPTURE`, which is available since Windows 10 version 2004
+const WDA_EXCLUDEFROMCAPTURE: DWORD = 0x11;
 
 #[derive(Debug)]
 pub struct SurfaceImpl {
     hwnd: HWND,
     image: RefCell<Buffer>,
+    /// [`Config::no_clear_on_resize`]
+    no_clear_on_resize: Cell<bool>,
     image_info: Cell<ImageInfo>,
-    scanline_align: Align,
+    scanline_align: Cell<Align>,
+    transform: Cell<Transform>,
+    /// Stores the transformed image if `transform != Identity`
+    transformed_image: RefCell<Vec<u8>>,
+    /// [`Config::hint_fast_path`]
+    fast_path: Cell<bool>,
+    /// `true` if `SetWindowDisplayAffinity` succeeded for
+    /// [`Config::content_protection`]
+    content_protection: Cell<bool>,
 }
 
 impl SurfaceImpl {
     pub(crate) unsafe fn new(window: &Window, _: &NullContextImpl, config: &Config) -> Self {
-        Self {
-            hwnd: window.hwnd() as _,
+        Self::from_hwnd(window.hwnd() as _, config)
+    }
+
+    pub(crate) unsafe fn from_raw_handles(
+        window: RawWindowHandle,
+        _display: RawDisplayHandle,
+        _: &NullContextImpl,
+        config: &Config,
+    ) -> Result<Self, SurfaceError> {
+        match window {
+            RawWindowHandle::Win32(handle) if !handle.hwnd.is_null() => {
+                Ok(Self::from_hwnd(handle.hwnd as _, config))
+            }
+            _ => Err(SurfaceError::UnsupportedHandle),
+        }
+    }
+
+    unsafe fn from_hwnd(hwnd: HWND, config: &Config) -> Self {
+        let this = Self {
+            hwnd,
             image: RefCell::new(Buffer::from_size_align(1, config.align).unwrap()),
-            image_info: Cell::new(ImageInfo::default()),
-            scanline_align: Align::new(config.scanline_align).unwrap(),
+            no_clear_on_resize: Cell::new(config.no_clear_on_resize),
+            image_info: Cell::new(ImageInfo {
+                color_space: config.color_space,
+                ..ImageInfo::default()
+            }),
+            scanline_align: Cell::new(Align::new(config.scanline_align).unwrap()),
+            transform: Cell::new(Transform::Identity),
+            transformed_image: RefCell::new(Vec::new()),
+            fast_path: Cell::new(config.hint_fast_path),
+            content_protection: Cell::new(false),
+        };
+
+        if config.content_protection {
+            this.set_content_protection(true);
         }
+
+        this
     }
 
     pub fn update_surface(&self, extent: [u32; 2], format: Format) {
         assert_ne!(extent[0], 0);
         assert_ne!(extent[1], 0);
-        assert!(extent[0] <= <i32>::max_value() as u32);
-        assert!(extent[1] <= <i32>::max_value() as u32);
+        assert!(extent[0] <= <i32>::MAX as u32);
+        assert!(extent[1] <= <i32>::MAX as u32);
 
         use std::convert::TryInto;
         let extent_usize: [usize; 2] = [
@@ -48,7 +132,7 @@ impl SurfaceImpl {
 
         let stride = extent_usize[0]
             .checked_mul(4)
-            .and_then(|x| self.scanline_align.align_up(x))
+            .and_then(|x| self.scanline_align.get().align_up(x))
             .expect("overflow");
 
         let size = stride.checked_mul(extent_usize[1]).expect("overflow");
@@ -58,15 +142,71 @@ impl SurfaceImpl {
         let _stride_pixels: std::os::raw::c_int = (stride / 4).try_into().expect("overflow");
 
         let mut image = self.image.borrow_mut();
-        image.resize(size);
+        if self.no_clear_on_resize.get() {
+            image.resize_no_clear(size);
+        } else {
+            image.resize(size);
+        }
 
         self.image_info.set(ImageInfo {
             extent,
             stride,
             format,
+            ..self.image_info.get()
         });
     }
 
+    pub fn reconfigure(&self, old: &Config, new: &Config) -> Vec<ConfigField> {
+        let mut unsupported = Vec::new();
+
+        // `Buffer`'s alignment is fixed
+        if new.align != old.align {
+            unsupported.push(ConfigField::Align);
+        }
+
+        self.no_clear_on_resize.set(new.no_clear_on_resize);
+
+        // `color_space` is merely a tag
+        self.image_info.set(ImageInfo {
+            color_space: new.color_space,
+            ..self.image_info.get()
+        });
+
+        self.fast_path.set(new.hint_fast_path);
+
+        if new.content_protection != old.content_protection {
+            self.set_content_protection(new.content_protection);
+        }
+
+        unsupported
+    }
+
+    pub fn resize_swapchain(&self, _count: usize) -> Result<(), SurfaceError> {
+        // There's always exactly one image
+        Ok(())
+    }
+
+    pub fn set_scanline_align(&self, scanline_align: Align) {
+        self.scanline_align.set(scanline_align);
+    }
+
+    pub fn supports_transparency(&self) -> bool {
+        // DWM uses the alpha channel only if desktop composition is enabled,
+        // which is always the case on Windows 8 and later
+        let mut enabled = FALSE;
+        unsafe { SUCCEEDED(DwmIsCompositionEnabled(&mut enabled)) && enabled != FALSE }
+    }
+
+    pub fn default_scanline_align(&self) -> Align {
+        // DIB scanlines are `DWORD`-aligned
+        Align::new(4).unwrap()
+    }
+
+    pub fn refresh_rate(&self, _window: &Window) -> Option<u32> {
+        // Not implemented; `EnumDisplaySettingsW` could provide this
+        None
+    }
+
     pub fn supported_formats(&self) -> impl Iterator<Item = Format> + '_ {
         [Format::Argb8888].iter().cloned()
     }
@@ -83,16 +223,66 @@ impl SurfaceImpl {
         true
     }
 
+    pub fn is_presenting(&self, i: usize) -> bool {
+        assert_eq!(i, 0);
+        false
+    }
+
+    pub fn is_locked(&self, i: usize) -> bool {
+        assert_eq!(i, 0);
+        self.image.try_borrow_mut().is_err()
+    }
+
     pub fn poll_next_image(&self) -> Option<usize> {
         Some(0)
     }
 
-    pub fn lock_image(&self, i: usize) -> impl Deref<Target = [u8]> + DerefMut + '_ {
+    pub fn enable_ready_cb(&self) {
+        // Images are never in use by the presentation engine
+    }
+
+    pub fn is_ready_cb_scheduled(&self) -> bool {
+        false
+    }
+
+    pub fn acquire_with_timeout(&self, _timeout: Duration) -> AcquireResult {
+        AcquireResult::Ready(0)
+    }
+
+    pub fn handle_os_event<T>(&self, _event: &Event<T>) {}
+
+    pub fn detach(&self) {}
+
+    pub fn wait_all_released(&self, _timeout: Duration) -> bool {
+        true
+    }
+
+    pub fn lock_image(&self, i: usize) -> impl DerefMut<Target = [u8]> + '_ {
         assert_eq!(i, 0);
         OwningRefMut::new(self.image.borrow_mut()).map_mut(|p| &mut **p)
     }
 
     pub fn present_image(&self, i: usize) {
+        // The failure is already logged by `try_present_image`
+        let _ = self.try_present_image(i);
+    }
+
+    pub fn present_image_at(&self, i: usize, _offset: [i32; 2]) {
+        self.present_image(i);
+    }
+
+    pub fn present_image_immediately(&self, i: usize) {
+        // `StretchDIBits` doesn't wait for the vertical blank
+        self.present_image(i);
+    }
+
+    pub fn present_image_uncommitted(&self, i: usize) {
+        self.present_image(i);
+    }
+
+    pub fn commit_present(&self) {}
+
+    pub fn try_present_image(&self, i: usize) -> Result<(), SurfaceError> {
         assert_eq!(i, 0);
 
         let image_info = self.image_info.get();
@@ -103,46 +293,256 @@ impl SurfaceImpl {
 
         assert_eq!(image_info.format, Format::Argb8888);
 
-        // The following value works for `Argb8888`.
-        // Although the GDI's documentation says that `BI_RGB` ignores the
-        // alpha channel, it still copies it to the backing store as-is, which
-        // DWM interprets as the alpha channel.
-        let bitmap_info_header = BITMAPINFOHEADER {
-            biSize: size_of::<BITMAPINFOHEADER>() as _,
-            biWidth: (image_info.stride / 4) as _,
-            biHeight: -(image_info.extent[1] as i32),
-            biPlanes: 1,
-            biBitCount: 32,
-            biCompression: BI_RGB,
-            biSizeImage: 0,
-            biXPelsPerMeter: 0,
-            biYPelsPerMeter: 0,
-            biClrUsed: 0,
-            biClrImportant: 0,
+        let mut transformed_image = self.transformed_image.borrow_mut();
+        let (image, image_info) = transform::apply(
+            self.transform.get(),
+            &image,
+            &image_info,
+            &mut transformed_image,
+        );
+
+        unsafe {
+            let hdc = if let Some(hdc) = UniqueDC::new(self.hwnd, GetDC(self.hwnd)) {
+                hdc
+            } else {
+                warn!("GetDC failed");
+                return Err(SurfaceError::PresentFailed);
+            };
+
+            // Tell GDI the resolution of the bitmap so that it's scaled
+            // correctly on mixed-DPI setups. 1 inch = 0.0254 meters
+            let pels_per_meter = ((window_dpi(self.hwnd, hdc.hdc()) * 10000 + 127) / 254) as _;
+
+            // The following value works for `Argb8888`.
+            // Although the GDI's documentation says that `BI_RGB` ignores the
+            // alpha channel, it still copies it to the backing store as-is, which
+            // DWM interprets as the alpha channel.
+            let bitmap_info_header = BITMAPINFOHEADER {
+                biSize: size_of::<BITMAPINFOHEADER>() as _,
+                biWidth: (image_info.stride / 4) as _,
+                biHeight: -(image_info.extent[1] as i32),
+                biPlanes: 1,
+                biBitCount: 32,
+                biCompression: BI_RGB,
+                biSizeImage: 0,
+                biXPelsPerMeter: pels_per_meter,
+                biYPelsPerMeter: pels_per_meter,
+                biClrUsed: 0,
+                biClrImportant: 0,
+            };
+
+            let bitmap_info = &bitmap_info_header as *const BITMAPINFOHEADER as *const BITMAPINFO;
+
+            let num_scanlines = if self.fast_path.get() {
+                // Skip `StretchDIBits`'s scaling machinery
+                SetDIBitsToDevice(
+                    hdc.hdc(),
+                    0,
+                    0,
+                    image_info.extent[0],
+                    image_info.extent[1],
+                    0,
+                    0,
+                    0,
+                    image_info.extent[1],
+                    image.as_ptr() as *const _,
+                    bitmap_info,
+                    DIB_RGB_COLORS,
+                )
+            } else {
+                // Use a high-quality filter in case GDI scales the image.
+                // `HALFTONE` requires resetting the brush origin.
+                SetStretchBltMode(hdc.hdc(), HALFTONE);
+                SetBrushOrgEx(hdc.hdc(), 0, 0, std::ptr::null_mut());
+
+                StretchDIBits(
+                    hdc.hdc(),
+                    0,
+                    0,
+                    image_info.extent[0] as _,
+                    image_info.extent[1] as _,
+                    0,
+                    0,
+                    image_info.extent[0] as _,
+                    image_info.extent[1] as _,
+                    image.as_ptr() as *const _,
+                    bitmap_info,
+                    DIB_RGB_COLORS,
+                    SRCCOPY,
+                )
+            };
+
+            // Both functions return the number of scanlines copied
+            if num_scanlines == 0 || num_scanlines as u32 == GDI_ERROR {
+                warn!("StretchDIBits failed (returned {})", num_scanlines);
+                return Err(SurfaceError::PresentFailed);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn present_image_damaged(&self, i: usize, _damage: &[[u32; 4]]) {
+        self.present_image(i);
+    }
+
+    pub fn present_region(&self, i: usize, _src_rect: [u32; 4], _dst_pos: [u32; 2]) {
+        self.present_image(i);
+    }
+
+    pub fn copy_image(&self, src_i: usize, dst_i: usize) {
+        assert_eq!(src_i, 0);
+        assert_eq!(dst_i, 0);
+
+        // There's only one image, so there's nothing to copy
+        let _ = self
+            .image
+            .try_borrow_mut()
+            .expect("the image is currently locked");
+    }
+
+    pub fn set_blur_behind(&self, _enabled: bool) {}
+
+    pub fn is_content_protection_active(&self) -> bool {
+        self.content_protection.get()
+    }
+
+    /// Apply `Config::content_protection`.
+    fn set_content_protection(&self, enabled: bool) {
+        let active = unsafe {
+            if enabled {
+                // `WDA_EXCLUDEFROMCAPTURE` fails on older versions of Windows
+                SetWindowDisplayAffinity(self.hwnd, WDA_EXCLUDEFROMCAPTURE) != 0
+                    || SetWindowDisplayAffinity(self.hwnd, WDA_MONITOR) != 0
+            } else {
+                SetWindowDisplayAffinity(self.hwnd, WDA_NONE);
+                false
+            }
+        };
+        self.content_protection.set(active);
+    }
+
+    #[cfg(feature = "screen-capture")]
+    pub fn screen_capture(&self, extent: [u32; 2]) -> Option<Vec<u8>> {
+        use winapi::um::wingdi::{
+            BitBlt, CreateCompatibleBitmap, CreateCompatibleDC, DeleteDC, DeleteObject, GetDIBits,
+            SelectObject,
         };
 
-        let bitmap_info = &bitmap_info_header as *const BITMAPINFOHEADER as *const BITMAPINFO;
+        let [width, height] = extent;
+        let mut out = vec![0u8; width as usize * height as usize * 4];
+        if width == 0 || height == 0 {
+            return Some(out);
+        }
 
         unsafe {
-            let hdc = UniqueDC::new(self.hwnd, GetDC(self.hwnd)).expect("GetDC failed");
+            let hdc = UniqueDC::new(self.hwnd, GetDC(self.hwnd))?;
 
-            StretchDIBits(
-                hdc.hdc(),
+            let mem_dc = CreateCompatibleDC(hdc.hdc());
+            if mem_dc.is_null() {
+                warn!("CreateCompatibleDC failed");
+                return None;
+            }
+            let bitmap = CreateCompatibleBitmap(hdc.hdc(), width as _, height as _);
+            if bitmap.is_null() {
+                warn!("CreateCompatibleBitmap failed");
+                DeleteDC(mem_dc);
+                return None;
+            }
+
+            let old_bitmap = SelectObject(mem_dc, bitmap as _);
+            let ok = BitBlt(
+                mem_dc,
                 0,
                 0,
-                image_info.extent[0] as _,
-                image_info.extent[1] as _,
+                width as _,
+                height as _,
+                hdc.hdc(),
                 0,
                 0,
-                image_info.extent[0] as _,
-                image_info.extent[1] as _,
-                image.as_ptr() as *const _,
-                bitmap_info,
-                DIB_RGB_COLORS,
                 SRCCOPY,
-            );
+            ) != 0;
+            SelectObject(mem_dc, old_bitmap);
+
+            let mut bitmap_info_header = BITMAPINFOHEADER {
+                biSize: size_of::<BITMAPINFOHEADER>() as _,
+                biWidth: width as _,
+                biHeight: -(height as i32),
+                biPlanes: 1,
+                biBitCount: 32,
+                biCompression: BI_RGB,
+                biSizeImage: 0,
+                biXPelsPerMeter: 0,
+                biYPelsPerMeter: 0,
+                biClrUsed: 0,
+                biClrImportant: 0,
+            };
+            let ok = ok
+                && GetDIBits(
+                    mem_dc,
+                    bitmap,
+                    0,
+                    height,
+                    out.as_mut_ptr() as *mut _,
+                    &mut bitmap_info_header as *mut BITMAPINFOHEADER as *mut BITMAPINFO,
+                    DIB_RGB_COLORS,
+                ) != 0;
+
+            DeleteObject(bitmap as _);
+            DeleteDC(mem_dc);
+
+            if !ok {
+                warn!("BitBlt or GetDIBits failed");
+                return None;
+            }
+        }
+
+        // GDI leaves the alpha channel undefined
+        for pixel in out.chunks_exact_mut(4) {
+            pixel[3] = 0xff;
+        }
+
+        Some(out)
+    }
+
+    #[cfg(debug_assertions)]
+    pub fn set_debug_label(&self, _label: &str) {}
+
+    pub fn set_damage_merge_strategy(&self, _strategy: MergeStrategy) {}
+
+    pub fn set_input_region(&self, _region: Option<&[[u32; 4]]>) {}
+
+    pub fn create_subsurface(
+        &self,
+        _rect: [u32; 4],
+        _config: &Config,
+    ) -> Option<NullSubSurfaceImpl> {
+        None
+    }
+
+    pub fn set_transform(&self, transform: Transform) {
+        self.transform.set(transform);
+    }
+
+    pub fn transform(&self) -> Transform {
+        self.transform.get()
+    }
+}
+
+/// Get the DPI of the window `hwnd`.
+///
+/// `GetDpiForWindow` reports the DPI of the monitor the window is on if the
+/// thread is per-monitor DPI aware (which is the case with winit, which
+/// prefers per-monitor v2 awareness). `LOGPIXELSX` only reports the system
+/// DPI, so it's used only as a fallback.
+unsafe fn window_dpi(hwnd: HWND, hdc: HDC) -> u32 {
+    if let Some(get_dpi_for_window) = *GET_DPI_FOR_WINDOW {
+        let dpi = get_dpi_for_window(hwnd);
+        if dpi != 0 {
+            return dpi;
         }
     }
+    GetDeviceCaps(hdc, LOGPIXELSX) as u32
 }
 
 struct UniqueDC(HWND, HDC);
@@ -0,0 +1,31 @@
+//! Exercises the platform-independent core. Run with
+//! `cargo test --no-default-features --features core-only` to check that it
+//! builds under `no_std`.
+use swsurface::{Align, Buffer, Format, ImageInfo};
+
+#[test]
+fn align_and_buffer() {
+    let align = Align::new(16).unwrap();
+    assert!(Align::new(3).is_err());
+
+    let image_info = ImageInfo {
+        extent: [5, 3],
+        stride: align
+            .align_up(5 * Format::Argb8888.bytes_per_pixel())
+            .unwrap(),
+        format: Format::Argb8888,
+        ..ImageInfo::default()
+    };
+    assert_eq!(image_info.stride, 32);
+
+    let size = image_info.stride * image_info.extent[1] as usize;
+    let mut buffer = Buffer::from_size_align(size, 64).unwrap();
+    assert_eq!(buffer.len(), 96);
+    assert_eq!(buffer.as_ptr() as usize % 64, 0);
+    assert!(buffer.iter().all(|&x| x == 0));
+
+    buffer[95] = 1;
+    buffer.resize(128);
+    assert_eq!(buffer[95], 1);
+    assert!(buffer[96..].iter().all(|&x| x == 0));
+}